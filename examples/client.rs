@@ -1,29 +1,48 @@
 use std::{
-    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
     time::{Duration, Instant}
 };
 
-use linkseeker::data::{FromMiddlemanMsg, ToMiddlemanMsg};
+use linkseeker::{
+    candidate::local_candidates,
+    client::{ClientConfig, ClientEvent, LinkSeekClient},
+    code::LinkCode,
+    data::{FromMiddlemanMsg, ToMiddlemanMsg},
+    punch::{PunchConfig, PunchEvent, Puncher}
+};
 
-fn punch(socket: &UdpSocket, remote: SocketAddr) {
+fn punch(socket: &UdpSocket, client: &LinkSeekClient, remote: SocketAddr, delta: Option<i32>, candidates: &[SocketAddr]) {
     let mut buf = [0; 1500];
-    let _r = socket.send_to(b"PUNCH", remote);
-    println!("punching...");
-    std::thread::sleep(Duration::from_secs(2));
-    let _r = socket.send_to(b"PUNCH", remote);
-    println!("punching...");
-    std::thread::sleep(Duration::from_secs(2));
-    let _r = socket.send_to(b"PUNCH", remote);
+    let local = client.candidates();
+    let mut puncher = Puncher::with_candidates(remote, delta, candidates, local, PunchConfig::default(), Instant::now());
     println!("punching...");
-    std::thread::sleep(Duration::from_secs(2));
-    println!("waiting for punch...");
-    let Ok((len, recv_remote)) = socket.recv_from(&mut buf) else {
-        return;
-    };
-    if &buf[0..len] == b"PUNCH" && recv_remote == remote {
-        println!("successfully punched to {}", remote);
-    } else {
-        eprintln!("error: received unexpected message {} from remote {}", String::from_utf8_lossy(&buf[0..len]), recv_remote);
+    loop {
+        while let Some(transmit) = puncher.poll_transmit() {
+            let _r = socket.send_to(&transmit.bytes, transmit.dest);
+        }
+        match puncher.poll_event() {
+            Some(PunchEvent::Succeeded { remote }) => {
+                println!("successfully punched to {}", remote);
+                return;
+            },
+            Some(PunchEvent::Failed { remote }) => {
+                eprintln!("error: could not punch to {}", remote);
+                return;
+            },
+            None => {},
+        }
+        let timeout = puncher.poll_timeout()
+            .map(|t| t.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)));
+        let _r = socket.set_read_timeout(timeout);
+        match socket.recv_from(&mut buf) {
+            Ok((len, recv_remote)) => {
+                if !puncher.handle_datagram(&buf[0..len], recv_remote, Instant::now()) {
+                    eprintln!("error: received unexpected message {} from remote {}", String::from_utf8_lossy(&buf[0..len]), recv_remote);
+                }
+            },
+            Err(_) => puncher.handle_timeout(Instant::now()),
+        }
     }
 }
 
@@ -43,6 +62,31 @@ fn recv_msg(socket: &UdpSocket, remote: SocketAddr) -> Option<FromMiddlemanMsg>
     FromMiddlemanMsg::parse(&buf[0..len])
 }
 
+/// Blocking driver for the sans-IO client: runs it until it emits an event
+fn next_event(socket: &UdpSocket, client: &mut LinkSeekClient) -> Option<ClientEvent> {
+    let mut buf = [0u8; 1500];
+    loop {
+        while let Some(transmit) = client.poll_transmit() {
+            let _r = socket.send_to(&transmit.bytes, transmit.dest);
+        }
+        if let Some(event) = client.poll_event() {
+            return Some(event);
+        }
+        let timeout = client.poll_timeout()
+            .map(|t| t.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)));
+        socket.set_read_timeout(timeout).ok()?;
+        match socket.recv_from(&mut buf) {
+            Ok((len, remote)) => {
+                client.handle_datagram(&buf[0..len], remote, Instant::now());
+            },
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                client.handle_timeout(Instant::now());
+            },
+            Err(_) => return None,
+        }
+    }
+}
+
 fn ping_script(socket: &UdpSocket, listener_ip: SocketAddr) -> bool {
     println!("running ping script");
     let sent_id = 10;
@@ -67,38 +111,39 @@ fn ping_script(socket: &UdpSocket, listener_ip: SocketAddr) -> bool {
 
 fn host_script(socket: &UdpSocket, listener_ip: SocketAddr) -> bool {
     println!("running host script");
-    send_msg(ToMiddlemanMsg::Register, socket, listener_ip);
+    let mut client = LinkSeekClient::new(listener_ip, ClientConfig::default());
+    client.set_candidates(local_candidates(socket.local_addr().unwrap().port()));
+    client.register(Instant::now());
 
-    let Some(FromMiddlemanMsg::RegisterOk { id }) = recv_msg(socket, listener_ip) else {
+    let Some(ClientEvent::Registered { id }) = next_event(socket, &mut client) else {
         eprintln!("did not receive correct answer for register");
         return false;
     };
-    println!("successfully register, have id: {}", id);
-    let Some(FromMiddlemanMsg::PunchOrder { remote, .. }) = recv_msg(socket, listener_ip) else {
+    println!("successfully register, have code: {}", LinkCode(id));
+    let Some(ClientEvent::PunchOrdered { remote, delta, candidates }) = next_event(socket, &mut client) else {
         return false;
     };
-    println!("got request to punch {}", remote);
-    punch(socket, remote);
+    println!("got request to punch {} (candidates: {:?})", remote, candidates);
+    punch(socket, &client, remote, delta, &candidates);
     true
 }
 
 fn client_script(udp_socket: &UdpSocket, listener_ip: SocketAddr, conn_id: u32) -> bool {
-    println!("running client script, connecting to id: {}", conn_id);
-
-    send_msg(ToMiddlemanMsg::Request { id: conn_id, use_proxy: false }, udp_socket, listener_ip);
-    println!("sent request for id {} to {}", conn_id, listener_ip);
-    let remote_addr;
-    match recv_msg(udp_socket, listener_ip) {
-        Some(FromMiddlemanMsg::PunchOrder { remote, .. }) => {
-            remote_addr = remote;
-        },
+    println!("running client script, connecting to code: {}", LinkCode(conn_id));
+
+    let mut client = LinkSeekClient::new(listener_ip, ClientConfig::default());
+    client.set_candidates(local_candidates(udp_socket.local_addr().unwrap().port()));
+    client.request(conn_id, false, Instant::now());
+    println!("sent request for code {} to {}", LinkCode(conn_id), listener_ip);
+    let (remote_addr, delta, candidates) = match next_event(udp_socket, &mut client) {
+        Some(ClientEvent::PunchOrdered { remote, delta, candidates }) => (remote, delta, candidates),
         e => {
             eprintln!("unexpected {:?}", e);
             return false;
         }
     };
-    println!("got request to punch {}", remote_addr);
-    punch(udp_socket, remote_addr);
+    println!("got request to punch {} (candidates: {:?})", remote_addr, candidates);
+    punch(udp_socket, &client, remote_addr, delta, &candidates);
     true
 }
 
@@ -110,13 +155,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         || "127.0.0.1:61999".to_socket_addrs().unwrap().next(),
         |arg1| arg1.to_socket_addrs().unwrap().next()
     ).unwrap();
-    let conn_id = arg2.and_then(|v| v.parse::<u32>().ok());
+    let conn_id = match arg2.map(|v| v.parse::<LinkCode>()) {
+        Some(Ok(code)) => Some(code.0),
+        Some(Err(e)) => {
+            eprintln!("invalid code: {}", e);
+            return Ok(());
+        },
+        None => None,
+    };
     
     let socket = UdpSocket::bind("0.0.0.0:0")?;
     socket.set_nonblocking(false)?;
+    // make sure the tracker is there before going any further
+    socket.set_read_timeout(Some(Duration::from_secs(2)))?;
+    if !ping_script(&socket, listener_ip) {
+        eprintln!("tracker {} did not answer the ping", listener_ip);
+        return Ok(());
+    }
     socket.set_read_timeout(None)?;
 
-
     match conn_id {
         None => {
             if !host_script(&socket, listener_ip) {
@@ -0,0 +1,79 @@
+use std::net::SocketAddr;
+
+/// What a proxy token gives access to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// `ToMiddlemanMsg::Request` through the proxy, to the host registered with this id
+    Id(u32),
+    /// `ToMiddlemanMsg::ProxyTo` or `ToMiddlemanMsg::ChannelBind` to this address
+    Remote(SocketAddr),
+    /// Every proxy, for trusted clients
+    Any,
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Id(id) => write!(f, "id:{:08x}", id),
+            Scope::Remote(addr) => write!(f, "addr:{}", addr),
+            Scope::Any => write!(f, "*"),
+        }
+    }
+}
+
+fn mac(secret: &[u8], scope: Scope, expires: u64) -> [u8; 32] {
+    hmac_sha256::HMAC::mac(format!("{}|{}", scope, expires), secret)
+}
+
+/// Token giving access to the proxy of a tracker started with `secret` as its `proxy_secret`, until
+/// `expires` (seconds since the unix epoch).
+///
+/// Whoever runs the tracker hands them out, a matchmaking server for instance, and clients give
+/// them to `LinkSeekClient::set_proxy_token`. A token is the expiry and the hex HMAC-SHA256 of the
+/// scope and the expiry, separated by a dot.
+pub fn make_token(secret: &[u8], scope: Scope, expires: u64) -> String {
+    let mac = mac(secret, scope, expires);
+    let hex = mac.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}.{}", expires, hex)
+}
+
+/// Checks that `token` gives access to `scope`, or to everything, at `unix_now`
+pub fn verify_token(secret: &[u8], token: &str, scope: Scope, unix_now: u64) -> Result<(), &'static str> {
+    let (expires, hex) = token.split_once('.').ok_or("malformed proxy token")?;
+    let expires = expires.parse::<u64>().map_err(|_| "malformed proxy token")?;
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err("malformed proxy token");
+    }
+    let mut given = [0u8; 32];
+    for (i, byte) in given.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| "malformed proxy token")?;
+    }
+    // compare every byte whatever the first difference, not to leak the expected mac through timing
+    let matches = |expected: [u8; 32]| expected.iter().zip(&given).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0;
+    if !matches(mac(secret, scope, expires)) && !matches(mac(secret, Scope::Any, expires)) {
+        return Err("invalid proxy token");
+    }
+    if unix_now >= expires {
+        return Err("proxy token expired");
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(test)]
+fn auth_tokens_are_scoped() {
+    let secret = b"hunter2";
+    let host = Scope::Id(0x1234);
+    let token = make_token(secret, host, 1000);
+    assert_eq!(verify_token(secret, &token, host, 999), Ok(()));
+    assert_eq!(verify_token(secret, &token, host, 1000), Err("proxy token expired"));
+    assert_eq!(verify_token(secret, &token, Scope::Id(0x1235), 999), Err("invalid proxy token"));
+    assert_eq!(verify_token(b"hunter3", &token, host, 999), Err("invalid proxy token"));
+    // a later expiry needs a new mac
+    let forged = token.replacen("1000", "2000", 1);
+    assert_eq!(verify_token(secret, &forged, host, 1500), Err("invalid proxy token"));
+    assert_eq!(verify_token(secret, "1000.zz", host, 999), Err("malformed proxy token"));
+
+    let any = make_token(secret, Scope::Any, 1000);
+    assert_eq!(verify_token(secret, &any, Scope::Remote("1.2.3.4:5".parse().unwrap()), 999), Ok(()));
+}
@@ -1,24 +1,144 @@
 use std::{
-    time::{Instant, Duration},
-    net::{UdpSocket, SocketAddr, IpAddr}
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    time::{Duration, Instant}
 };
 
-use linkseeker::tracker::LinkSeekTracker;
+use linkseeker::tracker::{settings_from_toml, LinkSeekTracker, SharedState, TrackerConfig, CONFIG_KEYS};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let arg1 = std::env::args().skip(1).next();
+const USAGE: &str = "usage: linkseeker [start_port] [public_ip] [workers] [--config <file.toml>] [--log-level <level>] [--<setting> <value>]...";
+
+#[derive(Default)]
+struct Args {
+    config_file: Option<String>,
+    log_level: Option<String>,
+    /// in order, the last one wins
+    settings: Vec<(String, String)>,
+}
+
+/// Command line: positional arguments of older versions, then `--setting value` or
+/// `--setting=value`
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut positional = ["port", "public_ip", "workers"].into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            let key = positional.next().ok_or(format!("unexpected argument {:?}", arg))?;
+            parsed.settings.push((key.to_string(), arg));
+            continue;
+        };
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key.replace('-', "_"), value.to_string()),
+            None => {
+                let value = args.next().ok_or(format!("--{} needs a value", flag))?;
+                (flag.replace('-', "_"), value)
+            },
+        };
+        match key.as_str() {
+            "config" => parsed.config_file = Some(value),
+            "log_level" => parsed.log_level = Some(value),
+            _ => parsed.settings.push((key, value)),
+        }
+    }
+    Ok(parsed)
+}
+
+fn load_config() -> Result<(TrackerConfig, String), String> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}\n\nsettings, also the keys of the config file:", USAGE);
+        println!("  {:<24} error, warn, info, debug, trace, or an env_logger filter", "log_level");
+        for (key, description) in CONFIG_KEYS {
+            println!("  {:<24} {}", key, description);
+        }
+        std::process::exit(0);
+    }
+    let Args { config_file, mut log_level, settings: cli_settings } = parse_args(args.into_iter())?;
+
+    let mut config = TrackerConfig::default();
+    if let Some(path) = config_file {
+        let text = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut settings = settings_from_toml(&text).map_err(|e| format!("{}: {}", path, e))?;
+        if let Some(i) = settings.iter().position(|(key, _)| key == "log_level") {
+            log_level.get_or_insert(settings.remove(i).1);
+        }
+        config.set_all(&settings).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    // the command line overrides the file
+    config.set_all(&cli_settings)?;
+    config.validate()?;
+    let log_level = log_level.or(std::env::var("RUST_LOG").ok()).unwrap_or("info".to_string());
+    Ok((config, log_level))
+}
 
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "debug")
+/// Answers `GET /metrics` with the tracker's metrics, one request per connection. Scrapes are rare,
+/// one at a time is plenty.
+fn serve_metrics(listener: TcpListener, shared: SharedState) {
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        if let Err(e) = answer_metrics(&mut stream, &shared) {
+            log::debug!("metrics request failed: {}", e);
+        }
     }
-    env_logger::init();
-    let start_port = if let Some(arg1) = arg1 {
-        arg1.parse::<u16>()?
-    } else {
-        linkseeker::client::DEFAULT_LINKSEEKER_PORT
+}
+
+fn answer_metrics(stream: &mut TcpStream, shared: &SharedState) -> std::io::Result<()> {
+    // a client that never finishes its request would block the others
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        match stream.read(&mut buf)? {
+            0 => break,
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut line = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (line.next(), line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", shared.metrics(Instant::now())),
+        (Some("GET"), _) => ("404 Not Found", "not found, see /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "only GET is supported\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (config, log_level) = match load_config() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("error: {}\n{}", e, USAGE);
+            std::process::exit(2);
+        },
     };
-    
-    let mut tracker = LinkSeekTracker::new(start_port)?;
-    tracker.run();
+    env_logger::Builder::new().parse_filters(&log_level).init();
+
+    let metrics_addr = config.metrics_addr;
+    let mut workers = LinkSeekTracker::workers(config)?;
+    if let Some(addr) = metrics_addr {
+        let listener = TcpListener::bind(addr).map_err(|e| format!("cannot serve metrics on {}: {}", addr, e))?;
+        if !addr.ip().is_loopback() {
+            log::warn!("serving metrics on {}, reachable by anyone who can reach that address", addr);
+        }
+        log::info!("serving metrics on http://{}/metrics", listener.local_addr().unwrap_or(addr));
+        let shared = workers[0].core.shared().clone();
+        std::thread::Builder::new().name("metrics".to_string()).spawn(move || serve_metrics(listener, shared))?;
+    }
+    let mut main_worker = workers.remove(0);
+    for (n, mut tracker) in workers.into_iter().enumerate() {
+        std::thread::Builder::new().name(format!("worker-{}", n + 1)).spawn(move || {
+            if let Err(e) = tracker.run() {
+                log::error!("worker {} stopped: {}", n + 1, e);
+            }
+        })?;
+    }
+    main_worker.run()?;
     Ok(())
-}
\ No newline at end of file
+}
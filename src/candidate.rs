@@ -0,0 +1,138 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+/// Maximum number of candidates a tracker keeps and forwards for one peer
+pub const MAX_CANDIDATES: usize = 8;
+
+/// Addresses of our local interfaces a peer on the same network could reach us at, for a socket
+/// bound to `port` on every interface.
+///
+/// The standard library cannot list interfaces, so this only finds the one of the default route,
+/// per address family. That's the one a peer behind the same router sees us on.
+pub fn local_candidates(port: u16) -> Vec<SocketAddr> {
+    let probes: [SocketAddr; 2] = [
+        ([0, 0, 0, 0], 0).into(),
+        ([0u16; 8], 0).into(),
+    ];
+    // connecting a UDP socket sends nothing, it only picks the route
+    let destinations: [SocketAddr; 2] = [
+        ([192, 0, 2, 1], 9).into(),
+        ([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 9).into(),
+    ];
+    let mut candidates = Vec::new();
+    for (bind, dest) in probes.into_iter().zip(destinations) {
+        let Ok(socket) = UdpSocket::bind(bind) else {
+            continue;
+        };
+        if socket.connect(dest).is_err() {
+            continue;
+        }
+        let Ok(local) = socket.local_addr() else {
+            continue;
+        };
+        if !local.ip().is_unspecified() && !local.ip().is_loopback() {
+            candidates.push(SocketAddr::new(local.ip(), port));
+        }
+    }
+    candidates
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+        // unique local fc00::/7 and link local fe80::/10
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// Neither private, link local, loopback, multicast nor unspecified
+fn is_public(ip: IpAddr) -> bool {
+    !is_private(ip) && !ip.is_loopback() && !ip.is_multicast() && !ip.is_unspecified()
+}
+
+/// IPv4 address for IPv4-mapped IPv6 ones (`::ffff:1.2.3.4`), which is how dual-stack sockets see
+/// IPv4 peers. Compare addresses once they are canonical.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Addresses a host and a requester should punch each other on, `host` and `requester` being the
+/// addresses the tracker sees them at: those when both are of the same family, otherwise a public
+/// candidate of one of them in the family of the other. `None` when they have no family in common.
+pub fn punch_addrs(host: SocketAddr, host_candidates: &[SocketAddr], requester: SocketAddr, requester_candidates: &[SocketAddr]) -> Option<(SocketAddr, SocketAddr)> {
+    if host.is_ipv4() == requester.is_ipv4() {
+        return Some((host, requester));
+    }
+    let public_in = |candidates: &[SocketAddr], ipv4: bool| {
+        candidates.iter().copied().find(|addr| addr.is_ipv4() == ipv4 && is_public(addr.ip()))
+    };
+    if let Some(requester) = public_in(requester_candidates, host.is_ipv4()) {
+        return Some((host, requester));
+    }
+    public_in(host_candidates, requester.is_ipv4()).map(|host| (host, requester))
+}
+
+/// Same /24 for IPv4, same /64 for IPv6
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..3] == b.octets()[..3],
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..4] == b.segments()[..4],
+        _ => false,
+    }
+}
+
+/// Order in which to check the addresses of a remote: `remote` is the address the tracker saw,
+/// `candidates` the ones the remote gave, `local` our own candidates.
+///
+/// Candidates on the same subnet as one of ours come first, since they are the only way to reach a
+/// peer behind the same router when it does not hairpin. Then the address seen by the tracker, other
+/// private addresses, and the remaining ones in the order the remote gave them. Duplicates,
+/// loopback and unspecified addresses are dropped.
+pub fn prioritize(remote: SocketAddr, candidates: &[SocketAddr], local: &[SocketAddr]) -> Vec<SocketAddr> {
+    let priority = |addr: &SocketAddr| {
+        if *addr == remote {
+            1
+        } else if local.iter().any(|l| same_subnet(l.ip(), addr.ip())) {
+            0
+        } else if is_private(addr.ip()) {
+            2
+        } else {
+            3
+        }
+    };
+    let mut ordered: Vec<SocketAddr> = Vec::with_capacity(candidates.len() + 1);
+    for addr in Some(&remote).into_iter().chain(candidates) {
+        if addr.ip().is_unspecified() || addr.ip().is_loopback() || ordered.contains(addr) {
+            continue;
+        }
+        ordered.push(*addr);
+    }
+    ordered.sort_by_key(priority);
+    ordered
+}
+
+#[test]
+#[cfg(test)]
+fn prioritize_same_lan_first() {
+    let remote: SocketAddr = "1.2.3.4:5000".parse().unwrap();
+    let lan: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+    let other_lan: SocketAddr = "10.0.0.5:5000".parse().unwrap();
+    let public: SocketAddr = "5.6.7.8:5000".parse().unwrap();
+    let local: SocketAddr = "192.168.1.10:6000".parse().unwrap();
+    let candidates = [public, other_lan, lan, remote, "127.0.0.1:5000".parse().unwrap()];
+    assert_eq!(prioritize(remote, &candidates, &[local]), vec![lan, remote, other_lan, public]);
+    // not on the same network, nothing beats what the tracker saw
+    assert_eq!(prioritize(remote, &candidates, &[]), vec![remote, other_lan, lan, public]);
+}
+
+#[test]
+#[cfg(test)]
+fn punch_addrs_share_a_family() {
+    let host: SocketAddr = "1.2.3.4:5000".parse().unwrap();
+    let host_v6: SocketAddr = "[2001:db8:1::5]:5000".parse().unwrap();
+    let requester: SocketAddr = "[2001:db8:2::7]:6000".parse().unwrap();
+    let lan: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+    assert_eq!(canonical("[::ffff:1.2.3.4]:5000".parse().unwrap()), host);
+    assert_eq!(punch_addrs(host, &[lan, host_v6], requester, &[]), Some((host_v6, requester)));
+    assert_eq!(punch_addrs(host, &[lan], requester, &[]), None);
+    assert_eq!(punch_addrs(host, &[], host, &[]), Some((host, host)));
+}
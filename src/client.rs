@@ -1,4 +1,13 @@
-use std::net::{ToSocketAddrs, SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{
+    collections::VecDeque,
+    net::{ToSocketAddrs, SocketAddr, IpAddr},
+    time::{Duration, Instant}
+};
+
+use crate::{
+    common::random_u32,
+    data::{FromMiddlemanMsg, ToMiddlemanMsg}
+};
 
 pub const DEFAULT_LINKSEEKER_PORT: u16 = 61990;
 
@@ -14,10 +23,662 @@ pub fn compute_linkseeker_key(ip_addr: IpAddr) -> u8 {
     key
 }
 
+/// Link ids carry the key of the tracker that issued them in their highest byte
+pub fn make_link_id(key: u8, random: u32) -> u32 {
+    ((key as u32) << 24) | (random & 0x00ff_ffff)
+}
+
+/// Key of the tracker that issued this link id
+pub fn link_id_key(id: u32) -> u8 {
+    (id >> 24) as u8
+}
+
+/// The tracker that issued this link id, among the ones returned by `fetch_linkseekers`
+pub fn tracker_for_link_id(trackers: &[(u8, SocketAddr)], id: u32) -> Option<SocketAddr> {
+    let key = link_id_key(id);
+    trackers.iter().find(|(k, _)| *k == key).map(|(_, addr)| *addr)
+}
+
 pub fn fetch_linkseekers(address: &str, port: Option<u16>) -> Result<Vec<(u8, SocketAddr)>, std::io::Error> {
     let addrs = (address, port.unwrap_or(DEFAULT_LINKSEEKER_PORT)).to_socket_addrs()?;
     let values = addrs
         .map(|addr| (compute_linkseeker_key(addr.ip()), addr))
         .collect();
     Ok(values)
-}
\ No newline at end of file
+}
+
+/// A datagram the caller must send with its own socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transmit {
+    pub dest: SocketAddr,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// The tracker gave us an id, that can be communicated to someone else.
+    Registered { id: u32 },
+    RegisterFailed { msg: String },
+    /// The tracker dropped our registration, register again to get a new id
+    RegisterExpired { id: u32 },
+    /// The tracker ordered us to punch the remote, see `punch::Puncher::with_candidates`
+    PunchOrdered { remote: SocketAddr, delta: Option<i32>, candidates: Vec<SocketAddr> },
+    /// Result of a punch check: `ok` if our NAT keeps the same port for every destination,
+    /// otherwise `delta` is its port allocation delta if it is predictable
+    PunchChecked { ok: bool, delta: Option<i32> },
+    RequestFailed { msg: String },
+    /// The tracker accepted to proxy us to the registered `remote`: send to the tracker to reach it
+    ProxyReady { remote: SocketAddr },
+    /// Someone asked the tracker to proxy them to us: punch `relay` and send to it to reach them
+    ProxyOrdered { relay: SocketAddr, remote: Option<SocketAddr> },
+    /// Our public address, as seen by the tracker socket `socket_n`
+    AddrObserved { observed: SocketAddr, socket_n: u8 },
+    /// Our relay session is open on `relay`, peers requesting us through the proxy will be bound
+    /// to channels of it
+    Allocated { relay: SocketAddr },
+    /// No relay session: peers requesting us through the proxy get a socket of the tracker each
+    AllocateFailed { msg: String },
+    /// `peer` is relayed on `channel` of our session: frame what we send it with `relay::encode`
+    /// and send it to the relay, unframe what comes from the relay with `relay::decode`
+    ChannelBound { channel: u16, peer: SocketAddr },
+    ChannelBindFailed { channel: u16, msg: String },
+    /// The tracker stopped relaying us to `remote`, see `FromMiddlemanMsg::ProxyClosed`
+    ProxyClosed { remote: SocketAddr, msg: String },
+    /// The tracker did not answer after every retry was sent
+    Timeout,
+    /// The tracker did not answer `LinkSeekClient::what_is_my_addr`, registrations and requests
+    /// carry on
+    AddrQueryTimeout,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Delay between two sends of the same message when the tracker does not answer
+    pub retry_interval: Duration,
+    /// Number of sends before giving up with a `ClientEvent::Timeout`
+    pub max_attempts: u32,
+    /// Number of tracker sockets a punch check is sent to, on consecutive ports
+    pub punch_check_sockets: u16,
+    /// How long a punch check waits for the results covering more sockets after the first one
+    pub punch_check_wait: Duration,
+    /// Delay between two lease renewals once registered. The tracker drops registrations after
+    /// 60 seconds, and most NATs forget idle UDP mappings after 30.
+    pub renew_interval: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            retry_interval: Duration::from_millis(500),
+            max_attempts: 10,
+            punch_check_sockets: 4,
+            punch_check_wait: Duration::from_secs(1),
+            renew_interval: Duration::from_secs(20),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientState {
+    Idle,
+    Registering,
+    /// Registered and waiting for someone to request us
+    Registered { id: u32 },
+    Requesting { id: u32, use_proxy: bool },
+    /// The fullest result so far, by number of ports it covers, and until when we wait for a
+    /// fuller one
+    PunchChecking { best: Option<(usize, bool, Option<i32>)>, deadline: Option<Instant> },
+}
+
+struct PendingSend {
+    msg: ToMiddlemanMsg,
+    dests: Vec<SocketAddr>,
+    attempts: u32,
+    next_send: Instant,
+}
+
+/// Sans-IO client for the register/request/punch flow.
+///
+/// It never touches a socket: feed it the datagrams you receive with `handle_datagram` and
+/// call `handle_timeout` when `poll_timeout` expires, then send everything `poll_transmit`
+/// returns and react to what `poll_event` returns.
+pub struct LinkSeekClient {
+    tracker: SocketAddr,
+    config: ClientConfig,
+    state: ClientState,
+    pending: Option<PendingSend>,
+    /// when to renew our lease, while registered
+    next_renew: Option<Instant>,
+    /// `WhatIsMyAddr` query, independent from the rest of the flow
+    addr_query: Option<PendingSend>,
+    /// other addresses we can be reached at, sent with `Register` and `Request`
+    candidates: Vec<SocketAddr>,
+    /// `Allocate` or `ChannelBind`, independent from the rest of the flow
+    relay_query: Option<PendingSend>,
+    /// channels of our relay session already reported
+    channels: Vec<(u16, SocketAddr)>,
+    /// sent with proxy requests and channel binds
+    proxy_token: Option<String>,
+    transmits: VecDeque<Transmit>,
+    events: VecDeque<ClientEvent>,
+}
+
+impl LinkSeekClient {
+    pub fn new(tracker: SocketAddr, config: ClientConfig) -> Self {
+        Self {
+            tracker,
+            config,
+            state: ClientState::Idle,
+            pending: None,
+            next_renew: None,
+            addr_query: None,
+            candidates: Vec::new(),
+            relay_query: None,
+            channels: Vec::new(),
+            proxy_token: None,
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn tracker(&self) -> SocketAddr {
+        self.tracker
+    }
+
+    /// Addresses the remote should try besides the one the tracker sees, sent with the next
+    /// `register` or `request`. Usually `candidate::local_candidates`, and the observed address of
+    /// `ClientEvent::AddrObserved` when talking to several trackers.
+    pub fn set_candidates(&mut self, candidates: Vec<SocketAddr>) {
+        self.candidates = candidates;
+    }
+
+    pub fn candidates(&self) -> &[SocketAddr] {
+        &self.candidates
+    }
+
+    /// Credential for trackers that only proxy for clients holding one, sent with the next proxy
+    /// requests and channel binds. See `auth::make_token`.
+    pub fn set_proxy_token(&mut self, token: Option<String>) {
+        self.proxy_token = token;
+    }
+
+    /// The id given by the tracker, if we are registered
+    pub fn registered_id(&self) -> Option<u32> {
+        match self.state {
+            ClientState::Registered { id } => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Register to the tracker, a `ClientEvent::Registered` follows when it answers
+    pub fn register(&mut self, now: Instant) {
+        self.state = ClientState::Registering;
+        self.next_renew = None;
+        let candidates = self.candidates.clone();
+        self.start_sending(ToMiddlemanMsg::Register { candidates }, vec![self.tracker], now);
+    }
+
+    /// Request to connect to a registered id, a `ClientEvent::PunchOrdered` follows when it answers,
+    /// or a `ClientEvent::ProxyReady` with `use_proxy`.
+    ///
+    /// The request must go to the tracker that issued the id, see `tracker_for_link_id`.
+    pub fn request(&mut self, id: u32, use_proxy: bool, now: Instant) {
+        self.next_renew = None;
+        self.state = ClientState::Requesting { id, use_proxy };
+        let candidates = self.candidates.clone();
+        let token = self.proxy_token.clone().filter(|_| use_proxy);
+        self.start_sending(ToMiddlemanMsg::Request { id, use_proxy, candidates, token }, vec![self.tracker], now);
+    }
+
+    /// Check whether our NAT is compatible with punching, a `ClientEvent::PunchChecked` follows.
+    /// It waits for the result covering every tracker socket, or `ClientConfig::punch_check_wait`
+    /// after the first one.
+    ///
+    /// Do it before registering or requesting: the tracker remembers the port allocation delta
+    /// it measured and sends it to the remote along with the punch order.
+    pub fn punch_check(&mut self, now: Instant) {
+        self.next_renew = None;
+        self.state = ClientState::PunchChecking { best: None, deadline: None };
+        let dests = (0..self.config.punch_check_sockets)
+            .map(|i| {
+                let mut dest = self.tracker;
+                dest.set_port(self.tracker.port().wrapping_add(i));
+                dest
+            })
+            .collect();
+        self.start_sending(ToMiddlemanMsg::PunchCheck { id: random_u32() }, dests, now);
+    }
+
+    /// Ask the tracker which public address it sees us as, a `ClientEvent::AddrObserved` follows,
+    /// or a `ClientEvent::AddrQueryTimeout`.
+    ///
+    /// This does not interrupt a registration or a request in progress.
+    pub fn what_is_my_addr(&mut self, now: Instant) {
+        self.addr_query = Some(PendingSend { msg: ToMiddlemanMsg::WhatIsMyAddr, dests: vec![self.tracker], attempts: 0, next_send: now });
+        Self::retry(&mut self.addr_query, &self.config, now, &mut self.transmits);
+    }
+
+    /// Open a relay session, a `ClientEvent::Allocated` or `ClientEvent::AllocateFailed` follows.
+    /// Peers requesting us through the proxy then get a `ClientEvent::ChannelBound` each, instead
+    /// of a socket of the tracker each.
+    ///
+    /// The session is kept alive by the lease renewals while registered.
+    pub fn allocate(&mut self, now: Instant) {
+        self.relay_query = Some(PendingSend { msg: ToMiddlemanMsg::Allocate, dests: vec![self.tracker], attempts: 0, next_send: now });
+        Self::retry(&mut self.relay_query, &self.config, now, &mut self.transmits);
+    }
+
+    /// Relay `peer` on `channel` of our session, a `ClientEvent::ChannelBound` or
+    /// `ClientEvent::ChannelBindFailed` follows.
+    /// Channels are between `relay::CHANNEL_MIN` and `relay::CHANNEL_MAX`.
+    pub fn bind_channel(&mut self, channel: u16, peer: SocketAddr, now: Instant) {
+        let msg = ToMiddlemanMsg::ChannelBind { channel, peer, token: self.proxy_token.clone() };
+        self.relay_query = Some(PendingSend { msg, dests: vec![self.tracker], attempts: 0, next_send: now });
+        Self::retry(&mut self.relay_query, &self.config, now, &mut self.transmits);
+    }
+
+    fn start_sending(&mut self, msg: ToMiddlemanMsg, dests: Vec<SocketAddr>, now: Instant) {
+        self.pending = Some(PendingSend { msg, dests, attempts: 0, next_send: now });
+        self.handle_timeout(now);
+    }
+
+    /// Returns whether or not the datagram was a linkseeker message coming from our tracker.
+    ///
+    /// Everything else should be handled by the caller. Answers may come from any socket of the tracker.
+    pub fn handle_datagram(&mut self, bytes: &[u8], from: SocketAddr, now: Instant) -> bool {
+        if from.ip() != self.tracker.ip() {
+            return false;
+        }
+        let Some(msg) = FromMiddlemanMsg::parse(bytes) else {
+            return false;
+        };
+        match (msg, self.state) {
+            (FromMiddlemanMsg::YourAddr { observed, socket_n }, _) if self.addr_query.is_some() => {
+                self.addr_query = None;
+                self.events.push_back(ClientEvent::AddrObserved { observed, socket_n });
+            },
+            (FromMiddlemanMsg::AllocateOk { port }, _) => {
+                if matches!(self.relay_query, Some(PendingSend { msg: ToMiddlemanMsg::Allocate, .. })) {
+                    self.relay_query = None;
+                    let mut relay = self.tracker;
+                    relay.set_port(port);
+                    self.events.push_back(ClientEvent::Allocated { relay });
+                }
+            },
+            (FromMiddlemanMsg::ChannelBound { channel, peer }, _) => {
+                if matches!(self.relay_query, Some(PendingSend { msg: ToMiddlemanMsg::ChannelBind { channel: c, .. }, .. }) if c == channel) {
+                    self.relay_query = None;
+                }
+                // bound on the requester's behalf, or a duplicate
+                if !self.channels.contains(&(channel, peer)) {
+                    self.channels.retain(|(c, _)| *c != channel);
+                    self.channels.push((channel, peer));
+                    self.events.push_back(ClientEvent::ChannelBound { channel, peer });
+                }
+            },
+            (FromMiddlemanMsg::ChannelBindErr { channel, msg }, _) => {
+                if matches!(self.relay_query, Some(PendingSend { msg: ToMiddlemanMsg::ChannelBind { channel: c, .. }, .. }) if c == channel) {
+                    self.relay_query = None;
+                    self.events.push_back(ClientEvent::ChannelBindFailed { channel, msg });
+                }
+            },
+            (FromMiddlemanMsg::ProxyClosed { remote, msg }, _) => {
+                self.channels.retain(|(_, peer)| *peer != remote);
+                self.events.push_back(ClientEvent::ProxyClosed { remote, msg });
+            },
+            (FromMiddlemanMsg::RegisterOk { id }, ClientState::Registering) => {
+                self.pending = None;
+                self.state = ClientState::Registered { id };
+                self.next_renew = Some(now + self.config.renew_interval);
+                self.events.push_back(ClientEvent::Registered { id });
+            },
+            (FromMiddlemanMsg::RenewOk { id }, ClientState::Registered { id: our_id }) if id == our_id => {
+                self.pending = None;
+            },
+            (FromMiddlemanMsg::RegisterExpired { id }, ClientState::Registered { id: our_id }) if id == our_id => {
+                self.pending = None;
+                self.next_renew = None;
+                self.state = ClientState::Idle;
+                self.events.push_back(ClientEvent::RegisterExpired { id });
+            },
+            (FromMiddlemanMsg::RegisterErr { msg }, ClientState::Registering) => {
+                self.pending = None;
+                self.state = ClientState::Idle;
+                self.events.push_back(ClientEvent::RegisterFailed { msg });
+            },
+            (FromMiddlemanMsg::RequestErr { msg }, ClientState::Requesting { .. }) => {
+                self.pending = None;
+                self.state = ClientState::Idle;
+                self.events.push_back(ClientEvent::RequestFailed { msg });
+            },
+            (FromMiddlemanMsg::PunchOrder { remote, delta, candidates }, ClientState::Registered { .. }) => {
+                self.events.push_back(ClientEvent::PunchOrdered { remote, delta, candidates });
+            },
+            (FromMiddlemanMsg::PunchOrder { remote, delta, candidates }, ClientState::Requesting { .. }) => {
+                self.pending = None;
+                self.state = ClientState::Idle;
+                self.events.push_back(ClientEvent::PunchOrdered { remote, delta, candidates });
+            },
+            (FromMiddlemanMsg::ProxyResult { remote, ok }, ClientState::Requesting { use_proxy: true, .. }) => {
+                self.pending = None;
+                self.state = ClientState::Idle;
+                if ok {
+                    self.events.push_back(ClientEvent::ProxyReady { remote });
+                } else {
+                    self.events.push_back(ClientEvent::RequestFailed { msg: "proxy refused".to_string() });
+                }
+            },
+            (FromMiddlemanMsg::PunchLinkseeker { port, remote }, ClientState::Registered { .. }) => {
+                let mut relay = self.tracker;
+                relay.set_port(port);
+                self.events.push_back(ClientEvent::ProxyOrdered { relay, remote });
+            },
+            (FromMiddlemanMsg::PunchCheckResult { ok, ports, delta }, ClientState::PunchChecking { best, deadline }) => {
+                // the tracker answers each socket it hears from, with every port it saw so far
+                let best = match best {
+                    Some(best) if best.0 > ports.len() => best,
+                    _ => (ports.len(), ok, delta),
+                };
+                let deadline = deadline.unwrap_or(now + self.config.punch_check_wait);
+                self.state = ClientState::PunchChecking { best: Some(best), deadline: Some(deadline) };
+                if best.0 >= self.config.punch_check_sockets as usize {
+                    self.finish_punch_check();
+                }
+            },
+            _ => {
+                // duplicates (the tracker sends everything twice) or answers we did not ask for
+            },
+        }
+        true
+    }
+
+    /// Sends retries and emits `ClientEvent::Timeout` when we ran out of them, renews our lease when due
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if let ClientState::PunchChecking { deadline: Some(deadline), .. } = self.state {
+            if now >= deadline {
+                self.finish_punch_check();
+            }
+        }
+        if let (Some(next_renew), ClientState::Registered { id }) = (self.next_renew, self.state) {
+            if now >= next_renew {
+                self.next_renew = Some(now + self.config.renew_interval);
+                // sent by the retry below, along with the other queries
+                self.pending = Some(PendingSend { msg: ToMiddlemanMsg::Renew { id }, dests: vec![self.tracker], attempts: 0, next_send: now });
+            }
+        }
+        if Self::retry(&mut self.pending, &self.config, now, &mut self.transmits) {
+            if let ClientState::PunchChecking { best: Some(_), .. } = self.state {
+                self.finish_punch_check();
+            } else {
+                self.next_renew = None;
+                self.state = ClientState::Idle;
+                self.events.push_back(ClientEvent::Timeout);
+            }
+        }
+        if Self::retry(&mut self.addr_query, &self.config, now, &mut self.transmits) {
+            self.events.push_back(ClientEvent::AddrQueryTimeout);
+        }
+        let relay_query = self.relay_query.as_ref().map(|p| p.msg.clone());
+        if Self::retry(&mut self.relay_query, &self.config, now, &mut self.transmits) {
+            let msg = "tracker did not answer".to_string();
+            match relay_query {
+                Some(ToMiddlemanMsg::ChannelBind { channel, .. }) => self.events.push_back(ClientEvent::ChannelBindFailed { channel, msg }),
+                _ => self.events.push_back(ClientEvent::AllocateFailed { msg }),
+            }
+        }
+    }
+
+    /// Reports the fullest result of the punch check
+    fn finish_punch_check(&mut self) {
+        if let ClientState::PunchChecking { best: Some((_, ok, delta)), .. } = self.state {
+            self.pending = None;
+            self.state = ClientState::Idle;
+            self.events.push_back(ClientEvent::PunchChecked { ok, delta });
+        }
+    }
+
+    /// Sends the pending message again if due. Returns whether or not we ran out of attempts.
+    fn retry(pending: &mut Option<PendingSend>, config: &ClientConfig, now: Instant, transmits: &mut VecDeque<Transmit>) -> bool {
+        let Some(p) = pending else {
+            return false;
+        };
+        if now < p.next_send {
+            return false;
+        }
+        if p.attempts >= config.max_attempts {
+            *pending = None;
+            return true;
+        }
+        p.attempts += 1;
+        p.next_send = now + config.retry_interval;
+        let bytes = p.msg.serialize();
+        for dest in &p.dests {
+            transmits.push_back(Transmit { dest: *dest, bytes: bytes.clone() });
+        }
+        false
+    }
+
+    /// When `handle_timeout` should be called next
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let punch_check = match self.state {
+            ClientState::PunchChecking { deadline, .. } => deadline,
+            _ => None,
+        };
+        [&self.pending, &self.addr_query, &self.relay_query].into_iter()
+            .filter_map(|p| p.as_ref().map(|p| p.next_send))
+            .chain(self.next_renew)
+            .chain(punch_check)
+            .min()
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<ClientEvent> {
+        self.events.pop_front()
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn link_id_routes_to_tracker() {
+    let a: SocketAddr = "1.1.1.1:61990".parse().unwrap();
+    let b: SocketAddr = "2.2.2.2:61990".parse().unwrap();
+    let trackers = vec![(compute_linkseeker_key(a.ip()), a), (compute_linkseeker_key(b.ip()), b)];
+    let id = make_link_id(compute_linkseeker_key(b.ip()), 0xdeadbeef);
+    assert_eq!(link_id_key(id), 8);
+    assert_eq!(id & 0x00ff_ffff, 0xadbeef);
+    assert_eq!(tracker_for_link_id(&trackers, id), Some(b));
+    assert_eq!(tracker_for_link_id(&trackers, make_link_id(0, 1)), None);
+}
+
+#[test]
+#[cfg(test)]
+fn client_register_retries_then_registers() {
+    let tracker: SocketAddr = "127.0.0.1:61990".parse().unwrap();
+    let now = Instant::now();
+    let mut client = LinkSeekClient::new(tracker, ClientConfig::default());
+    client.register(now);
+    let t = client.poll_transmit().unwrap();
+    assert_eq!(t.dest, tracker);
+    assert_eq!(ToMiddlemanMsg::parse(&t.bytes), Some(ToMiddlemanMsg::Register { candidates: vec![] }));
+    assert!(client.poll_transmit().is_none());
+
+    // nothing came back: retry
+    let later = now + ClientConfig::default().retry_interval;
+    assert_eq!(client.poll_timeout(), Some(later));
+    client.handle_timeout(later);
+    assert!(client.poll_transmit().is_some());
+
+    let answer = FromMiddlemanMsg::RegisterOk { id: 42 }.serialize();
+    assert!(client.handle_datagram(&answer, tracker, later));
+    assert!(client.handle_datagram(&answer, tracker, later));
+    assert_eq!(client.poll_event(), Some(ClientEvent::Registered { id: 42 }));
+    assert_eq!(client.poll_event(), None);
+    assert_eq!(client.poll_timeout(), Some(later + ClientConfig::default().renew_interval));
+
+    let remote: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+    let candidates = vec!["192.168.1.20:5678".parse().unwrap()];
+    let order = FromMiddlemanMsg::PunchOrder { remote, delta: Some(1), candidates: candidates.clone() }.serialize();
+    assert!(client.handle_datagram(&order, tracker, later));
+    assert_eq!(client.poll_event(), Some(ClientEvent::PunchOrdered { remote, delta: Some(1), candidates }));
+}
+
+#[test]
+#[cfg(test)]
+fn client_request_times_out() {
+    let tracker: SocketAddr = "127.0.0.1:61990".parse().unwrap();
+    let config = ClientConfig { retry_interval: Duration::from_secs(1), max_attempts: 3, ..Default::default() };
+    let mut now = Instant::now();
+    let mut client = LinkSeekClient::new(tracker, config);
+    client.request(1234, false, now);
+    for _ in 0..3 {
+        let t = client.poll_transmit().unwrap();
+        assert_eq!(ToMiddlemanMsg::parse(&t.bytes), Some(ToMiddlemanMsg::Request { id: 1234, use_proxy: false, candidates: vec![], token: None }));
+        now += Duration::from_secs(1);
+        client.handle_timeout(now);
+    }
+    assert!(client.poll_transmit().is_none());
+    assert_eq!(client.poll_event(), Some(ClientEvent::Timeout));
+    assert!(!client.handle_datagram(b"not for us", tracker, now));
+}
+
+
+#[test]
+#[cfg(test)]
+fn client_punch_check_every_socket() {
+    let tracker: SocketAddr = "127.0.0.1:61990".parse().unwrap();
+    let now = Instant::now();
+    let mut client = LinkSeekClient::new(tracker, ClientConfig::default());
+    client.punch_check(now);
+    let mut ports = Vec::new();
+    while let Some(t) = client.poll_transmit() {
+        assert!(matches!(ToMiddlemanMsg::parse(&t.bytes), Some(ToMiddlemanMsg::PunchCheck { .. })));
+        ports.push(t.dest.port());
+    }
+    assert_eq!(ports, vec![61990, 61991, 61992, 61993]);
+
+    let mut from = tracker;
+    from.set_port(61991);
+    // the first result only covers 2 ports, the last ones cover every socket
+    let result = FromMiddlemanMsg::PunchCheckResult { ok: false, ports: vec![3000, 3001], delta: Some(1) };
+    assert!(client.handle_datagram(&result.serialize(), from, now));
+    assert_eq!(client.poll_event(), None);
+    let result = FromMiddlemanMsg::PunchCheckResult { ok: false, ports: vec![3000, 3001, 3005], delta: None };
+    assert!(client.handle_datagram(&result.serialize(), from, now));
+    assert!(client.handle_datagram(&result.serialize(), from, now));
+    assert_eq!(client.poll_event(), None);
+    let result = FromMiddlemanMsg::PunchCheckResult { ok: false, ports: vec![3000, 3001, 3005, 3006], delta: None };
+    assert!(client.handle_datagram(&result.serialize(), from, now));
+    assert_eq!(client.poll_event(), Some(ClientEvent::PunchChecked { ok: false, delta: None }));
+    assert_eq!(client.poll_timeout(), None);
+
+    // a socket never answers: the fullest result once the wait is over
+    client.punch_check(now);
+    while client.poll_transmit().is_some() {}
+    let result = FromMiddlemanMsg::PunchCheckResult { ok: true, ports: vec![3000, 3000], delta: Some(0) };
+    assert!(client.handle_datagram(&result.serialize(), from, now));
+    let result = FromMiddlemanMsg::PunchCheckResult { ok: true, ports: vec![3000, 3000, 3000], delta: Some(0) };
+    assert!(client.handle_datagram(&result.serialize(), from, now));
+    assert_eq!(client.poll_event(), None);
+    let mut later = now;
+    let event = loop {
+        if let Some(event) = client.poll_event() {
+            break event;
+        }
+        later = client.poll_timeout().unwrap();
+        client.handle_timeout(later);
+    };
+    assert_eq!(event, ClientEvent::PunchChecked { ok: true, delta: Some(0) });
+    assert_eq!(later, now + ClientConfig::default().punch_check_wait);
+    assert_eq!(client.poll_timeout(), None);
+}
+
+#[test]
+#[cfg(test)]
+fn client_renews_lease() {
+    let tracker: SocketAddr = "127.0.0.1:61990".parse().unwrap();
+    let config = ClientConfig::default();
+    let mut now = Instant::now();
+    let mut client = LinkSeekClient::new(tracker, config.clone());
+    client.register(now);
+    client.handle_datagram(&FromMiddlemanMsg::RegisterOk { id: 42 }.serialize(), tracker, now);
+    assert_eq!(client.poll_event(), Some(ClientEvent::Registered { id: 42 }));
+    while client.poll_transmit().is_some() {}
+
+    // the renew does not hold back the retry of another query
+    client.what_is_my_addr(now);
+    while client.poll_transmit().is_some() {}
+    now += config.renew_interval;
+    client.handle_timeout(now);
+    let t = client.poll_transmit().unwrap();
+    assert_eq!(ToMiddlemanMsg::parse(&t.bytes), Some(ToMiddlemanMsg::Renew { id: 42 }));
+    let t = client.poll_transmit().unwrap();
+    assert_eq!(ToMiddlemanMsg::parse(&t.bytes), Some(ToMiddlemanMsg::WhatIsMyAddr));
+    let observed: SocketAddr = "5.6.7.8:4321".parse().unwrap();
+    client.handle_datagram(&FromMiddlemanMsg::YourAddr { observed, socket_n: 0 }.serialize(), tracker, now);
+    assert_eq!(client.poll_event(), Some(ClientEvent::AddrObserved { observed, socket_n: 0 }));
+    client.handle_datagram(&FromMiddlemanMsg::RenewOk { id: 42 }.serialize(), tracker, now);
+    assert_eq!(client.poll_timeout(), Some(now + config.renew_interval));
+    assert_eq!(client.registered_id(), Some(42));
+
+    client.handle_datagram(&FromMiddlemanMsg::RegisterExpired { id: 42 }.serialize(), tracker, now);
+    assert_eq!(client.poll_event(), Some(ClientEvent::RegisterExpired { id: 42 }));
+    assert_eq!(client.registered_id(), None);
+    assert_eq!(client.poll_timeout(), None);
+}
+
+#[test]
+#[cfg(test)]
+fn client_what_is_my_addr_keeps_registration() {
+    let tracker: SocketAddr = "127.0.0.1:61990".parse().unwrap();
+    let now = Instant::now();
+    let mut client = LinkSeekClient::new(tracker, ClientConfig::default());
+    client.register(now);
+    client.handle_datagram(&FromMiddlemanMsg::RegisterOk { id: 42 }.serialize(), tracker, now);
+    assert_eq!(client.poll_event(), Some(ClientEvent::Registered { id: 42 }));
+    while client.poll_transmit().is_some() {}
+
+    client.what_is_my_addr(now);
+    let t = client.poll_transmit().unwrap();
+    assert_eq!(ToMiddlemanMsg::parse(&t.bytes), Some(ToMiddlemanMsg::WhatIsMyAddr));
+    let observed: SocketAddr = "5.6.7.8:4321".parse().unwrap();
+    client.handle_datagram(&FromMiddlemanMsg::YourAddr { observed, socket_n: 0 }.serialize(), tracker, now);
+    assert_eq!(client.poll_event(), Some(ClientEvent::AddrObserved { observed, socket_n: 0 }));
+    assert_eq!(client.registered_id(), Some(42));
+
+    // no answer: the registration is not the one that timed out
+    client.what_is_my_addr(now);
+    let event = loop {
+        let now = client.poll_timeout().unwrap();
+        client.handle_timeout(now);
+        if let Some(event) = client.poll_event() {
+            break event;
+        }
+    };
+    assert_eq!(event, ClientEvent::AddrQueryTimeout);
+    assert_eq!(client.registered_id(), Some(42));
+}
+
+#[test]
+#[cfg(test)]
+fn client_relay_queries_fail_on_their_own() {
+    let tracker: SocketAddr = "127.0.0.1:61990".parse().unwrap();
+    let now = Instant::now();
+    let mut client = LinkSeekClient::new(tracker, ClientConfig::default());
+    let unanswered = |client: &mut LinkSeekClient| loop {
+        let now = client.poll_timeout().unwrap();
+        client.handle_timeout(now);
+        if let Some(event) = client.poll_event() {
+            break event;
+        }
+    };
+
+    client.allocate(now);
+    assert_eq!(unanswered(&mut client), ClientEvent::AllocateFailed { msg: "tracker did not answer".to_string() });
+    let peer: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+    client.bind_channel(0x4000, peer, now);
+    assert_eq!(unanswered(&mut client), ClientEvent::ChannelBindFailed { channel: 0x4000, msg: "tracker did not answer".to_string() });
+    assert_eq!(client.poll_timeout(), None);
+}
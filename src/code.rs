@@ -0,0 +1,131 @@
+/// Crockford's base32 alphabet: no I, L, O or U, so codes read aloud are hard to get wrong
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+/// 7 characters carry 35 bits, enough for a `u32`
+const DATA_LEN: usize = 7;
+
+/// Human-friendly form of a link id: 7 base32 characters and a check character, like `0FJW-FACG`.
+///
+/// Parsing is case insensitive, ignores dashes and spaces, reads `I`/`L` as `1` and `O` as `0`,
+/// and rejects any code whose check character does not match, so a typo does not silently
+/// point to someone else's id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LinkCode(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkCodeError {
+    /// The code does not have 8 characters
+    Length,
+    InvalidChar(char),
+    /// The check character does not match, there is a typo somewhere
+    Checksum,
+    /// The code is too large to be a link id
+    Overflow,
+}
+
+impl std::fmt::Display for LinkCodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkCodeError::Length => write!(f, "a link code has 8 characters"),
+            LinkCodeError::InvalidChar(c) => write!(f, "'{}' is not valid in a link code", c),
+            LinkCodeError::Checksum => write!(f, "the link code has a typo"),
+            LinkCodeError::Overflow => write!(f, "the link code is too large"),
+        }
+    }
+}
+
+impl std::error::Error for LinkCodeError {}
+
+fn decode_char(c: char) -> Option<u8> {
+    let c = match c.to_ascii_uppercase() {
+        'I' | 'L' => '1',
+        'O' => '0',
+        c => c,
+    };
+    ALPHABET.iter().position(|a| *a as char == c).map(|p| p as u8)
+}
+
+/// Luhn mod 32: catches every single character error and most swaps of two neighbours
+fn check_digit(digits: &[u8]) -> u8 {
+    let mut factor = 2;
+    let mut sum = 0u32;
+    for digit in digits.iter().rev() {
+        let addend = factor * *digit as u32;
+        factor = if factor == 2 { 1 } else { 2 };
+        sum += addend / 32 + addend % 32;
+    }
+    ((32 - sum % 32) % 32) as u8
+}
+
+impl std::fmt::Display for LinkCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut digits = [0u8; DATA_LEN];
+        for (i, digit) in digits.iter_mut().rev().enumerate() {
+            *digit = ((self.0 as u64 >> (5 * i)) & 0x1f) as u8;
+        }
+        let check = check_digit(&digits);
+        for (i, digit) in digits.iter().chain(Some(&check)).enumerate() {
+            if i == 4 {
+                write!(f, "-")?;
+            }
+            write!(f, "{}", ALPHABET[*digit as usize] as char)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for LinkCode {
+    type Err = LinkCodeError;
+
+    fn from_str(input: &str) -> Result<LinkCode, LinkCodeError> {
+        let mut digits = Vec::with_capacity(DATA_LEN + 1);
+        for c in input.chars().filter(|c| *c != '-' && !c.is_whitespace()) {
+            digits.push(decode_char(c).ok_or(LinkCodeError::InvalidChar(c))?);
+        }
+        let Some((check, data)) = digits.split_last() else {
+            return Err(LinkCodeError::Length);
+        };
+        if data.len() != DATA_LEN {
+            return Err(LinkCodeError::Length);
+        }
+        if check_digit(data) != *check {
+            return Err(LinkCodeError::Checksum);
+        }
+        let value = data.iter().fold(0u64, |acc, d| (acc << 5) | *d as u64);
+        let value = u32::try_from(value).map_err(|_| LinkCodeError::Overflow)?;
+        Ok(LinkCode(value))
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn link_code_roundtrip() {
+    for id in [0, 1, 0xdeadbeef, u32::MAX] {
+        let code = LinkCode(id).to_string();
+        assert_eq!(code.len(), 9);
+        assert_eq!(code.parse::<LinkCode>(), Ok(LinkCode(id)));
+        assert_eq!(code.to_lowercase().replace('-', " ").parse::<LinkCode>(), Ok(LinkCode(id)));
+    }
+    assert_eq!(LinkCode(0x1f2e3d4c).to_string(), "0FJW-FACG");
+    assert_eq!("0000-0000".parse::<LinkCode>(), Ok(LinkCode(0)));
+    assert_eq!("OOOO-OOOO".parse::<LinkCode>(), Ok(LinkCode(0)));
+}
+
+#[test]
+#[cfg(test)]
+fn link_code_rejects_typos() {
+    let code = LinkCode(0x12345678).to_string().replace('-', "");
+    let chars = code.chars().collect::<Vec<_>>();
+    for i in 0..chars.len() {
+        // every single substitution
+        for replacement in ALPHABET.iter().map(|c| *c as char).filter(|c| *c != chars[i]) {
+            let mut typo = chars.clone();
+            typo[i] = replacement;
+            let typo = typo.iter().collect::<String>();
+            assert!(typo.parse::<LinkCode>().is_err(), "{} was accepted", typo);
+        }
+    }
+    assert_eq!("1234".parse::<LinkCode>(), Err(LinkCodeError::Length));
+    assert_eq!("1234-567U".parse::<LinkCode>(), Err(LinkCodeError::InvalidChar('U')));
+    let too_large = format!("ZZZZZZZ{}", ALPHABET[check_digit(&[31; DATA_LEN]) as usize] as char);
+    assert_eq!(too_large.parse::<LinkCode>(), Err(LinkCodeError::Overflow));
+}
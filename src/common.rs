@@ -1,4 +1,12 @@
 
 pub const UDPUNCH_ID: &str = "#lnksk@";
 pub const UDPUNCH_ID_BYTES: &[u8] = b"#lnksk@";
-pub const UDPUNCH_ID_LEN: usize = UDPUNCH_ID_BYTES.len();
\ No newline at end of file
+pub const UDPUNCH_ID_LEN: usize = UDPUNCH_ID_BYTES.len();
+
+/// Random number from the std hasher seed, so the client side does not need `rand`
+pub(crate) fn random_u32() -> u32 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u64(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64));
+    hasher.finish() as u32
+}
@@ -0,0 +1,637 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant}
+};
+
+use crate::{
+    client::{tracker_for_link_id, ClientConfig, ClientEvent, LinkSeekClient, Transmit},
+    common::random_u32,
+    data::ToMiddlemanMsg,
+    lan::{LanConfig, LanDiscovery, LanEvent},
+    punch::{PunchConfig, PunchEvent, Puncher}
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPath {
+    /// The hole was punched, we talk to the peer directly
+    Direct,
+    /// Everything goes through the tracker proxy
+    Relay,
+    /// Everything goes through our relay session on the tracker, framed for this channel with
+    /// `relay::encode` and `relay::decode`
+    Channel { channel: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectEvent {
+    Registered { id: u32 },
+    /// Send to `remote` to reach `peer`: the peer itself for a direct path, the tracker for a relayed one.
+    ///
+    /// The tracker drops what is relayed during the first 250ms, to get past the DmZ of some routers.
+    Connected { peer: SocketAddr, remote: SocketAddr, path: ConnectionPath },
+    /// Also sent when the tracker stops relaying a connected peer
+    Failed { peer: Option<SocketAddr>, msg: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectConfig {
+    pub client: ClientConfig,
+    pub punch: PunchConfig,
+    /// How long the host waits for the relay after its punch failed
+    pub relay_wait: Duration,
+    /// Look for the host on the local network before asking the tracker, and announce our id
+    /// there once registered when hosting. The socket must have broadcast enabled, and the
+    /// announces must reach the other hosts' discovery sockets (`lan::bind_discovery_socket`).
+    pub lan: Option<LanConfig>,
+    /// Port of the socket the connector's datagrams go through, announced along with our id on
+    /// the local network
+    pub local_port: u16,
+    /// Open a relay session once registered, so every peer relayed to us goes through the socket
+    /// of the tracker we already talk to, on a channel each
+    pub relay_channels: bool,
+}
+
+impl Default for ConnectConfig {
+    fn default() -> Self {
+        Self {
+            client: ClientConfig::default(),
+            punch: PunchConfig::default(),
+            relay_wait: Duration::from_secs(10),
+            lan: None,
+            local_port: 0,
+            relay_channels: false,
+        }
+    }
+}
+
+enum Attempt {
+    Punching(Puncher),
+    /// the punch failed, waiting for the proxy to be set up
+    WaitingRelay { deadline: Instant },
+    /// the puncher is kept around to answer late hellos from the peer
+    Direct(Puncher),
+    Relay,
+}
+
+/// Sans-IO connection to a peer, direct if possible, through the tracker proxy otherwise.
+///
+/// Built on `LinkSeekClient` and `Puncher`, and driven the same way. When punching times out, the
+/// requester asks the tracker for a proxy and the tracker orders the host to punch it: both sides
+/// end up with a single `ConnectEvent::Connected` whatever the path.
+pub struct LinkSeekConnector {
+    client: LinkSeekClient,
+    config: ConnectConfig,
+    /// id we asked to connect to, if we are the requester
+    requested: Option<u32>,
+    /// peers whose punch failed, all waiting on the client's pending proxy request for `requested`.
+    /// Empty when no proxy request is pending.
+    relay_waiters: Vec<SocketAddr>,
+    lan: Option<LanDiscovery>,
+    /// the host found on the local network, punched before asking the tracker
+    lan_peer: Option<SocketAddr>,
+    attempts: Vec<(SocketAddr, Attempt)>,
+    transmits: VecDeque<Transmit>,
+    events: VecDeque<ConnectEvent>,
+}
+
+impl LinkSeekConnector {
+    pub fn new(tracker: SocketAddr, config: ConnectConfig) -> Self {
+        Self {
+            client: LinkSeekClient::new(tracker, config.client.clone()),
+            lan: config.lan.clone().map(LanDiscovery::new),
+            config,
+            requested: None,
+            relay_waiters: Vec::new(),
+            lan_peer: None,
+            attempts: Vec::new(),
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Addresses the peer should try besides the one the tracker sees, see `LinkSeekClient::set_candidates`
+    pub fn set_candidates(&mut self, candidates: Vec<SocketAddr>) {
+        self.client.set_candidates(candidates);
+    }
+
+    /// See `LinkSeekClient::set_proxy_token`
+    pub fn set_proxy_token(&mut self, token: Option<String>) {
+        self.client.set_proxy_token(token);
+    }
+
+    /// Register to the tracker and accept every peer requesting our id, on the local network too
+    /// with `ConnectConfig::lan`
+    pub fn host(&mut self, now: Instant) {
+        self.client.register(now);
+        self.process(now);
+    }
+
+    /// Connector to the tracker that issued this link id, among the ones returned by
+    /// `client::fetch_linkseekers`. `None` if none of them issued it.
+    pub fn for_link_id(trackers: &[(u8, SocketAddr)], id: u32, config: ConnectConfig) -> Option<Self> {
+        let tracker = tracker_for_link_id(trackers, id)?;
+        Some(Self::new(tracker, config))
+    }
+
+    /// Connect to the host registered with this id
+    pub fn connect(&mut self, id: u32, now: Instant) {
+        self.requested = Some(id);
+        match &mut self.lan {
+            Some(lan) => lan.query(id, now),
+            None => {
+                self.relay_waiters.clear();
+                self.client.request(id, false, now);
+            },
+        }
+        self.process(now);
+    }
+
+    /// Punch a peer that found us without the tracker nor our `ConnectConfig::lan`
+    pub fn accept(&mut self, peer: SocketAddr, now: Instant) {
+        if self.attempt_mut(peer).is_none() {
+            let puncher = Puncher::new(peer, self.config.punch.clone(), now);
+            self.attempts.push((peer, Attempt::Punching(puncher)));
+        }
+        self.process(now);
+    }
+
+    /// Returns whether or not the datagram was a linkseeker message, everything else is yours
+    pub fn handle_datagram(&mut self, bytes: &[u8], from: SocketAddr, now: Instant) -> bool {
+        let consumed = self.client.handle_datagram(bytes, from, now)
+            || self.lan.as_mut().is_some_and(|lan| lan.handle_datagram(bytes, from, now))
+            || self.attempts.iter_mut().any(|(_, attempt)| match attempt {
+                Attempt::Punching(puncher) | Attempt::Direct(puncher) => puncher.handle_datagram(bytes, from, now),
+                _ => false,
+            });
+        self.process(now);
+        consumed
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.client.handle_timeout(now);
+        if let Some(lan) = &mut self.lan {
+            lan.handle_timeout(now);
+        }
+        for (_, attempt) in &mut self.attempts {
+            if let Attempt::Punching(puncher) = attempt {
+                puncher.handle_timeout(now);
+            }
+        }
+        let mut i = 0;
+        while i < self.attempts.len() {
+            match self.attempts[i] {
+                (peer, Attempt::WaitingRelay { deadline }) if now >= deadline => {
+                    self.attempts.remove(i);
+                    self.relay_waiters.retain(|p| *p != peer);
+                    self.events.push_back(ConnectEvent::Failed { peer: Some(peer), msg: "relay was never set up".to_string() });
+                },
+                _ => i += 1,
+            }
+        }
+        self.process(now);
+    }
+
+    fn attempt_mut(&mut self, peer: SocketAddr) -> Option<&mut Attempt> {
+        self.attempts.iter_mut().find(|(p, _)| *p == peer).map(|(_, attempt)| attempt)
+    }
+
+    /// Fail the attempts waiting for the proxy, if the failure is about the proxy request
+    fn fail(&mut self, peers: Vec<SocketAddr>, msg: String) {
+        if peers.is_empty() {
+            self.events.push_back(ConnectEvent::Failed { peer: None, msg });
+            return;
+        }
+        self.attempts.retain(|(p, a)| !peers.contains(p) || !matches!(a, Attempt::WaitingRelay { .. }));
+        for peer in peers {
+            self.events.push_back(ConnectEvent::Failed { peer: Some(peer), msg: msg.clone() });
+        }
+    }
+
+    fn on_client_event(&mut self, event: ClientEvent, now: Instant) {
+        match event {
+            ClientEvent::Registered { id } => {
+                if self.config.relay_channels {
+                    self.client.allocate(now);
+                }
+                if let Some(lan) = &mut self.lan {
+                    lan.announce(id, self.config.local_port, now);
+                }
+                self.events.push_back(ConnectEvent::Registered { id });
+            },
+            ClientEvent::RequestFailed { msg } => {
+                let peers = std::mem::take(&mut self.relay_waiters);
+                self.fail(peers, msg)
+            },
+            // the tracker binds channels on its own, no attempt waits on one
+            ClientEvent::RegisterFailed { msg } | ClientEvent::ChannelBindFailed { msg, .. } => self.fail(Vec::new(), msg),
+            ClientEvent::Timeout => {
+                let peers = std::mem::take(&mut self.relay_waiters);
+                self.fail(peers, "tracker did not answer".to_string())
+            },
+            ClientEvent::RegisterExpired { id } => {
+                if let Some(lan) = &mut self.lan {
+                    lan.stop_announcing(id);
+                }
+                self.fail(Vec::new(), "registration expired".to_string())
+            },
+            ClientEvent::PunchOrdered { remote, delta, candidates } => {
+                if self.attempt_mut(remote).is_none() {
+                    let local = self.client.candidates();
+                    let puncher = Puncher::with_candidates(remote, delta, &candidates, local, self.config.punch.clone(), now);
+                    self.attempts.push((remote, Attempt::Punching(puncher)));
+                }
+            },
+            ClientEvent::ProxyReady { remote } => {
+                // every waiter asked for the same id, the relay to its host replaces their attempts
+                let waiters = std::mem::take(&mut self.relay_waiters);
+                self.attempts.retain(|(p, a)| *p == remote || !waiters.contains(p) || !matches!(a, Attempt::WaitingRelay { .. }));
+                match self.attempt_mut(remote) {
+                    Some(attempt) => *attempt = Attempt::Relay,
+                    None => self.attempts.push((remote, Attempt::Relay)),
+                }
+                let relay = self.client.tracker();
+                self.events.push_back(ConnectEvent::Connected { peer: remote, remote: relay, path: ConnectionPath::Relay });
+            },
+            ClientEvent::ProxyOrdered { relay, remote } => {
+                let peer = remote
+                    .or_else(|| self.attempts.iter().find(|(_, a)| matches!(a, Attempt::WaitingRelay { .. })).map(|(p, _)| *p));
+                let Some(peer) = peer else {
+                    // nobody we know of, there is no peer to report connected
+                    return;
+                };
+                // punch the tracker so our NAT lets its relayed packets in
+                let ping = ToMiddlemanMsg::Ping { id: random_u32() };
+                self.transmits.push_back(Transmit { dest: relay, bytes: ping.serialize() });
+                match self.attempt_mut(peer) {
+                    Some(attempt) => *attempt = Attempt::Relay,
+                    None => self.attempts.push((peer, Attempt::Relay)),
+                }
+                self.events.push_back(ConnectEvent::Connected { peer, remote: relay, path: ConnectionPath::Relay });
+            },
+            ClientEvent::ChannelBound { channel, peer } => {
+                match self.attempt_mut(peer) {
+                    Some(attempt) => *attempt = Attempt::Relay,
+                    None => self.attempts.push((peer, Attempt::Relay)),
+                }
+                let relay = self.client.tracker();
+                self.events.push_back(ConnectEvent::Connected { peer, remote: relay, path: ConnectionPath::Channel { channel } });
+            },
+            ClientEvent::ProxyClosed { remote, msg } => {
+                if let Some(i) = self.attempts.iter().position(|(peer, a)| *peer == remote && matches!(a, Attempt::Relay)) {
+                    self.attempts.remove(i);
+                    self.events.push_back(ConnectEvent::Failed { peer: Some(remote), msg });
+                }
+            },
+            // about our own address, no attempt waits on it
+            ClientEvent::AddrQueryTimeout => {},
+            // without a relay session, peers requesting us get a socket of the tracker each
+            ClientEvent::AllocateFailed { .. } => {},
+            ClientEvent::PunchChecked { .. } | ClientEvent::AddrObserved { .. } | ClientEvent::Allocated { .. } => {},
+        }
+    }
+
+    fn on_lan_event(&mut self, event: LanEvent, now: Instant) {
+        match event {
+            LanEvent::Found { id, addr } if self.requested == Some(id) && self.attempt_mut(addr).is_none() => {
+                self.lan_peer = Some(addr);
+                let puncher = Puncher::new(addr, self.config.punch.clone(), now);
+                self.attempts.push((addr, Attempt::Punching(puncher)));
+            },
+            LanEvent::NotFound { id } if self.requested == Some(id) => {
+                self.relay_waiters.clear();
+                self.client.request(id, false, now);
+            },
+            // queries are sent from the requester's socket, the one to punch
+            LanEvent::Queried { from, .. } if self.attempt_mut(from).is_none() => {
+                let puncher = Puncher::new(from, self.config.punch.clone(), now);
+                self.attempts.push((from, Attempt::Punching(puncher)));
+            },
+            _ => {},
+        }
+    }
+
+    fn process(&mut self, now: Instant) {
+        while let Some(event) = self.lan.as_mut().and_then(|lan| lan.poll_event()) {
+            self.on_lan_event(event, now);
+        }
+        while let Some(event) = self.client.poll_event() {
+            self.on_client_event(event, now);
+        }
+        // after the client's events, announces follow registrations
+        while let Some(t) = self.lan.as_mut().and_then(|lan| lan.poll_transmit()) {
+            self.transmits.push_back(t);
+        }
+        let mut i = 0;
+        while i < self.attempts.len() {
+            let (peer, attempt) = &mut self.attempts[i];
+            let peer = *peer;
+            let Attempt::Punching(puncher) = attempt else {
+                i += 1;
+                continue;
+            };
+            while let Some(t) = puncher.poll_transmit() {
+                self.transmits.push_back(t);
+            }
+            match puncher.poll_event() {
+                Some(PunchEvent::Succeeded { remote }) => {
+                    self.events.push_back(ConnectEvent::Connected { peer, remote, path: ConnectionPath::Direct });
+                    let Attempt::Punching(puncher) = std::mem::replace(attempt, Attempt::Relay) else {
+                        unreachable!()
+                    };
+                    *attempt = Attempt::Direct(puncher);
+                },
+                Some(PunchEvent::Failed { .. }) if self.lan_peer == Some(peer) => {
+                    // not reachable on the local network after all, go through the tracker
+                    self.lan_peer = None;
+                    self.attempts.remove(i);
+                    if let Some(id) = self.requested {
+                        self.relay_waiters.clear();
+                        self.client.request(id, false, now);
+                    }
+                    continue;
+                },
+                Some(PunchEvent::Failed { .. }) => {
+                    *attempt = Attempt::WaitingRelay { deadline: now + self.config.relay_wait };
+                    if let Some(id) = self.requested {
+                        // one request for every punch failing meanwhile
+                        if self.relay_waiters.is_empty() {
+                            self.client.request(id, true, now);
+                        }
+                        self.relay_waiters.push(peer);
+                    }
+                },
+                None => {},
+            }
+            i += 1;
+        }
+        for (_, attempt) in &mut self.attempts {
+            if let Attempt::Direct(puncher) = attempt {
+                while let Some(t) = puncher.poll_transmit() {
+                    self.transmits.push_back(t);
+                }
+            }
+        }
+        while let Some(t) = self.client.poll_transmit() {
+            self.transmits.push_back(t);
+        }
+    }
+
+    /// When `handle_timeout` should be called next
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let attempts = self.attempts.iter().filter_map(|(_, attempt)| match attempt {
+            Attempt::Punching(puncher) => puncher.poll_timeout(),
+            Attempt::WaitingRelay { deadline } => Some(*deadline),
+            _ => None,
+        });
+        let lan = self.lan.as_ref().and_then(|lan| lan.poll_timeout());
+        self.client.poll_timeout().into_iter().chain(lan).chain(attempts).min()
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<ConnectEvent> {
+        self.events.pop_front()
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn connect_falls_back_to_relay() {
+    use crate::data::FromMiddlemanMsg;
+
+    let tracker: SocketAddr = "10.0.0.1:61990".parse().unwrap();
+    let host: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let mut now = Instant::now();
+    let mut connector = LinkSeekConnector::new(tracker, ConnectConfig::default());
+    connector.connect(7, now);
+    while connector.poll_transmit().is_some() {}
+
+    let order = FromMiddlemanMsg::PunchOrder { remote: host, delta: None, candidates: vec![] };
+    assert!(connector.handle_datagram(&order.serialize(), tracker, now));
+    // the host never answers our hellos
+    let mut proxy_requested = false;
+    while let Some(timeout) = connector.poll_timeout() {
+        now = timeout.max(now);
+        connector.handle_timeout(now);
+        while let Some(t) = connector.poll_transmit() {
+            if ToMiddlemanMsg::parse(&t.bytes) == Some(ToMiddlemanMsg::Request { id: 7, use_proxy: true, candidates: vec![], token: None }) {
+                proxy_requested = true;
+            }
+        }
+        if proxy_requested {
+            break;
+        }
+    }
+    assert!(proxy_requested);
+    assert_eq!(connector.poll_event(), None);
+
+    let result = FromMiddlemanMsg::ProxyResult { remote: host, ok: true };
+    assert!(connector.handle_datagram(&result.serialize(), tracker, now));
+    assert_eq!(connector.poll_event(), Some(ConnectEvent::Connected { peer: host, remote: tracker, path: ConnectionPath::Relay }));
+}
+
+#[test]
+#[cfg(test)]
+fn connect_host_follows_proxy_order() {
+    use crate::data::FromMiddlemanMsg;
+
+    let tracker: SocketAddr = "10.0.0.1:61990".parse().unwrap();
+    let peer: SocketAddr = "3.3.3.3:3000".parse().unwrap();
+    let now = Instant::now();
+    let mut connector = LinkSeekConnector::new(tracker, ConnectConfig::default());
+    connector.host(now);
+    assert!(connector.handle_datagram(&FromMiddlemanMsg::RegisterOk { id: 9 }.serialize(), tracker, now));
+    assert_eq!(connector.poll_event(), Some(ConnectEvent::Registered { id: 9 }));
+    while connector.poll_transmit().is_some() {}
+
+    let order = FromMiddlemanMsg::PunchLinkseeker { port: 61993, remote: Some(peer) };
+    assert!(connector.handle_datagram(&order.serialize(), tracker, now));
+    let relay: SocketAddr = "10.0.0.1:61993".parse().unwrap();
+    let ping = connector.poll_transmit().unwrap();
+    assert_eq!(ping.dest, relay);
+    assert_eq!(connector.poll_event(), Some(ConnectEvent::Connected { peer, remote: relay, path: ConnectionPath::Relay }));
+}
+
+#[test]
+#[cfg(test)]
+fn connect_resolves_on_lan_first() {
+    use crate::data::{FromMiddlemanMsg, LanMsg};
+
+    let tracker: SocketAddr = "10.0.0.1:61990".parse().unwrap();
+    let host: SocketAddr = "192.168.1.10:6000".parse().unwrap();
+    let joiner: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+    let config = ConnectConfig { lan: Some(LanConfig::default()), ..Default::default() };
+    let group = LanConfig::default().group;
+    let mut now = Instant::now();
+
+    // the host announces its id once registered, and punches whoever queries it
+    let mut hosting = LinkSeekConnector::new(tracker, ConnectConfig { local_port: host.port(), ..config.clone() });
+    hosting.host(now);
+    while hosting.poll_transmit().is_some() {}
+    assert!(hosting.handle_datagram(&FromMiddlemanMsg::RegisterOk { id: 7 }.serialize(), tracker, now));
+    assert_eq!(hosting.poll_event(), Some(ConnectEvent::Registered { id: 7 }));
+    let announce = hosting.poll_transmit().unwrap();
+    assert_eq!(LanMsg::parse(&announce.bytes), Some(LanMsg::Announce { id: 7, port: host.port() }));
+    assert_eq!(announce.dest, group);
+
+    let mut joining = LinkSeekConnector::new(tracker, config.clone());
+    joining.connect(7, now);
+    let deliver = |from: &mut LinkSeekConnector, from_addr: SocketAddr, to: &mut LinkSeekConnector, to_addr: SocketAddr| {
+        let mut delivered = 0;
+        while let Some(t) = from.poll_transmit() {
+            assert_ne!(t.dest, tracker);
+            if t.dest == to_addr || t.dest == group {
+                assert!(to.handle_datagram(&t.bytes, from_addr, now));
+                delivered += 1;
+            }
+        }
+        delivered
+    };
+    while deliver(&mut joining, joiner, &mut hosting, host) + deliver(&mut hosting, host, &mut joining, joiner) > 0 {}
+    assert_eq!(hosting.poll_event(), Some(ConnectEvent::Connected { peer: joiner, remote: joiner, path: ConnectionPath::Direct }));
+    assert_eq!(joining.poll_event(), Some(ConnectEvent::Connected { peer: host, remote: host, path: ConnectionPath::Direct }));
+
+    // a host that does not answer
+    let mut connector = LinkSeekConnector::new(tracker, config);
+    connector.connect(7, now);
+    let query = connector.poll_transmit().unwrap();
+    assert_eq!(LanMsg::parse(&query.bytes), Some(LanMsg::Query { id: 7 }));
+    assert_ne!(query.dest, tracker);
+
+    let answer = LanMsg::Announce { id: 7, port: host.port() };
+    assert!(connector.handle_datagram(&answer.serialize(), "192.168.1.10:61989".parse().unwrap(), now));
+    let mut punched = false;
+    while let Some(t) = connector.poll_transmit() {
+        assert_ne!(t.dest, tracker);
+        punched |= t.dest == host;
+    }
+    assert!(punched);
+
+    // the host does not answer the hellos: ask the tracker
+    let mut requested = false;
+    while let Some(timeout) = connector.poll_timeout() {
+        now = timeout.max(now);
+        connector.handle_timeout(now);
+        while let Some(t) = connector.poll_transmit() {
+            if ToMiddlemanMsg::parse(&t.bytes) == Some(ToMiddlemanMsg::Request { id: 7, use_proxy: false, candidates: vec![], token: None }) {
+                requested = true;
+            }
+        }
+        if requested {
+            break;
+        }
+    }
+    assert!(requested);
+    assert_eq!(connector.poll_event(), None);
+}
+
+#[test]
+#[cfg(test)]
+fn connect_blames_failures_on_their_attempt() {
+    use crate::data::FromMiddlemanMsg;
+
+    let tracker: SocketAddr = "10.0.0.1:61990".parse().unwrap();
+    let host: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let mut now = Instant::now();
+    let other: SocketAddr = "4.4.4.4:4000".parse().unwrap();
+    let mut connector = LinkSeekConnector::new(tracker, ConnectConfig::default());
+    connector.connect(7, now);
+    let order = FromMiddlemanMsg::PunchOrder { remote: host, delta: None, candidates: vec![] };
+    assert!(connector.handle_datagram(&order.serialize(), tracker, now));
+    now += Duration::from_millis(100);
+    connector.accept(other, now);
+    // both punches fail, the second one waits on the request of the first one
+    while connector.relay_waiters.len() < 2 {
+        now = connector.poll_timeout().unwrap().max(now);
+        connector.handle_timeout(now);
+    }
+    let requests = std::iter::from_fn(|| connector.poll_transmit())
+        .filter(|t| matches!(ToMiddlemanMsg::parse(&t.bytes), Some(ToMiddlemanMsg::Request { use_proxy: true, .. })))
+        .count();
+    assert_eq!(requests, 1);
+    assert!(connector.handle_datagram(&FromMiddlemanMsg::RequestErr { msg: "proxy access needs a token".to_string() }.serialize(), tracker, now));
+    assert_eq!(connector.poll_event(), Some(ConnectEvent::Failed { peer: Some(host), msg: "proxy access needs a token".to_string() }));
+    assert_eq!(connector.poll_event(), Some(ConnectEvent::Failed { peer: Some(other), msg: "proxy access needs a token".to_string() }));
+    assert!(connector.attempts.is_empty());
+
+    // the host waits for the relay after its punch failed, then loses its registration
+    let peer: SocketAddr = "3.3.3.3:3000".parse().unwrap();
+    let mut hosting = LinkSeekConnector::new(tracker, ConnectConfig::default());
+    hosting.host(now);
+    assert!(hosting.handle_datagram(&FromMiddlemanMsg::RegisterOk { id: 9 }.serialize(), tracker, now));
+    let order = FromMiddlemanMsg::PunchOrder { remote: peer, delta: None, candidates: vec![] };
+    assert!(hosting.handle_datagram(&order.serialize(), tracker, now));
+    while !matches!(hosting.attempts.as_slice(), [(_, Attempt::WaitingRelay { .. })]) {
+        now = hosting.poll_timeout().unwrap().max(now);
+        hosting.handle_timeout(now);
+    }
+    assert!(hosting.handle_datagram(&FromMiddlemanMsg::RegisterExpired { id: 9 }.serialize(), tracker, now));
+    assert_eq!(hosting.poll_event(), Some(ConnectEvent::Registered { id: 9 }));
+    assert_eq!(hosting.poll_event(), Some(ConnectEvent::Failed { peer: None, msg: "registration expired".to_string() }));
+    assert!(matches!(hosting.attempts.as_slice(), [(p, Attempt::WaitingRelay { .. })] if *p == peer));
+
+    // a proxy order for nobody we know of is not a connection to the tracker
+    let mut hosting = LinkSeekConnector::new(tracker, ConnectConfig::default());
+    hosting.host(now);
+    assert!(hosting.handle_datagram(&FromMiddlemanMsg::RegisterOk { id: 9 }.serialize(), tracker, now));
+    assert_eq!(hosting.poll_event(), Some(ConnectEvent::Registered { id: 9 }));
+    while hosting.poll_transmit().is_some() {}
+    assert!(hosting.handle_datagram(&FromMiddlemanMsg::PunchLinkseeker { port: 61991, remote: None }.serialize(), tracker, now));
+    assert_eq!(hosting.poll_event(), None);
+    assert_eq!(hosting.poll_transmit(), None);
+    assert!(hosting.attempts.is_empty());
+}
+
+#[test]
+#[cfg(test)]
+fn connect_checks_every_attempt_when_lan_fails() {
+    use crate::data::LanMsg;
+
+    let tracker: SocketAddr = "10.0.0.1:61990".parse().unwrap();
+    let host: SocketAddr = "192.168.1.10:6000".parse().unwrap();
+    let other: SocketAddr = "192.168.1.30:7000".parse().unwrap();
+    let config = ConnectConfig { lan: Some(LanConfig::default()), ..Default::default() };
+    let mut now = Instant::now();
+    let mut connector = LinkSeekConnector::new(tracker, config);
+    connector.connect(7, now);
+    let answer = LanMsg::Announce { id: 7, port: host.port() };
+    assert!(connector.handle_datagram(&answer.serialize(), "192.168.1.10:61989".parse().unwrap(), now));
+    connector.accept(other, now);
+    while connector.poll_transmit().is_some() {}
+
+    // both punches fail on the same timeout, the one after the LAN host's too
+    while connector.lan_peer.is_some() {
+        now = connector.poll_timeout().unwrap().max(now);
+        connector.handle_timeout(now);
+    }
+    assert!(matches!(connector.attempts.as_slice(), [(peer, Attempt::WaitingRelay { .. })] if *peer == other));
+}
+
+#[test]
+#[cfg(test)]
+fn connect_host_relays_on_channel() {
+    use crate::data::FromMiddlemanMsg;
+
+    let tracker: SocketAddr = "10.0.0.1:61990".parse().unwrap();
+    let peer: SocketAddr = "3.3.3.3:3000".parse().unwrap();
+    let now = Instant::now();
+    let config = ConnectConfig { relay_channels: true, ..Default::default() };
+    let mut connector = LinkSeekConnector::new(tracker, config);
+    connector.host(now);
+    while connector.poll_transmit().is_some() {}
+    assert!(connector.handle_datagram(&FromMiddlemanMsg::RegisterOk { id: 9 }.serialize(), tracker, now));
+    assert_eq!(connector.poll_event(), Some(ConnectEvent::Registered { id: 9 }));
+    let allocate = connector.poll_transmit().unwrap();
+    assert_eq!(ToMiddlemanMsg::parse(&allocate.bytes), Some(ToMiddlemanMsg::Allocate));
+    assert!(connector.handle_datagram(&FromMiddlemanMsg::AllocateOk { port: 61990 }.serialize(), tracker, now));
+
+    let bound = FromMiddlemanMsg::ChannelBound { channel: 0x4000, peer }.serialize();
+    assert!(connector.handle_datagram(&bound, tracker, now));
+    assert!(connector.handle_datagram(&bound, tracker, now));
+    let path = ConnectionPath::Channel { channel: 0x4000 };
+    assert_eq!(connector.poll_event(), Some(ConnectEvent::Connected { peer, remote: tracker, path }));
+    assert_eq!(connector.poll_event(), None);
+}
@@ -1,27 +1,87 @@
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ToMiddlemanMsg {
-    /// Register to the middleman, should return an id
-    Register,
-    /// Request to connect to the registered.
-    Request { id: u32, use_proxy: bool },
+    /// Register to the middleman, should return an id. `candidates` are other addresses we can be
+    /// reached at, like our local interface addresses, forwarded to whoever requests our id.
+    Register { candidates: Vec<std::net::SocketAddr> },
+    /// Renew the lease of a registered id before it expires, also keeps our NAT mapping open
+    Renew { id: u32 },
+    /// Request to connect to the registered. `candidates` are forwarded to the host, like for `Register`.
+    /// `token` is what trackers with a gated proxy want for `use_proxy`, see `auth::make_token`.
+    Request { id: u32, use_proxy: bool, candidates: Vec<std::net::SocketAddr>, token: Option<String> },
     PunchCheck { id: u32 },
-    ProxyTo { remote: std::net::SocketAddr },
+    /// Proxy to any address, `token` as for `Request`
+    ProxyTo { remote: std::net::SocketAddr, token: Option<String> },
     Ping { id: u32 },
     DomainNameReq { domain: String },
+    /// Ask the tracker which address it sees us as. The answer is sent from the socket
+    /// `reply_from` if given, or by the other tracker `reply_via` from its own IP, to test the
+    /// filtering behaviour of our NAT.
+    NatProbe { id: u32, reply_from: Option<u8>, reply_via: Option<std::net::SocketAddr> },
+    /// Sent by a tracker to another one, for a `NatProbe` with `reply_via`: answer `observed` as if
+    /// it had sent the probe
+    NatProbeFor { id: u32, observed: std::net::SocketAddr },
+    /// Ask the tracker which public address it sees us as
+    WhatIsMyAddr,
+    /// Open a relay session on the tracker socket this is sent to: peers requesting us through the
+    /// proxy are then relayed on channels of that socket instead of one socket each. Sending it
+    /// again, or a `Renew`, keeps the session alive.
+    Allocate,
+    /// Relay `peer` on `channel` of our session, see `relay`. `token` as for `Request`.
+    ChannelBind { channel: u16, peer: std::net::SocketAddr, token: Option<String> },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FromMiddlemanMsg {
     RegisterOk { id: u32 },
     RegisterErr { msg: String },
+    RenewOk { id: u32 },
+    /// The lease of a registered id has lapsed, or the id is unknown
+    RegisterExpired { id: u32 },
     /// Request to connect to the registered has failed.
     RequestErr { msg: String },
-    /// Order the client or host to punch the remote
-    PunchOrder { remote: std::net::SocketAddr },
-    /// Order a client to punch THIS server, at port given
-    PunchLinkseeker { port: u16 },
-    PunchCheckResult { ok: bool },
+    /// Order the client or host to punch the remote. `delta` is the port allocation delta of the
+    /// remote's NAT, if it ran a punch check recently. `candidates` are the other addresses the
+    /// remote gave, see `candidate::prioritize`.
+    PunchOrder { remote: std::net::SocketAddr, delta: Option<i32>, candidates: Vec<std::net::SocketAddr> },
+    /// Order a client to punch THIS server, at port given. `remote` is the peer that will be proxied.
+    PunchLinkseeker { port: u16, remote: Option<std::net::SocketAddr> },
+    /// `ports` are the ports seen by each tracker socket, in socket order. `delta` is the difference
+    /// between two consecutive ones, if it is constant.
+    PunchCheckResult { ok: bool, ports: Vec<u16>, delta: Option<i32> },
     ProxyResult { remote: std::net::SocketAddr, ok: bool },
     DomainNameResult { domain: String, results: Vec<std::net::SocketAddr> },
+    /// The tracker could not resolve `domain`, `msg` tells why
+    DomainNameErr { domain: String, msg: String },
     Pong { id: u32 },
-}
\ No newline at end of file
+    /// Answer to a `NatProbe`: `observed` is our address as seen by the tracker socket `socket_n`
+    NatProbeResult { id: u32, observed: std::net::SocketAddr, socket_n: u8 },
+    /// Answer to `WhatIsMyAddr`: `observed` is our address as seen by the tracker socket `socket_n`
+    YourAddr { observed: std::net::SocketAddr, socket_n: u8 },
+    /// The relay session is open, on the tracker socket of that port
+    AllocateOk { port: u16 },
+    /// Datagrams from `peer` are relayed to us on `channel`, and ours on `channel` to them. Sent as an
+    /// answer to `ChannelBind`, or when a peer requests our id through the proxy.
+    ChannelBound { channel: u16, peer: std::net::SocketAddr },
+    ChannelBindErr { channel: u16, msg: String },
+    /// The tracker stopped relaying us to `remote`, a proxy or a channel of our relay session,
+    /// because it went over a quota
+    ProxyClosed { remote: std::net::SocketAddr, msg: String },
+}
+
+/// Messages sent directly between two peers, without the middleman
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMsg {
+    /// Sent in bursts while punching, the remote must answer with the same nonce
+    PunchHello { nonce: u32 },
+    /// Answer to a `PunchHello`: proves both directions of the hole are open
+    PunchAck { nonce: u32 },
+}
+
+/// Messages broadcast on the local network, see `lan::LanDiscovery`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LanMsg {
+    /// The host registered with `id` can be reached at `port`, on the IP that sent this
+    Announce { id: u32, port: u16 },
+    /// Looking for the host registered with `id`, it answers with an `Announce`
+    Query { id: u32 },
+}
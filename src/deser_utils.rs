@@ -24,7 +24,7 @@ impl std::str::FromStr for SocketAddrCustom {
 
     fn from_str(input: &str) -> Result<SocketAddrCustom, ()> {
         match input.split_at_checked(1) {
-            Some((first, last)) if first == "4" => {
+            Some(("4", last)) => {
                 let Some((ip, port)) = last.split_at_checked(8) else {
                     return Err(());
                 };
@@ -32,7 +32,7 @@ impl std::str::FromStr for SocketAddrCustom {
                 let port = u16::from_str_radix(port, 16).map_err(|_| ())?;
                 Ok(SocketAddrCustom(SocketAddr::V4(SocketAddrV4::new(ip.into(), port))))
             },
-            Some((first, last)) if first == "6" => {
+            Some(("6", last)) => {
                 let Some((ip, port)) = last.split_at_checked(32) else {
                     return Err(());
                 };
@@ -65,7 +65,7 @@ impl<T: std::str::FromStr> std::str::FromStr for VecCustom<T> {
 
     fn from_str(input: &str) -> Result<VecCustom<T>, ()> {
         let mut v = Vec::new();
-        if input.len() == 0 {
+        if input.is_empty() {
             return Ok(VecCustom(v));
         }
         for el in input.split(',') {
@@ -81,8 +81,8 @@ impl<T: std::str::FromStr> std::str::FromStr for VecCustom<T> {
 fn deser_socket_addr() {
     use std::str::FromStr;
 
-    let v4: SocketAddr = SocketAddr::from(([1, 2, 3, 4], 1234)).into();
-    let v6: SocketAddr = SocketAddr::from(([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16], 1234)).into();
+    let v4: SocketAddr = SocketAddr::from(([1, 2, 3, 4], 1234));
+    let v6: SocketAddr = SocketAddr::from(([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16], 1234));
 
     let s4 = format!("{}", SocketAddrCustom(v4));
     let s6 = format!("{}", SocketAddrCustom(v6));
@@ -0,0 +1,255 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant}
+};
+
+use crate::{
+    client::Transmit,
+    data::LanMsg
+};
+
+/// Port the discovery sockets listen on, next to the tracker ports
+pub const DEFAULT_LAN_PORT: u16 = 61989;
+
+#[derive(Debug, Clone)]
+pub struct LanConfig {
+    /// Broadcast or multicast address announces and queries are sent to
+    pub group: SocketAddr,
+    /// Delay between two unsolicited announces of the same id
+    pub announce_interval: Duration,
+    /// Delay between two sends of the same query
+    pub query_interval: Duration,
+    /// A query not answered after that long gives a `LanEvent::NotFound`
+    pub query_timeout: Duration,
+    /// How long an announce heard from someone else is remembered
+    pub cache_ttl: Duration,
+}
+
+impl Default for LanConfig {
+    fn default() -> Self {
+        Self {
+            group: SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), DEFAULT_LAN_PORT),
+            announce_interval: Duration::from_secs(2),
+            query_interval: Duration::from_millis(200),
+            query_timeout: Duration::from_secs(1),
+            cache_ttl: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LanEvent {
+    /// Someone on the local network looks for one of our ids, from `from`.
+    /// Punch it to connect, `connect::LinkSeekConnector` does when hosting with a `LanConfig`.
+    Queried { id: u32, from: SocketAddr },
+    /// The host of `id` is on the local network, at `addr`
+    Found { id: u32, addr: SocketAddr },
+    NotFound { id: u32 },
+}
+
+/// Socket bound to the port of `group`, able to receive its broadcasts or multicasts.
+///
+/// Hosts answer queries through it. Queries can be sent from any socket with broadcast enabled,
+/// the answer goes back to it.
+pub fn bind_discovery_socket(group: SocketAddr) -> std::io::Result<UdpSocket> {
+    match group.ip() {
+        IpAddr::V4(ip) => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port()))?;
+            if ip.is_multicast() {
+                socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?;
+            } else {
+                socket.set_broadcast(true)?;
+            }
+            Ok(socket)
+        },
+        IpAddr::V6(ip) => {
+            let socket = UdpSocket::bind((std::net::Ipv6Addr::UNSPECIFIED, group.port()))?;
+            socket.join_multicast_v6(&ip, 0)?;
+            Ok(socket)
+        },
+    }
+}
+
+struct Announced {
+    port: u16,
+    next_announce: Instant,
+}
+
+struct Query {
+    next_send: Instant,
+    deadline: Instant,
+}
+
+/// Sans-IO discovery of hosts on the local network, driven the same way as `LinkSeekClient`.
+///
+/// Hosts announce their link id to the group periodically and answer queries for it, so a
+/// requester on the same network finds them without going through a tracker, even when the
+/// internet is down. Uses the same `#lnksk@` framing as every other message, see `data::LanMsg`.
+pub struct LanDiscovery {
+    config: LanConfig,
+    announced: HashMap<u32, Announced>,
+    queries: HashMap<u32, Query>,
+    /// announces heard from others: address and when we forget it
+    known: HashMap<u32, (SocketAddr, Instant)>,
+    transmits: VecDeque<Transmit>,
+    events: VecDeque<LanEvent>,
+}
+
+impl LanDiscovery {
+    pub fn new(config: LanConfig) -> Self {
+        Self {
+            config,
+            announced: HashMap::new(),
+            queries: HashMap::new(),
+            known: HashMap::new(),
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Announce that we host `id`, reachable at `port` (the port of the socket the game uses)
+    pub fn announce(&mut self, id: u32, port: u16, now: Instant) {
+        self.announced.insert(id, Announced { port, next_announce: now });
+        self.handle_timeout(now);
+    }
+
+    pub fn stop_announcing(&mut self, id: u32) {
+        self.announced.remove(&id);
+    }
+
+    /// Look for the host of `id`, a `LanEvent::Found` or `LanEvent::NotFound` follows
+    pub fn query(&mut self, id: u32, now: Instant) {
+        if let Some(addr) = self.lookup(id, now) {
+            self.events.push_back(LanEvent::Found { id, addr });
+            return;
+        }
+        self.queries.insert(id, Query { next_send: now, deadline: now + self.config.query_timeout });
+        self.handle_timeout(now);
+    }
+
+    /// Address of the host of `id`, if we heard it announce recently
+    pub fn lookup(&self, id: u32, now: Instant) -> Option<SocketAddr> {
+        self.known.get(&id).filter(|(_, expire)| now < *expire).map(|(addr, _)| *addr)
+    }
+
+    /// Returns whether or not the datagram was a discovery message
+    pub fn handle_datagram(&mut self, bytes: &[u8], from: SocketAddr, now: Instant) -> bool {
+        let Some(msg) = LanMsg::parse(bytes) else {
+            return false;
+        };
+        match msg {
+            LanMsg::Query { id } => {
+                if let Some(announced) = self.announced.get(&id) {
+                    let answer = LanMsg::Announce { id, port: announced.port };
+                    self.transmits.push_back(Transmit { dest: from, bytes: answer.serialize() });
+                    self.events.push_back(LanEvent::Queried { id, from });
+                }
+            },
+            LanMsg::Announce { id, port } => {
+                if self.announced.contains_key(&id) {
+                    // our own broadcast coming back
+                    return true;
+                }
+                let addr = SocketAddr::new(from.ip(), port);
+                self.known.insert(id, (addr, now + self.config.cache_ttl));
+                if self.queries.remove(&id).is_some() {
+                    self.events.push_back(LanEvent::Found { id, addr });
+                }
+            },
+        }
+        true
+    }
+
+    /// Sends announces and queries when due, gives up on queries past their timeout
+    pub fn handle_timeout(&mut self, now: Instant) {
+        for (id, announced) in &mut self.announced {
+            if now >= announced.next_announce {
+                announced.next_announce = now + self.config.announce_interval;
+                let bytes = LanMsg::Announce { id: *id, port: announced.port }.serialize();
+                self.transmits.push_back(Transmit { dest: self.config.group, bytes });
+            }
+        }
+        let events = &mut self.events;
+        self.queries.retain(|id, query| {
+            if now >= query.deadline {
+                events.push_back(LanEvent::NotFound { id: *id });
+                return false;
+            }
+            true
+        });
+        for (id, query) in &mut self.queries {
+            if now >= query.next_send {
+                query.next_send = now + self.config.query_interval;
+                let bytes = LanMsg::Query { id: *id }.serialize();
+                self.transmits.push_back(Transmit { dest: self.config.group, bytes });
+            }
+        }
+        self.known.retain(|_, (_, expire)| now < *expire);
+    }
+
+    /// When `handle_timeout` should be called next
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let announces = self.announced.values().map(|a| a.next_announce);
+        let queries = self.queries.values().map(|q| q.next_send.min(q.deadline));
+        announces.chain(queries).min()
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<LanEvent> {
+        self.events.pop_front()
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn lan_query_finds_host() {
+    let config = LanConfig::default();
+    let host_addr: SocketAddr = "192.168.1.10:61989".parse().unwrap();
+    let requester_addr: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+    let now = Instant::now();
+    let mut host = LanDiscovery::new(config.clone());
+    host.announce(42, 6000, now);
+    let announce = host.poll_transmit().unwrap();
+    assert_eq!(announce.dest, config.group);
+
+    let mut requester = LanDiscovery::new(config.clone());
+    requester.query(42, now);
+    let query = requester.poll_transmit().unwrap();
+    assert_eq!(query.dest, config.group);
+    assert!(host.handle_datagram(&query.bytes, requester_addr, now));
+    assert_eq!(host.poll_event(), Some(LanEvent::Queried { id: 42, from: requester_addr }));
+
+    let answer = host.poll_transmit().unwrap();
+    assert_eq!(answer.dest, requester_addr);
+    assert!(requester.handle_datagram(&answer.bytes, host_addr, now));
+    let found: SocketAddr = "192.168.1.10:6000".parse().unwrap();
+    assert_eq!(requester.poll_event(), Some(LanEvent::Found { id: 42, addr: found }));
+    assert_eq!(requester.poll_timeout(), None);
+
+    // the next query is answered from what we heard
+    requester.query(42, now);
+    assert_eq!(requester.poll_event(), Some(LanEvent::Found { id: 42, addr: found }));
+}
+
+#[test]
+#[cfg(test)]
+fn lan_query_times_out() {
+    let config = LanConfig::default();
+    let mut now = Instant::now();
+    let mut requester = LanDiscovery::new(config.clone());
+    requester.query(42, now);
+    let mut sent = 0;
+    while let Some(timeout) = requester.poll_timeout() {
+        now = timeout;
+        requester.handle_timeout(now);
+        while requester.poll_transmit().is_some() {
+            sent += 1;
+        }
+    }
+    assert!(sent >= (config.query_timeout.as_millis() / config.query_interval.as_millis()) as usize);
+    assert_eq!(requester.poll_event(), Some(LanEvent::NotFound { id: 42 }));
+}
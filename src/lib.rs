@@ -4,6 +4,16 @@ pub mod serialize;
 pub mod data;
 pub mod common;
 pub mod client;
+pub mod punch;
+pub mod candidate;
+pub mod lan;
+pub mod relay;
+pub mod nat;
+pub mod connect;
+pub mod pool;
+pub mod code;
+#[cfg(feature = "auth")]
+pub mod auth;
 #[cfg(feature = "tracker")]
 pub mod tracker;
 
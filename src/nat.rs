@@ -0,0 +1,392 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant}
+};
+
+use crate::{
+    client::Transmit,
+    common::random_u32,
+    data::{FromMiddlemanMsg, PeerMsg, ToMiddlemanMsg}
+};
+
+/// Mapping or filtering behaviour of a NAT, as described in RFC 4787
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatBehavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+/// Result of a `NatProber`. A `None` field means the test could not be run, because the
+/// tracker did not answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatReport {
+    /// Our public address, as seen by the tracker
+    pub mapped: Option<SocketAddr>,
+    /// Telling address dependent mapping apart needs a second tracker IP, without one it is
+    /// reported as endpoint independent.
+    pub mapping: Option<NatBehavior>,
+    /// Endpoint independent needs an answer from the IP of the secondary tracker, sent before we
+    /// ever sent to it: without a secondary tracker, or with one that does not answer for the
+    /// tracker, an answer from another port of the tracker proves address dependent at best.
+    pub filtering: Option<NatBehavior>,
+    /// The NAT kept our local port as the public port
+    pub port_preservation: Option<bool>,
+    /// A packet sent to our own public address came back to us
+    pub hairpinning: Option<bool>,
+}
+
+impl NatReport {
+    /// Whether direct punching has a chance to work, if not go straight to the proxy
+    pub fn is_punchable(&self) -> bool {
+        matches!(self.mapping, Some(NatBehavior::EndpointIndependent | NatBehavior::AddressDependent))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NatProbeConfig {
+    /// Delay between two sends of a probe that was not answered yet
+    pub retry_interval: Duration,
+    /// Maximum duration of each of the two phases of the probing
+    pub phase_timeout: Duration,
+}
+
+impl Default for NatProbeConfig {
+    fn default() -> Self {
+        Self {
+            retry_interval: Duration::from_millis(200),
+            phase_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+struct Probe {
+    id: u32,
+    dest: SocketAddr,
+    /// where the answer comes from, anything else answering the id is not the tracker
+    from: SocketAddr,
+    reply_from: Option<u8>,
+    reply_via: Option<SocketAddr>,
+    result: Option<SocketAddr>,
+}
+
+impl Probe {
+    fn new(dest: SocketAddr, from: SocketAddr, reply_from: Option<u8>, reply_via: Option<SocketAddr>) -> Self {
+        Self { id: random_u32(), dest, from, reply_from, reply_via, result: None }
+    }
+
+    fn transmit(&self) -> Transmit {
+        let msg = ToMiddlemanMsg::NatProbe { id: self.id, reply_from: self.reply_from, reply_via: self.reply_via };
+        Transmit { dest: self.dest, bytes: msg.serialize() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Only talk to the first socket of the tracker, and ask it to answer from the second one and
+    /// from the secondary tracker
+    Filtering,
+    /// Talk to the second socket of the tracker, the secondary tracker, and ourselves
+    Mapping,
+    Done,
+}
+
+/// Sans-IO NAT classifier, driven the same way as `LinkSeekClient`.
+///
+/// The prober uses the first two sockets of the tracker: `tracker` and `alternate`, the address of
+/// its socket 1 on the same IP. It must be fed every datagram received on the socket being probed, and `local_port` must
+/// be the local port of that socket.
+pub struct NatProber {
+    config: NatProbeConfig,
+    local_port: u16,
+    phase: Phase,
+    phase_deadline: Instant,
+    next_send: Instant,
+    /// probe to the first tracker socket
+    base: Probe,
+    /// probe to the first tracker socket, answered by the second one
+    filter: Probe,
+    /// probe to the first tracker socket, answered by the secondary tracker
+    change_ip: Option<Probe>,
+    /// probe to the second tracker socket
+    alternate: Probe,
+    /// probe to another tracker, on another IP
+    secondary: Option<Probe>,
+    hairpin_nonce: u32,
+    hairpin_received: bool,
+    transmits: VecDeque<Transmit>,
+    report: Option<NatReport>,
+}
+
+impl NatProber {
+    pub fn new(tracker: SocketAddr, alternate: SocketAddr, secondary: Option<SocketAddr>, local_port: u16, config: NatProbeConfig, now: Instant) -> Self {
+        let mut prober = Self {
+            phase: Phase::Filtering,
+            phase_deadline: now + config.phase_timeout,
+            next_send: now,
+            config,
+            local_port,
+            base: Probe::new(tracker, tracker, None, None),
+            filter: Probe::new(tracker, alternate, Some(1), None),
+            change_ip: secondary.map(|s| Probe::new(tracker, s, None, Some(s))),
+            alternate: Probe::new(alternate, alternate, None, None),
+            secondary: secondary.map(|s| Probe::new(s, s, None, None)),
+            hairpin_nonce: random_u32(),
+            hairpin_received: false,
+            transmits: VecDeque::new(),
+            report: None,
+        };
+        prober.handle_timeout(now);
+        prober
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+
+    /// Returns whether or not the datagram was an answer to one of our probes
+    pub fn handle_datagram(&mut self, bytes: &[u8], from: SocketAddr, now: Instant) -> bool {
+        if let Some(FromMiddlemanMsg::NatProbeResult { id, observed, .. }) = FromMiddlemanMsg::parse(bytes) {
+            let filtering = self.phase == Phase::Filtering;
+            let probes = [Some(&mut self.base), Some(&mut self.filter), self.change_ip.as_mut(), Some(&mut self.alternate), self.secondary.as_mut()];
+            let Some(probe) = probes.into_iter().flatten().find(|p| p.id == id && p.from == from) else {
+                return false;
+            };
+            // once we sent to the secondary tracker, its answers for the tracker prove nothing
+            if probe.reply_via.is_none() || filtering {
+                probe.result = Some(observed);
+            }
+        } else if let Some(PeerMsg::PunchHello { nonce }) = PeerMsg::parse(bytes) {
+            if nonce != self.hairpin_nonce {
+                return false;
+            }
+            self.hairpin_received = true;
+        } else {
+            return false;
+        }
+        self.advance(now);
+        true
+    }
+
+    fn mapping_phase_complete(&self) -> bool {
+        self.alternate.result.is_some()
+            && self.secondary.as_ref().is_none_or(|p| p.result.is_some())
+            && self.hairpin_received
+    }
+
+    fn advance(&mut self, now: Instant) {
+        match self.phase {
+            Phase::Filtering => {
+                let complete = self.base.result.is_some()
+                    && self.filter.result.is_some()
+                    && self.change_ip.as_ref().is_none_or(|p| p.result.is_some());
+                if !complete && now < self.phase_deadline {
+                    return;
+                }
+                if self.base.result.is_none() {
+                    // the tracker never answered, there is nothing else we can test
+                    self.finish();
+                    return;
+                }
+                self.phase = Phase::Mapping;
+                self.phase_deadline = now + self.config.phase_timeout;
+                self.next_send = now;
+            },
+            Phase::Mapping => {
+                if self.mapping_phase_complete() || now >= self.phase_deadline {
+                    self.finish();
+                }
+            },
+            Phase::Done => {},
+        }
+    }
+
+    fn finish(&mut self) {
+        self.phase = Phase::Done;
+        let mapped = self.base.result;
+        let mapping = match (mapped, self.alternate.result) {
+            (Some(m0), Some(m1)) if m0 != m1 => Some(NatBehavior::AddressAndPortDependent),
+            (Some(m0), Some(_)) => match self.secondary.as_ref().and_then(|p| p.result) {
+                Some(m2) if m2 != m0 => Some(NatBehavior::AddressDependent),
+                _ => Some(NatBehavior::EndpointIndependent),
+            },
+            _ => None,
+        };
+        let filtering = match (mapped, self.filter.result, self.change_ip.as_ref().and_then(|p| p.result)) {
+            (_, _, Some(_)) => Some(NatBehavior::EndpointIndependent),
+            (_, Some(_), None) => Some(NatBehavior::AddressDependent),
+            (Some(_), None, None) => Some(NatBehavior::AddressAndPortDependent),
+            (None, None, None) => None,
+        };
+        self.report = Some(NatReport {
+            mapped,
+            mapping,
+            filtering,
+            port_preservation: mapped.map(|m| m.port() == self.local_port),
+            hairpinning: mapped.map(|_| self.hairpin_received),
+        });
+    }
+
+    /// Sends the probes that were not answered yet, and moves to the next phase on timeout
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.advance(now);
+        if self.phase == Phase::Done || now < self.next_send {
+            return;
+        }
+        self.next_send = now + self.config.retry_interval;
+        match self.phase {
+            Phase::Filtering => {
+                for probe in [Some(&self.base), Some(&self.filter), self.change_ip.as_ref()].into_iter().flatten() {
+                    if probe.result.is_none() {
+                        self.transmits.push_back(probe.transmit());
+                    }
+                }
+            },
+            Phase::Mapping => {
+                for probe in [Some(&self.alternate), self.secondary.as_ref()].into_iter().flatten() {
+                    if probe.result.is_none() {
+                        self.transmits.push_back(probe.transmit());
+                    }
+                }
+                if let (Some(mapped), false) = (self.base.result, self.hairpin_received) {
+                    let hello = PeerMsg::PunchHello { nonce: self.hairpin_nonce };
+                    self.transmits.push_back(Transmit { dest: mapped, bytes: hello.serialize() });
+                }
+            },
+            Phase::Done => {},
+        }
+    }
+
+    /// When `handle_timeout` should be called next
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        match self.phase {
+            Phase::Done => None,
+            _ => Some(self.next_send.min(self.phase_deadline)),
+        }
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    /// The report, once every test is done
+    pub fn poll_report(&mut self) -> Option<NatReport> {
+        self.report.take()
+    }
+}
+
+#[cfg(test)]
+fn answer_all(prober: &mut NatProber, mapped: impl Fn(SocketAddr) -> Option<SocketAddr>, now: Instant) {
+    while let Some(t) = prober.poll_transmit() {
+        if let Some(ToMiddlemanMsg::NatProbe { id, reply_from, reply_via }) = ToMiddlemanMsg::parse(&t.bytes) {
+            let Some(observed) = mapped(t.dest) else {
+                continue;
+            };
+            if reply_from.is_some() || reply_via.is_some() {
+                // port dependent filtering drops it
+                continue;
+            }
+            let answer = FromMiddlemanMsg::NatProbeResult { id, observed, socket_n: 0 };
+            prober.handle_datagram(&answer.serialize(), t.dest, now);
+        }
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn nat_probe_port_dependent_mapping() {
+    let tracker: SocketAddr = "10.0.0.1:61990".parse().unwrap();
+    let alternate: SocketAddr = "10.0.0.1:61991".parse().unwrap();
+    let mut now = Instant::now();
+    let mut prober = NatProber::new(tracker, alternate, None, 5000, NatProbeConfig::default(), now);
+    // symmetric NAT: a new public port for every destination
+    let mapped = |dest: SocketAddr| Some(SocketAddr::from(([8, 8, 8, 8], dest.port() - 50000)));
+    while let Some(timeout) = prober.poll_timeout() {
+        answer_all(&mut prober, mapped, now);
+        now = timeout.max(now);
+        prober.handle_timeout(now);
+    }
+    let report = prober.poll_report().unwrap();
+    assert_eq!(report.mapped, Some("8.8.8.8:11990".parse().unwrap()));
+    assert_eq!(report.mapping, Some(NatBehavior::AddressAndPortDependent));
+    assert_eq!(report.filtering, Some(NatBehavior::AddressAndPortDependent));
+    assert_eq!(report.port_preservation, Some(false));
+    assert_eq!(report.hairpinning, Some(false));
+    assert!(!report.is_punchable());
+}
+
+#[test]
+#[cfg(test)]
+fn nat_probe_no_answer() {
+    let tracker: SocketAddr = "10.0.0.1:61990".parse().unwrap();
+    let alternate: SocketAddr = "10.0.0.1:61991".parse().unwrap();
+    let mut now = Instant::now();
+    let mut prober = NatProber::new(tracker, alternate, None, 5000, NatProbeConfig::default(), now);
+    while let Some(timeout) = prober.poll_timeout() {
+        // answers from anything but the probed trackers are dropped
+        while let Some(t) = prober.poll_transmit() {
+            if let Some(ToMiddlemanMsg::NatProbe { id, .. }) = ToMiddlemanMsg::parse(&t.bytes) {
+                let answer = FromMiddlemanMsg::NatProbeResult { id, observed: "8.8.8.8:5000".parse().unwrap(), socket_n: 0 };
+                assert!(!prober.handle_datagram(&answer.serialize(), "6.6.6.6:61990".parse().unwrap(), now));
+            }
+        }
+        now = timeout.max(now);
+        prober.handle_timeout(now);
+    }
+    let report = prober.poll_report().unwrap();
+    assert_eq!(report, NatReport { mapped: None, mapping: None, filtering: None, port_preservation: None, hairpinning: None });
+}
+
+#[test]
+#[cfg(test)]
+fn nat_probe_filtering_needs_another_ip() {
+    use std::collections::HashSet;
+
+    let tracker: SocketAddr = "10.0.0.1:61990".parse().unwrap();
+    let alternate: SocketAddr = "10.0.0.1:61991".parse().unwrap();
+    let secondary: SocketAddr = "10.0.0.2:61990".parse().unwrap();
+    let public: SocketAddr = "8.8.8.8:5000".parse().unwrap();
+    for (has_secondary, filtering) in [
+        (true, NatBehavior::EndpointIndependent),
+        (true, NatBehavior::AddressDependent),
+        (true, NatBehavior::AddressAndPortDependent),
+        // a reply from another port only proves address dependent filtering
+        (false, NatBehavior::EndpointIndependent),
+    ] {
+        let mut now = Instant::now();
+        let mut prober = NatProber::new(tracker, alternate, has_secondary.then_some(secondary), 5000, NatProbeConfig::default(), now);
+        let mut contacted = HashSet::new();
+        while let Some(timeout) = prober.poll_timeout() {
+            while let Some(t) = prober.poll_transmit() {
+                contacted.insert(t.dest);
+                let Some(ToMiddlemanMsg::NatProbe { id, reply_from, reply_via }) = ToMiddlemanMsg::parse(&t.bytes) else {
+                    continue;
+                };
+                let from = match (reply_from, reply_via) {
+                    (_, Some(via)) => via,
+                    (Some(n), None) => SocketAddr::new(t.dest.ip(), tracker.port() + n as u16),
+                    (None, None) => t.dest,
+                };
+                let passes = match filtering {
+                    NatBehavior::EndpointIndependent => true,
+                    NatBehavior::AddressDependent => contacted.iter().any(|c| c.ip() == from.ip()),
+                    NatBehavior::AddressAndPortDependent => contacted.contains(&from),
+                };
+                if passes {
+                    let answer = FromMiddlemanMsg::NatProbeResult { id, observed: public, socket_n: 0 };
+                    prober.handle_datagram(&answer.serialize(), from, now);
+                }
+            }
+            now = timeout.max(now);
+            prober.handle_timeout(now);
+        }
+        let report = prober.poll_report().unwrap();
+        let expected = match has_secondary {
+            true => filtering,
+            false => NatBehavior::AddressDependent,
+        };
+        assert_eq!(report.filtering, Some(expected), "{:?} NAT, secondary tracker: {}", filtering, has_secondary);
+        assert_eq!(report.mapping, Some(NatBehavior::EndpointIndependent));
+    }
+}
@@ -1,14 +1,12 @@
 use std::net::SocketAddr;
 
 use crate::{
-    common::{UDPUNCH_ID, UDPUNCH_ID_BYTES, UDPUNCH_ID_LEN}, data::{FromMiddlemanMsg, ToMiddlemanMsg}, deser_utils::{SocketAddrCustom, VecCustom}
+    common::{UDPUNCH_ID_BYTES, UDPUNCH_ID_LEN}, data::{FromMiddlemanMsg, LanMsg, PeerMsg, ToMiddlemanMsg}, deser_utils::{SocketAddrCustom, VecCustom}
 };
 
 /// check the head, if it exists return the tail as bytes
 fn check_head(bytes: &[u8]) -> Option<&[u8]> {
-    let Some((id, tail)) = bytes.split_at_checked(UDPUNCH_ID_LEN) else {
-        return None;
-    };
+    let (id, tail) = bytes.split_at_checked(UDPUNCH_ID_LEN)?;
     if id != UDPUNCH_ID_BYTES {
         return None;
     }
@@ -19,6 +17,12 @@ fn parse_kv(input: &str) -> Option<(&str, &str)> {
     input.split_once('=')
 }
 
+/// Addresses in the compact form of `SocketAddrCustom`, an absent or invalid list is empty
+fn parse_addrs(input: &str) -> Vec<SocketAddr> {
+    let addrs = input.parse::<VecCustom<SocketAddrCustom>>().map(|v| v.0).unwrap_or_default();
+    addrs.into_iter().map(|addr| addr.0).collect()
+}
+
 fn process_all_kv<'a>(iter: impl Iterator<Item=&'a str>, mut f: impl FnMut(&str, &str)) -> Option<()> {
     for raw_kv in iter {
         let (k, v) = parse_kv(raw_kv)?;
@@ -32,9 +36,7 @@ impl FromMiddlemanMsg {
         let tail = check_head(bytes)?;
         let tail = String::from_utf8_lossy(tail);
         let mut s = tail.split('/');
-        let Some(command) = s.next() else {
-            return None;
-        };
+        let command = s.next()?;
         let parsed = match command {
             "registerok" => {
                 let mut id: Option<u32> = None;
@@ -43,6 +45,20 @@ impl FromMiddlemanMsg {
                 })?;
                 Self::RegisterOk { id: id? }
             },
+            "renewok" => {
+                let mut id: Option<u32> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "id" { id = v.parse::<u32>().ok() }
+                })?;
+                Self::RenewOk { id: id? }
+            },
+            "regexpired" => {
+                let mut id: Option<u32> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "id" { id = v.parse::<u32>().ok() }
+                })?;
+                Self::RegisterExpired { id: id? }
+            },
             "registererr" => {
                 let mut msg: Option<String> = None;
                 process_all_kv(s, |k, v| {
@@ -59,24 +75,35 @@ impl FromMiddlemanMsg {
             },
             "punchorder" => {
                 let mut remote: Option<SocketAddr> = None;
+                let mut delta: Option<i32> = None;
+                let mut candidates: Vec<SocketAddr> = Vec::new();
                 process_all_kv(s, |k, v| {
                     if k == "remote" { remote = v.parse::<SocketAddr>().ok(); }
+                    if k == "delta" { delta = v.parse::<i32>().ok(); }
+                    if k == "cands" { candidates = parse_addrs(v); }
                 })?;
-                Self::PunchOrder { remote: remote? }
+                Self::PunchOrder { remote: remote?, delta, candidates }
             },
             "punchlnksk" => {
                 let mut port: Option<u16> = None;
+                let mut remote: Option<SocketAddr> = None;
                 process_all_kv(s, |k, v| {
                     if k == "port" { port = v.parse::<u16>().ok(); }
+                    if k == "remote" { remote = v.parse::<SocketAddr>().ok(); }
                 })?;
-                Self::PunchLinkseeker { port: port? }
+                Self::PunchLinkseeker { port: port?, remote }
             },
             "punchcheckr" => {
                 let mut ok: Option<bool> = None;
+                let mut ports: Option<VecCustom<u16>> = None;
+                let mut delta: Option<i32> = None;
                 process_all_kv(s, |k, v| {
                     if k == "ok" { ok = if v == "1" { Some(true) } else if v == "0" { Some(false) } else { None }; }
+                    if k == "ports" { ports = v.parse().ok() }
+                    if k == "delta" { delta = v.parse::<i32>().ok(); }
                 })?;
-                Self::PunchCheckResult { ok: ok? }
+                let ports = ports.map(|p| p.0).unwrap_or_default();
+                Self::PunchCheckResult { ok: ok?, ports, delta }
             },
             "proxyr" => {
                 let mut ok: Option<bool> = None;
@@ -101,12 +128,73 @@ impl FromMiddlemanMsg {
                     if k == "results" { results = v.parse().ok() }
                     if k == "domain" { domain = Some(v.to_string()) }
                 })?;
-                let Some(results) = results else {
-                    return None;
-                };
+                let results = results?;
                 let results = results.0.iter().map(|addr| addr.0).collect::<Vec<_>>();
                 Self::DomainNameResult { domain: domain?, results }
             },
+            "dnrerr" => {
+                let mut domain: Option<String> = None;
+                let mut msg: Option<String> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "domain" { domain = Some(v.to_string()) }
+                    if k == "msg" { msg = Some(v.to_string()); }
+                })?;
+                Self::DomainNameErr { domain: domain?, msg: msg? }
+            },
+            "natprober" => {
+                let mut id: Option<u32> = None;
+                let mut observed: Option<SocketAddr> = None;
+                let mut socket_n: Option<u8> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "id" { id = v.parse::<u32>().ok() }
+                    if k == "observed" { observed = v.parse::<SocketAddr>().ok(); }
+                    if k == "socket" { socket_n = v.parse::<u8>().ok() }
+                })?;
+                Self::NatProbeResult { id: id?, observed: observed?, socket_n: socket_n? }
+            },
+            "youraddr" => {
+                let mut observed: Option<SocketAddr> = None;
+                let mut socket_n: Option<u8> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "observed" { observed = v.parse::<SocketAddr>().ok(); }
+                    if k == "socket" { socket_n = v.parse::<u8>().ok() }
+                })?;
+                Self::YourAddr { observed: observed?, socket_n: socket_n? }
+            },
+            "allocok" => {
+                let mut port: Option<u16> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "port" { port = v.parse::<u16>().ok() }
+                })?;
+                Self::AllocateOk { port: port? }
+            },
+            "chanbound" => {
+                let mut channel: Option<u16> = None;
+                let mut peer: Option<SocketAddr> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "channel" { channel = v.parse::<u16>().ok() }
+                    if k == "peer" { peer = v.parse::<SocketAddr>().ok(); }
+                })?;
+                Self::ChannelBound { channel: channel?, peer: peer? }
+            },
+            "chanbinderr" => {
+                let mut channel: Option<u16> = None;
+                let mut msg: Option<String> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "channel" { channel = v.parse::<u16>().ok() }
+                    if k == "msg" { msg = Some(v.to_string()); }
+                })?;
+                Self::ChannelBindErr { channel: channel?, msg: msg? }
+            },
+            "proxyclosed" => {
+                let mut remote: Option<SocketAddr> = None;
+                let mut msg: Option<String> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "remote" { remote = v.parse::<SocketAddr>().ok(); }
+                    if k == "msg" { msg = Some(v.to_string()); }
+                })?;
+                Self::ProxyClosed { remote: remote?, msg: msg? }
+            },
             _ => return None,
         };
         Some(parsed)
@@ -118,21 +206,34 @@ impl ToMiddlemanMsg {
         let tail = check_head(bytes)?;
         let tail = String::from_utf8_lossy(tail);
         let mut s = tail.split('/');
-        let Some(command) = s.next() else {
-            return None;
-        };
+        let command = s.next()?;
         let parsed = match command {
             "register" => {
-                Self::Register
+                let mut candidates: Vec<SocketAddr> = Vec::new();
+                process_all_kv(s, |k, v| {
+                    if k == "cands" { candidates = parse_addrs(v); }
+                })?;
+                Self::Register { candidates }
+            },
+            "renew" => {
+                let mut id: Option<u32> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "id" { id = v.parse::<u32>().ok() }
+                })?;
+                Self::Renew { id: id? }
             },
             "request" => {
                 let mut id: Option<u32> = None;
                 let mut use_proxy: Option<bool> = None;
+                let mut candidates: Vec<SocketAddr> = Vec::new();
+                let mut token: Option<String> = None;
                 process_all_kv(s, |k, v| {
                     if k == "id" { id = v.parse::<u32>().ok() }
                     if k == "useproxy" { use_proxy = if v == "1" { Some(true) } else if v == "0" { Some(false) } else { None }; }
+                    if k == "cands" { candidates = parse_addrs(v); }
+                    if k == "token" { token = Some(v.to_string()); }
                 })?;
-                Self::Request { id: id?, use_proxy: use_proxy.unwrap_or(false) }
+                Self::Request { id: id?, use_proxy: use_proxy.unwrap_or(false), candidates, token }
             },
             "punchcheck" => {
                 let mut id: Option<u32> = None;
@@ -143,10 +244,12 @@ impl ToMiddlemanMsg {
             },
             "proxy" => {
                 let mut remote: Option<SocketAddr> = None;
+                let mut token: Option<String> = None;
                 process_all_kv(s, |k, v| {
                     if k == "remote" { remote = v.parse::<SocketAddr>().ok(); }
+                    if k == "token" { token = Some(v.to_string()); }
                 })?;
-                Self::ProxyTo { remote: remote? }
+                Self::ProxyTo { remote: remote?, token }
             },
             "ping" => {
                 let mut id: Option<u32> = None;
@@ -162,6 +265,99 @@ impl ToMiddlemanMsg {
                 })?;
                 Self::DomainNameReq { domain: domain? }
             },
+            "natprobe" => {
+                let mut id: Option<u32> = None;
+                let mut reply_from: Option<u8> = None;
+                let mut reply_via: Option<SocketAddr> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "id" { id = v.parse::<u32>().ok() }
+                    if k == "reply" { reply_from = v.parse::<u8>().ok() }
+                    if k == "via" { reply_via = v.parse::<SocketAddr>().ok() }
+                })?;
+                Self::NatProbe { id: id?, reply_from, reply_via }
+            },
+            "natprobefor" => {
+                let mut id: Option<u32> = None;
+                let mut observed: Option<SocketAddr> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "id" { id = v.parse::<u32>().ok() }
+                    if k == "observed" { observed = v.parse::<SocketAddr>().ok() }
+                })?;
+                Self::NatProbeFor { id: id?, observed: observed? }
+            },
+            "whatismyaddr" => {
+                Self::WhatIsMyAddr
+            },
+            "allocate" => {
+                Self::Allocate
+            },
+            "chanbind" => {
+                let mut channel: Option<u16> = None;
+                let mut peer: Option<SocketAddr> = None;
+                let mut token: Option<String> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "channel" { channel = v.parse::<u16>().ok() }
+                    if k == "peer" { peer = v.parse::<SocketAddr>().ok(); }
+                    if k == "token" { token = Some(v.to_string()); }
+                })?;
+                Self::ChannelBind { channel: channel?, peer: peer?, token }
+            },
+            _ => return None,
+        };
+        Some(parsed)
+    }
+}
+
+impl PeerMsg {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let tail = check_head(bytes)?;
+        let tail = String::from_utf8_lossy(tail);
+        let mut s = tail.split('/');
+        let command = s.next()?;
+        let parsed = match command {
+            "phello" => {
+                let mut nonce: Option<u32> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "nonce" { nonce = v.parse::<u32>().ok() }
+                })?;
+                Self::PunchHello { nonce: nonce? }
+            },
+            "pack" => {
+                let mut nonce: Option<u32> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "nonce" { nonce = v.parse::<u32>().ok() }
+                })?;
+                Self::PunchAck { nonce: nonce? }
+            },
+            _ => return None,
+        };
+        Some(parsed)
+    }
+}
+
+impl LanMsg {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let tail = check_head(bytes)?;
+        let tail = String::from_utf8_lossy(tail);
+        let mut s = tail.split('/');
+        let command = s.next()?;
+        let parsed = match command {
+            "lanannounce" => {
+                let mut id: Option<u32> = None;
+                let mut port: Option<u16> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "id" { id = v.parse::<u32>().ok() }
+                    if k == "port" { port = v.parse::<u16>().ok() }
+                })?;
+                Self::Announce { id: id?, port: port? }
+            },
+            "lanquery" => {
+                let mut id: Option<u32> = None;
+                process_all_kv(s, |k, v| {
+                    if k == "id" { id = v.parse::<u32>().ok() }
+                })?;
+                Self::Query { id: id? }
+            },
             _ => return None,
         };
         Some(parsed)
@@ -172,11 +368,45 @@ impl ToMiddlemanMsg {
 #[cfg(test)]
 fn parse_deserialized_from_middleman() {
     let remote = "127.0.0.1:15555".parse::<SocketAddr>().unwrap();
-    let orig = FromMiddlemanMsg::PunchOrder { remote };
+    let orig = FromMiddlemanMsg::PunchOrder { remote, delta: None, candidates: vec![] };
+    let deser = FromMiddlemanMsg::parse(&orig.serialize()).unwrap();
+    assert_eq!(orig, deser);
+
+    let orig = FromMiddlemanMsg::PunchOrder { remote, delta: Some(-2), candidates: vec![] };
     let deser = FromMiddlemanMsg::parse(&orig.serialize()).unwrap();
     assert_eq!(orig, deser);
 }
 
+#[test]
+#[cfg(test)]
+fn parse_deserialized_candidates() {
+    let candidates = vec![
+        "192.168.1.20:5000".parse::<SocketAddr>().unwrap(),
+        "[fe80::1]:5000".parse::<SocketAddr>().unwrap(),
+    ];
+    let orig = ToMiddlemanMsg::Register { candidates: candidates.clone() };
+    assert_eq!(ToMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
+    let orig = ToMiddlemanMsg::Request { id: 5, use_proxy: false, candidates: candidates.clone(), token: None };
+    assert_eq!(ToMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
+    let orig = FromMiddlemanMsg::PunchOrder { remote: "1.2.3.4:5000".parse().unwrap(), delta: Some(1), candidates };
+    assert_eq!(FromMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
+
+    // sent by older clients
+    assert_eq!(ToMiddlemanMsg::parse(b"#lnksk@register"), Some(ToMiddlemanMsg::Register { candidates: vec![] }));
+}
+
+#[test]
+#[cfg(test)]
+fn parse_deserialized_punch_check_result() {
+    let orig = FromMiddlemanMsg::PunchCheckResult { ok: false, ports: vec![1000, 1001, 1002, 1003], delta: Some(1) };
+    let deser = FromMiddlemanMsg::parse(&orig.serialize()).unwrap();
+    assert_eq!(orig, deser);
+
+    // sent by older trackers
+    let deser = FromMiddlemanMsg::parse(b"#lnksk@punchcheckr/ok=1").unwrap();
+    assert_eq!(deser, FromMiddlemanMsg::PunchCheckResult { ok: true, ports: vec![], delta: None });
+}
+
 #[test]
 #[cfg(test)]
 fn parse_deserialized_from_middleman2() {
@@ -195,12 +425,91 @@ fn parse_deserialized_from_middleman2() {
     ] };
     let deser = FromMiddlemanMsg::parse(&orig.serialize()).unwrap();
     assert_eq!(orig, deser);
+
+    let orig = FromMiddlemanMsg::DomainNameErr { domain: "pote.com:80".into(), msg: "no address found".into() };
+    assert_eq!(FromMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
 }
 
 #[test]
 #[cfg(test)]
 fn parse_deserialized_to_middleman() {
-    let orig = ToMiddlemanMsg::Request { id: 1234, use_proxy: true };
+    let orig = ToMiddlemanMsg::Request { id: 1234, use_proxy: true, candidates: vec![], token: Some("1700000000.0123abcd".into()) };
+    let deser = ToMiddlemanMsg::parse(&orig.serialize()).unwrap();
+    assert_eq!(orig, deser);
+}
+
+#[test]
+#[cfg(test)]
+fn parse_deserialized_nat_probe() {
+    let orig = ToMiddlemanMsg::NatProbe { id: 77, reply_from: Some(1), reply_via: None };
+    let deser = ToMiddlemanMsg::parse(&orig.serialize()).unwrap();
+    assert_eq!(orig, deser);
+
+    let orig = ToMiddlemanMsg::NatProbe { id: 77, reply_from: None, reply_via: Some("[2001:db8::1]:61990".parse().unwrap()) };
     let deser = ToMiddlemanMsg::parse(&orig.serialize()).unwrap();
     assert_eq!(orig, deser);
-}
\ No newline at end of file
+
+    let orig = ToMiddlemanMsg::NatProbeFor { id: 77, observed: "8.8.8.8:4000".parse().unwrap() };
+    let deser = ToMiddlemanMsg::parse(&orig.serialize()).unwrap();
+    assert_eq!(orig, deser);
+
+    let orig = FromMiddlemanMsg::NatProbeResult { id: 77, observed: "[::1]:4000".parse().unwrap(), socket_n: 2 };
+    let deser = FromMiddlemanMsg::parse(&orig.serialize()).unwrap();
+    assert_eq!(orig, deser);
+}
+
+#[test]
+#[cfg(test)]
+fn parse_deserialized_what_is_my_addr() {
+    let orig = ToMiddlemanMsg::WhatIsMyAddr;
+    assert_eq!(ToMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
+    let orig = FromMiddlemanMsg::YourAddr { observed: "1.2.3.4:5000".parse().unwrap(), socket_n: 3 };
+    assert_eq!(FromMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
+}
+
+#[test]
+#[cfg(test)]
+fn parse_deserialized_renew() {
+    let orig = ToMiddlemanMsg::Renew { id: 31 };
+    assert_eq!(ToMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
+    let orig = FromMiddlemanMsg::RenewOk { id: 31 };
+    assert_eq!(FromMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
+    let orig = FromMiddlemanMsg::RegisterExpired { id: 31 };
+    assert_eq!(FromMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
+}
+
+#[test]
+#[cfg(test)]
+fn parse_deserialized_peer() {
+    let orig = PeerMsg::PunchHello { nonce: 987654 };
+    let deser = PeerMsg::parse(&orig.serialize()).unwrap();
+    assert_eq!(orig, deser);
+    assert_eq!(ToMiddlemanMsg::parse(&orig.serialize()), None);
+}
+
+#[test]
+#[cfg(test)]
+fn parse_deserialized_lan() {
+    let orig = LanMsg::Announce { id: 0x1f2e3d4c, port: 5000 };
+    assert_eq!(LanMsg::parse(&orig.serialize()).unwrap(), orig);
+    let orig = LanMsg::Query { id: 0x1f2e3d4c };
+    assert_eq!(LanMsg::parse(&orig.serialize()).unwrap(), orig);
+    assert_eq!(ToMiddlemanMsg::parse(&orig.serialize()), None);
+}
+
+#[test]
+#[cfg(test)]
+fn parse_deserialized_channels() {
+    let peer = "1.2.3.4:5000".parse::<SocketAddr>().unwrap();
+    for orig in [ToMiddlemanMsg::Allocate, ToMiddlemanMsg::ChannelBind { channel: 0x4001, peer, token: Some("1000.ab".into()) }] {
+        assert_eq!(ToMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
+    }
+    for orig in [
+        FromMiddlemanMsg::AllocateOk { port: 61990 },
+        FromMiddlemanMsg::ChannelBound { channel: 0x4001, peer },
+        FromMiddlemanMsg::ChannelBindErr { channel: 0x4001, msg: "channel already bound".into() },
+        FromMiddlemanMsg::ProxyClosed { remote: peer, msg: "session quota exceeded".into() },
+    ] {
+        assert_eq!(FromMiddlemanMsg::parse(&orig.serialize()).unwrap(), orig);
+    }
+}
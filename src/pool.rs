@@ -0,0 +1,317 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant}
+};
+
+use crate::{
+    client::{link_id_key, Transmit},
+    common::random_u32,
+    data::{FromMiddlemanMsg, ToMiddlemanMsg}
+};
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Delay between two pings to every tracker
+    pub ping_interval: Duration,
+    /// A ping not answered after that long is lost
+    pub ping_timeout: Duration,
+    /// How much the recent loss ratio weighs against the RTT when ranking trackers: with 4,
+    /// losing 1 ping in 4 counts as much as doubling the RTT
+    pub loss_penalty: f32,
+    /// Consecutive lost pings after which a tracker is considered down
+    pub max_losses: u32,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(2),
+            ping_timeout: Duration::from_secs(1),
+            loss_penalty: 4.0,
+            max_losses: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrackerStats {
+    pub key: u8,
+    pub addr: SocketAddr,
+    /// Smoothed round trip time, `None` until the first pong
+    pub rtt: Option<Duration>,
+    pub sent: u64,
+    pub lost: u64,
+    /// Smoothed ratio of lost pings, the recent ones weighing the most
+    pub recent_loss: f32,
+    pub consecutive_losses: u32,
+}
+
+impl TrackerStats {
+    fn new(key: u8, addr: SocketAddr) -> Self {
+        Self { key, addr, rtt: None, sent: 0, lost: 0, recent_loss: 0.0, consecutive_losses: 0 }
+    }
+
+    /// Ratio of lost pings, between 0 and 1
+    pub fn loss(&self) -> f32 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.lost as f32 / self.sent as f32
+    }
+
+    /// RTT inflated by the recent losses, lower is better: each lost ping costs a retry
+    pub fn score(&self, config: &PoolConfig) -> Option<Duration> {
+        Some(self.rtt?.mul_f32(1.0 + config.loss_penalty * self.recent_loss))
+    }
+
+    fn is_up(&self, config: &PoolConfig) -> bool {
+        self.rtt.is_some() && self.consecutive_losses < config.max_losses
+    }
+
+    fn add_rtt_sample(&mut self, sample: Duration) {
+        // same smoothing as TCP's SRTT
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+        self.add_loss_sample(false);
+        self.consecutive_losses = 0;
+    }
+
+    fn add_loss_sample(&mut self, lost: bool) {
+        self.recent_loss = (self.recent_loss * 7.0 + if lost { 1.0 } else { 0.0 }) / 8.0;
+        if lost {
+            self.lost += 1;
+            self.consecutive_losses += 1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolEvent {
+    /// A tracker was picked, either the first one to answer or a much faster or more reliable one
+    Selected { key: u8, addr: SocketAddr },
+    /// The selected tracker stopped answering, another one took over
+    FailedOver { from: SocketAddr, to: SocketAddr },
+    /// No tracker answers anymore
+    AllDown,
+}
+
+struct InFlight {
+    id: u32,
+    tracker_n: usize,
+    sent: Instant,
+}
+
+/// Sans-IO pool of trackers, usually built from `client::fetch_linkseekers`.
+///
+/// Pings every tracker periodically, keeps their RTT and loss, selects the best one (see
+/// `TrackerStats::score`) and fails over when it stops answering. Driven the same way as
+/// `LinkSeekClient`.
+pub struct TrackerPool {
+    config: PoolConfig,
+    trackers: Vec<TrackerStats>,
+    in_flight: Vec<InFlight>,
+    next_ping: Instant,
+    selected: Option<usize>,
+    transmits: VecDeque<Transmit>,
+    events: VecDeque<PoolEvent>,
+}
+
+impl TrackerPool {
+    pub fn new(trackers: Vec<(u8, SocketAddr)>, config: PoolConfig, now: Instant) -> Self {
+        let mut pool = Self {
+            config,
+            trackers: trackers.into_iter().map(|(key, addr)| TrackerStats::new(key, addr)).collect(),
+            in_flight: Vec::new(),
+            next_ping: now,
+            selected: None,
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        };
+        pool.handle_timeout(now);
+        pool
+    }
+
+    /// The selected tracker, if any answered
+    pub fn best(&self) -> Option<(u8, SocketAddr)> {
+        self.selected.map(|i| (self.trackers[i].key, self.trackers[i].addr))
+    }
+
+    /// The tracker that issued this link id, preferring one that answers if several share its key
+    pub fn tracker_for_link_id(&self, id: u32) -> Option<SocketAddr> {
+        let key = link_id_key(id);
+        let mut matching = self.trackers.iter().filter(|t| t.key == key);
+        let first = matching.clone().next()?;
+        let up = matching.find(|t| t.is_up(&self.config)).unwrap_or(first);
+        Some(up.addr)
+    }
+
+    pub fn stats(&self) -> &[TrackerStats] {
+        &self.trackers
+    }
+
+    /// Returns whether or not the datagram was a pong answering one of our pings
+    pub fn handle_datagram(&mut self, bytes: &[u8], from: SocketAddr, now: Instant) -> bool {
+        let Some(FromMiddlemanMsg::Pong { id }) = FromMiddlemanMsg::parse(bytes) else {
+            return false;
+        };
+        let Some(pos) = self.in_flight.iter().position(|p| p.id == id && self.trackers[p.tracker_n].addr == from) else {
+            return false;
+        };
+        let ping = self.in_flight.swap_remove(pos);
+        self.trackers[ping.tracker_n].add_rtt_sample(now.saturating_duration_since(ping.sent));
+        self.select();
+        true
+    }
+
+    /// Counts lost pings, and sends new ones when due
+    pub fn handle_timeout(&mut self, now: Instant) {
+        let timeout = self.config.ping_timeout;
+        let trackers = &mut self.trackers;
+        self.in_flight.retain(|p| {
+            let lost = now >= p.sent + timeout;
+            if lost {
+                trackers[p.tracker_n].add_loss_sample(true);
+            }
+            !lost
+        });
+        if now >= self.next_ping {
+            self.next_ping = now + self.config.ping_interval;
+            for (tracker_n, tracker) in self.trackers.iter_mut().enumerate() {
+                let id = random_u32();
+                tracker.sent += 1;
+                self.in_flight.push(InFlight { id, tracker_n, sent: now });
+                self.transmits.push_back(Transmit { dest: tracker.addr, bytes: ToMiddlemanMsg::Ping { id }.serialize() });
+            }
+        }
+        self.select();
+    }
+
+    fn select(&mut self) {
+        let best = self.trackers.iter().enumerate()
+            .filter(|(_, t)| t.is_up(&self.config))
+            .min_by_key(|(_, t)| t.score(&self.config))
+            .map(|(i, _)| i);
+        let current = self.selected.filter(|i| self.trackers[*i].is_up(&self.config));
+        match (self.selected, current, best) {
+            (None, _, Some(best)) => {
+                self.selected = Some(best);
+                self.events.push_back(PoolEvent::Selected { key: self.trackers[best].key, addr: self.trackers[best].addr });
+            },
+            (Some(old), None, Some(best)) => {
+                self.selected = Some(best);
+                self.events.push_back(PoolEvent::FailedOver { from: self.trackers[old].addr, to: self.trackers[best].addr });
+            },
+            (Some(_), None, None) => {
+                self.selected = None;
+                self.events.push_back(PoolEvent::AllDown);
+            },
+            // only switch for a tracker scoring twice better, so we don't flap between close ones
+            (Some(_), Some(current), Some(best))
+                if best != current
+                && self.trackers[best].score(&self.config).unwrap_or_default() * 2
+                    < self.trackers[current].score(&self.config).unwrap_or_default() =>
+            {
+                self.selected = Some(best);
+                self.events.push_back(PoolEvent::Selected { key: self.trackers[best].key, addr: self.trackers[best].addr });
+            },
+            _ => {},
+        }
+    }
+
+    /// When `handle_timeout` should be called next
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        let lost = self.in_flight.iter().map(|p| p.sent + self.config.ping_timeout);
+        lost.chain(Some(self.next_ping)).min()
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<PoolEvent> {
+        self.events.pop_front()
+    }
+}
+
+#[cfg(test)]
+/// answers the pings of the `alive` trackers, in that order
+fn answer_pings(pool: &mut TrackerPool, alive: &[SocketAddr], now: Instant) {
+    let mut pings = Vec::new();
+    while let Some(t) = pool.poll_transmit() {
+        let Some(ToMiddlemanMsg::Ping { id }) = ToMiddlemanMsg::parse(&t.bytes) else {
+            panic!("pool should only send pings");
+        };
+        pings.push((t.dest, id));
+    }
+    for addr in alive {
+        for (_, id) in pings.iter().filter(|(dest, _)| dest == addr) {
+            assert!(pool.handle_datagram(&FromMiddlemanMsg::Pong { id: *id }.serialize(), *addr, now));
+        }
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn pool_selects_and_fails_over() {
+    let a: SocketAddr = "1.1.1.1:61990".parse().unwrap();
+    let b: SocketAddr = "2.2.2.2:61990".parse().unwrap();
+    let config = PoolConfig::default();
+    let mut now = Instant::now();
+    let mut pool = TrackerPool::new(vec![(1, a), (2, b)], config.clone(), now);
+    assert_eq!(pool.best(), None);
+
+    // b answers first
+    now += Duration::from_millis(20);
+    answer_pings(&mut pool, &[b, a], now);
+    assert_eq!(pool.poll_event(), Some(PoolEvent::Selected { key: 2, addr: b }));
+    assert_eq!(pool.best(), Some((2, b)));
+
+    // then b goes away
+    let mut rounds = 0;
+    while pool.best() == Some((2, b)) {
+        now = pool.poll_timeout().unwrap();
+        pool.handle_timeout(now);
+        // a answers in 20ms as before: b is left when it goes down, not before
+        answer_pings(&mut pool, &[a], now + Duration::from_millis(20));
+        rounds += 1;
+        assert!(rounds < 4 * config.max_losses);
+    }
+    assert_eq!(pool.poll_event(), Some(PoolEvent::FailedOver { from: b, to: a }));
+    assert_eq!(pool.best(), Some((1, a)));
+    assert!(pool.stats()[1].loss() > 0.0);
+}
+
+#[test]
+#[cfg(test)]
+fn pool_prefers_reliable_over_fast() {
+    let lossy: SocketAddr = "1.1.1.1:61990".parse().unwrap();
+    let steady: SocketAddr = "2.2.2.2:61990".parse().unwrap();
+    let mut now = Instant::now();
+    let mut pool = TrackerPool::new(vec![(1, lossy), (2, steady)], PoolConfig::default(), now);
+    let mut selected = Vec::new();
+    // the lossy tracker answers in 10ms, one ping in 3, the steady one in 15ms
+    for round in 0..30 {
+        let sent = now;
+        let pings: Vec<_> = std::iter::from_fn(|| pool.poll_transmit()).collect();
+        for (addr, rtt, answers) in [(lossy, 10, round % 3 == 0), (steady, 15, true)] {
+            for ping in pings.iter().filter(|t| t.dest == addr && answers) {
+                let Some(ToMiddlemanMsg::Ping { id }) = ToMiddlemanMsg::parse(&ping.bytes) else {
+                    panic!("pool should only send pings");
+                };
+                assert!(pool.handle_datagram(&FromMiddlemanMsg::Pong { id }.serialize(), addr, sent + Duration::from_millis(rtt)));
+            }
+        }
+        while let Some(timeout) = pool.poll_timeout().filter(|t| *t <= sent + PoolConfig::default().ping_interval) {
+            now = timeout;
+            pool.handle_timeout(now);
+        }
+        selected.extend(std::iter::from_fn(|| pool.poll_event()));
+    }
+    assert_eq!(selected.first(), Some(&PoolEvent::Selected { key: 1, addr: lossy }));
+    assert_eq!(selected.last(), Some(&PoolEvent::Selected { key: 2, addr: steady }));
+    assert_eq!(pool.best(), Some((2, steady)));
+    assert!(pool.stats()[0].rtt < pool.stats()[1].rtt);
+}
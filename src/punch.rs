@@ -0,0 +1,497 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant}
+};
+
+use crate::{
+    candidate::prioritize,
+    client::Transmit,
+    common::random_u32,
+    data::{FromMiddlemanMsg, PeerMsg}
+};
+
+#[derive(Debug, Clone)]
+pub struct PunchConfig {
+    /// Number of bursts of `PunchHello` sent to the remote
+    pub bursts: u32,
+    pub packets_per_burst: u32,
+    /// Delay between two bursts
+    pub burst_interval: Duration,
+    /// Time after which the punch is considered failed if not confirmed both ways
+    pub timeout: Duration,
+    /// Number of ports punched after the remote's port when its NAT allocates them sequentially
+    pub predicted_ports: u16,
+}
+
+impl Default for PunchConfig {
+    fn default() -> Self {
+        Self {
+            bursts: 10,
+            packets_per_burst: 2,
+            burst_interval: Duration::from_millis(250),
+            timeout: Duration::from_secs(5),
+            predicted_ports: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PunchEvent {
+    /// The remote acked our hello and we received theirs: the hole is open both ways
+    Succeeded { remote: SocketAddr },
+    Failed { remote: SocketAddr },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PunchState {
+    Punching,
+    Succeeded,
+    Failed,
+}
+
+/// Sans-IO hole puncher, driven the same way as `LinkSeekClient`.
+///
+/// Both peers send `PunchHello` with their own nonce and answer every hello with a `PunchAck`
+/// carrying the same nonce. Receiving an ack for our nonce proves that our packets reach the
+/// remote, receiving their hello proves that theirs reach us.
+///
+/// With several targets, hellos are sent to all of them until the remote answers from an address
+/// with the same IP as one of them. From then on we only talk to that address. Targets join in
+/// order, one burst apart per IP: the first IP gets the first burst to itself, so it answers first
+/// when both it and a later one would go through.
+pub struct Puncher {
+    remote: SocketAddr,
+    targets: Vec<SocketAddr>,
+    /// for each target, the burst from which it gets hellos: the rank of its IP among the targets'
+    /// ones
+    first_bursts: Vec<u32>,
+    /// the remote answered, `remote` is its actual address
+    locked: bool,
+    config: PunchConfig,
+    nonce: u32,
+    state: PunchState,
+    deadline: Instant,
+    bursts_sent: u32,
+    next_burst: Instant,
+    /// we received a hello from the remote
+    heard: bool,
+    /// the remote acked our hello
+    acked: bool,
+    transmits: VecDeque<Transmit>,
+    events: VecDeque<PunchEvent>,
+}
+
+/// Addresses a NAT allocating ports sequentially is likely to use next: the remote itself, then
+/// `count` ports spaced by `delta`
+pub fn predict_ports(remote: SocketAddr, delta: i32, count: u16) -> Vec<SocketAddr> {
+    let mut targets = vec![remote];
+    if delta == 0 {
+        return targets;
+    }
+    for k in 1..=count as i32 {
+        let Ok(port) = u16::try_from(remote.port() as i32 + k * delta) else {
+            break;
+        };
+        if port == 0 {
+            break;
+        }
+        let mut target = remote;
+        target.set_port(port);
+        targets.push(target);
+    }
+    targets
+}
+
+impl Puncher {
+    pub fn new(remote: SocketAddr, config: PunchConfig, now: Instant) -> Self {
+        Self::with_targets(vec![remote], config, now)
+    }
+
+    /// Punch every target until one of them answers, in order, see `Puncher`. Panics if `targets`
+    /// is empty.
+    pub fn with_targets(targets: Vec<SocketAddr>, config: PunchConfig, now: Instant) -> Self {
+        let mut ips = Vec::new();
+        let first_bursts = targets.iter()
+            .map(|t| {
+                let rank = ips.iter().position(|ip| *ip == t.ip()).unwrap_or_else(|| {
+                    ips.push(t.ip());
+                    ips.len() - 1
+                });
+                // the last burst goes to every target, however many IPs they have
+                (rank as u32).min(config.bursts.saturating_sub(1))
+            })
+            .collect();
+        let mut puncher = Self {
+            remote: targets[0],
+            targets,
+            first_bursts,
+            locked: false,
+            deadline: now + config.timeout,
+            config,
+            nonce: random_u32(),
+            state: PunchState::Punching,
+            bursts_sent: 0,
+            next_burst: now,
+            heard: false,
+            acked: false,
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        };
+        puncher.handle_timeout(now);
+        puncher
+    }
+
+    /// Punch the remote, and the ports its NAT will likely allocate if we know its `delta`
+    pub fn with_prediction(remote: SocketAddr, delta: Option<i32>, config: PunchConfig, now: Instant) -> Self {
+        let targets = match delta {
+            Some(delta) => predict_ports(remote, delta, config.predicted_ports),
+            None => vec![remote],
+        };
+        Self::with_targets(targets, config, now)
+    }
+
+    /// Punch the candidates of the remote in the order of `candidate::prioritize`, then the ports
+    /// its NAT will likely allocate. `local` are our own candidates. The first one to answer wins,
+    /// the higher priority ones getting a head start.
+    pub fn with_candidates(
+        remote: SocketAddr,
+        delta: Option<i32>,
+        candidates: &[SocketAddr],
+        local: &[SocketAddr],
+        config: PunchConfig,
+        now: Instant
+    ) -> Self {
+        let mut targets = prioritize(remote, candidates, local);
+        if let Some(delta) = delta {
+            let predicted = predict_ports(remote, delta, config.predicted_ports);
+            targets.extend(predicted.into_iter().filter(|p| *p != remote));
+        }
+        Self::with_targets(targets, config, now)
+    }
+
+    /// Start punching the remote of a `FromMiddlemanMsg::PunchOrder`, `None` for any other message.
+    /// `local` are the candidates we gave the tracker.
+    pub fn from_order(msg: &FromMiddlemanMsg, local: &[SocketAddr], config: PunchConfig, now: Instant) -> Option<Self> {
+        match msg {
+            FromMiddlemanMsg::PunchOrder { remote, delta, candidates } => {
+                Some(Self::with_candidates(*remote, *delta, candidates, local, config, now))
+            },
+            _ => None,
+        }
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub fn nonce(&self) -> u32 {
+        self.nonce
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state != PunchState::Punching
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.state == PunchState::Succeeded
+    }
+
+    fn send(&mut self, msg: PeerMsg) {
+        if self.locked {
+            self.transmits.push_back(Transmit { dest: self.remote, bytes: msg.serialize() });
+            return;
+        }
+        let bytes = msg.serialize();
+        for (target, first_burst) in self.targets.iter().zip(&self.first_bursts) {
+            if *first_burst <= self.bursts_sent {
+                self.transmits.push_back(Transmit { dest: *target, bytes: bytes.clone() });
+            }
+        }
+    }
+
+    fn is_from_remote(&self, from: SocketAddr) -> bool {
+        if self.locked {
+            from == self.remote
+        } else {
+            self.targets.iter().any(|t| t.ip() == from.ip())
+        }
+    }
+
+    /// Returns whether or not the datagram was a punch message from the remote.
+    ///
+    /// Hellos are still answered once we are done, the remote may not have received our ack yet.
+    pub fn handle_datagram(&mut self, bytes: &[u8], from: SocketAddr, _now: Instant) -> bool {
+        if !self.is_from_remote(from) {
+            return false;
+        }
+        let Some(msg) = PeerMsg::parse(bytes) else {
+            return false;
+        };
+        if !self.locked {
+            self.remote = from;
+            self.locked = true;
+        }
+        match msg {
+            PeerMsg::PunchHello { nonce } => {
+                self.heard = true;
+                self.send(PeerMsg::PunchAck { nonce });
+                if self.state == PunchState::Punching && !self.acked {
+                    // the remote can reach us now, give it something to ack right away
+                    self.send(PeerMsg::PunchHello { nonce: self.nonce });
+                }
+            },
+            PeerMsg::PunchAck { nonce } => {
+                if nonce == self.nonce {
+                    self.acked = true;
+                }
+            },
+        }
+        if self.state == PunchState::Punching && self.heard && self.acked {
+            self.state = PunchState::Succeeded;
+            self.events.push_back(PunchEvent::Succeeded { remote: self.remote });
+        }
+        true
+    }
+
+    /// Sends the next burst when it is due, and fails the punch once the timeout is reached
+    pub fn handle_timeout(&mut self, now: Instant) {
+        if self.state != PunchState::Punching {
+            return;
+        }
+        if now >= self.deadline {
+            self.state = PunchState::Failed;
+            self.events.push_back(PunchEvent::Failed { remote: self.remote });
+            return;
+        }
+        if self.bursts_sent < self.config.bursts && now >= self.next_burst {
+            for _ in 0..self.config.packets_per_burst {
+                self.send(PeerMsg::PunchHello { nonce: self.nonce });
+            }
+            self.bursts_sent += 1;
+            self.next_burst = now + self.config.burst_interval;
+        }
+    }
+
+    /// When `handle_timeout` should be called next
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        if self.state != PunchState::Punching {
+            return None;
+        }
+        if self.bursts_sent < self.config.bursts {
+            Some(self.next_burst.min(self.deadline))
+        } else {
+            Some(self.deadline)
+        }
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.transmits.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<PunchEvent> {
+        self.events.pop_front()
+    }
+}
+
+/// Punches from several local sockets at once, which raises the odds of hitting a port the remote
+/// NAT allocated. Transmits and events are tagged with the index of the local socket.
+pub struct PunchSet {
+    punchers: Vec<Puncher>,
+    /// an event was already reported for the whole set
+    reported: bool,
+}
+
+impl PunchSet {
+    pub fn new(punchers: Vec<Puncher>) -> Self {
+        Self { punchers, reported: false }
+    }
+
+    pub fn handle_datagram(&mut self, socket_n: usize, bytes: &[u8], from: SocketAddr, now: Instant) -> bool {
+        self.punchers.get_mut(socket_n).is_some_and(|p| p.handle_datagram(bytes, from, now))
+    }
+
+    pub fn handle_timeout(&mut self, now: Instant) {
+        for puncher in &mut self.punchers {
+            puncher.handle_timeout(now);
+        }
+    }
+
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.punchers.iter().filter_map(|p| p.poll_timeout()).min()
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<(usize, Transmit)> {
+        self.punchers.iter_mut().enumerate().find_map(|(i, p)| p.poll_transmit().map(|t| (i, t)))
+    }
+
+    /// The first success of any socket, or a failure once they all failed
+    pub fn poll_event(&mut self) -> Option<(usize, PunchEvent)> {
+        let mut first_failed = None;
+        for (i, puncher) in self.punchers.iter_mut().enumerate() {
+            while let Some(event) = puncher.poll_event() {
+                match event {
+                    PunchEvent::Succeeded { .. } if !self.reported => {
+                        self.reported = true;
+                        return Some((i, event));
+                    },
+                    PunchEvent::Failed { .. } => { first_failed.get_or_insert((i, event)); },
+                    _ => {},
+                }
+            }
+        }
+        if self.reported || !self.punchers.iter().all(|p| p.is_done()) {
+            return None;
+        }
+        self.reported = true;
+        first_failed
+    }
+}
+
+#[cfg(test)]
+fn exchange(from: &mut Puncher, to: &mut Puncher, now: Instant) {
+    while let Some(t) = from.poll_transmit() {
+        assert!(from.targets.contains(&t.dest));
+        let from_addr = to.remote;
+        to.handle_datagram(&t.bytes, from_addr, now);
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn punch_confirms_both_ways() {
+    let a_addr: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let b_addr: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let now = Instant::now();
+    let mut a = Puncher::new(b_addr, PunchConfig::default(), now);
+    let mut b = Puncher::new(a_addr, PunchConfig::default(), now);
+
+    // the first burst of a is dropped by b's NAT, but opens a's side
+    while a.poll_transmit().is_some() {}
+    // b's burst goes through: a answers with acks and its own hello
+    exchange(&mut b, &mut a, now);
+    assert!(!a.is_done());
+    exchange(&mut a, &mut b, now);
+    assert_eq!(b.poll_event(), Some(PunchEvent::Succeeded { remote: a_addr }));
+    exchange(&mut b, &mut a, now);
+    assert_eq!(a.poll_event(), Some(PunchEvent::Succeeded { remote: b_addr }));
+}
+
+#[test]
+#[cfg(test)]
+fn punch_one_way_is_not_success() {
+    let b_addr: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let config = PunchConfig::default();
+    let mut now = Instant::now();
+    let mut a = Puncher::new(b_addr, config.clone(), now);
+
+    // we hear the remote, but it never acks what we send
+    let hello = PeerMsg::PunchHello { nonce: 5 }.serialize();
+    assert!(a.handle_datagram(&hello, b_addr, now));
+    let mut sent = 0;
+    while let Some(timeout) = a.poll_timeout() {
+        now = timeout;
+        a.handle_timeout(now);
+        while a.poll_transmit().is_some() {
+            sent += 1;
+        }
+    }
+    assert!(sent >= (config.bursts * config.packets_per_burst) as usize);
+    assert_eq!(a.poll_event(), Some(PunchEvent::Failed { remote: b_addr }));
+    assert!(!a.is_success());
+}
+
+
+#[test]
+#[cfg(test)]
+fn punch_predicted_ports() {
+    let remote: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let targets = predict_ports(remote, 2, 3);
+    assert_eq!(targets.iter().map(|t| t.port()).collect::<Vec<_>>(), vec![2000, 2002, 2004, 2006]);
+    assert_eq!(predict_ports(remote, 0, 3), vec![remote]);
+    assert_eq!(predict_ports("2.2.2.2:65534".parse().unwrap(), 1, 3).len(), 2);
+
+    let now = Instant::now();
+    let mut a = Puncher::with_prediction(remote, Some(2), PunchConfig::default(), now);
+    let mut dests = Vec::new();
+    while let Some(t) = a.poll_transmit() {
+        dests.push(t.dest);
+    }
+    assert!(dests.contains(&"2.2.2.2:2032".parse().unwrap()));
+
+    // the remote NAT picked 2004 for us: we stick to it
+    let actual: SocketAddr = "2.2.2.2:2004".parse().unwrap();
+    assert!(a.handle_datagram(&PeerMsg::PunchHello { nonce: 1 }.serialize(), actual, now));
+    assert!(a.handle_datagram(&PeerMsg::PunchAck { nonce: a.nonce() }.serialize(), actual, now));
+    assert_eq!(a.poll_event(), Some(PunchEvent::Succeeded { remote: actual }));
+    assert!(!a.handle_datagram(&PeerMsg::PunchHello { nonce: 1 }.serialize(), remote, now));
+}
+
+#[test]
+#[cfg(test)]
+fn punch_same_lan_through_candidates() {
+    // both behind the same router, which does not hairpin
+    let a_public: SocketAddr = "9.9.9.9:1000".parse().unwrap();
+    let b_public: SocketAddr = "9.9.9.9:1001".parse().unwrap();
+    let a_lan: SocketAddr = "192.168.1.10:5000".parse().unwrap();
+    let b_lan: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+    let now = Instant::now();
+    let mut a = Puncher::with_candidates(b_public, None, &[b_lan], &[a_lan], PunchConfig::default(), now);
+    let mut b = Puncher::with_candidates(a_public, None, &[a_lan], &[b_lan], PunchConfig::default(), now);
+    assert_eq!(a.targets, vec![b_lan, b_public]);
+    // the LAN candidate gets a head start: the first burst is for it alone
+    let first_burst: Vec<_> = std::iter::from_fn(|| b.poll_transmit()).map(|t| t.dest).collect();
+    assert_eq!(first_burst, vec![a_lan; PunchConfig::default().packets_per_burst as usize]);
+    b.handle_timeout(now + PunchConfig::default().burst_interval);
+    let second_burst: Vec<_> = std::iter::from_fn(|| b.poll_transmit()).map(|t| t.dest).collect();
+    assert_eq!(second_burst, [a_lan, a_public].repeat(PunchConfig::default().packets_per_burst as usize));
+
+    for _ in 0..2 {
+        while let Some(t) = a.poll_transmit() {
+            if t.dest == b_lan {
+                b.handle_datagram(&t.bytes, a_lan, now);
+            }
+        }
+        while let Some(t) = b.poll_transmit() {
+            if t.dest == a_lan {
+                a.handle_datagram(&t.bytes, b_lan, now);
+            }
+        }
+    }
+    assert_eq!(a.poll_event(), Some(PunchEvent::Succeeded { remote: b_lan }));
+    assert_eq!(b.poll_event(), Some(PunchEvent::Succeeded { remote: a_lan }));
+}
+
+#[test]
+#[cfg(test)]
+fn punch_prefers_higher_priority_candidate() {
+    // the router hairpins: both the LAN and the public addresses go through
+    let a_public: SocketAddr = "9.9.9.9:1000".parse().unwrap();
+    let b_public: SocketAddr = "9.9.9.9:1001".parse().unwrap();
+    let a_lan: SocketAddr = "192.168.1.10:5000".parse().unwrap();
+    let b_lan: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+    let mut now = Instant::now();
+    let config = PunchConfig::default();
+    let mut a = Puncher::with_candidates(b_public, None, &[b_lan], &[a_lan], config.clone(), now);
+    let mut b = Puncher::with_candidates(a_public, None, &[a_lan], &[b_lan], config.clone(), now);
+
+    // what goes to a LAN address comes from the sender's LAN address, the rest from its public
+    // one, and arrives last: sent at once, the lower priority address would win
+    let deliver = |from: &mut Puncher, to: &mut Puncher, (lan, public): (SocketAddr, SocketAddr), now: Instant| {
+        let mut transmits: Vec<_> = std::iter::from_fn(|| from.poll_transmit()).collect();
+        transmits.sort_by_key(|t| [a_lan, b_lan].contains(&t.dest));
+        for t in transmits {
+            let source = if [a_lan, b_lan].contains(&t.dest) { lan } else { public };
+            to.handle_datagram(&t.bytes, source, now);
+        }
+    };
+    while !a.is_done() || !b.is_done() {
+        deliver(&mut a, &mut b, (a_lan, a_public), now);
+        deliver(&mut b, &mut a, (b_lan, b_public), now);
+        now += config.burst_interval;
+        a.handle_timeout(now);
+        b.handle_timeout(now);
+    }
+    assert_eq!(a.poll_event(), Some(PunchEvent::Succeeded { remote: b_lan }));
+    assert_eq!(b.poll_event(), Some(PunchEvent::Succeeded { remote: a_lan }));
+}
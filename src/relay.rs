@@ -0,0 +1,47 @@
+/// First channel number. Smaller first bytes are never channel data, `#lnksk@` messages included.
+pub const CHANNEL_MIN: u16 = 0x4000;
+pub const CHANNEL_MAX: u16 = 0x7ffe;
+const HEADER_LEN: usize = 4;
+
+pub fn is_valid_channel(channel: u16) -> bool {
+    (CHANNEL_MIN..=CHANNEL_MAX).contains(&channel)
+}
+
+/// Frame `payload` for `channel`, like TURN's ChannelData (RFC 8656): the channel number and the
+/// payload length, both big endian, then the payload.
+///
+/// Once a client has a relay session (`ToMiddlemanMsg::Allocate`), everything it exchanges with its
+/// peers through the tracker is framed. The peers themselves send and receive plain datagrams.
+/// Panics if the payload does not fit in a datagram.
+pub fn encode(channel: u16, payload: &[u8]) -> Vec<u8> {
+    let len = u16::try_from(payload.len()).expect("payload too large for a datagram");
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&channel.to_be_bytes());
+    bytes.extend_from_slice(&len.to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Channel and payload of a framed datagram, `None` if it is not channel data
+pub fn decode(bytes: &[u8]) -> Option<(u16, &[u8])> {
+    let (header, rest) = bytes.split_at_checked(HEADER_LEN)?;
+    let channel = u16::from_be_bytes([header[0], header[1]]);
+    let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+    if !is_valid_channel(channel) {
+        return None;
+    }
+    // anything after the payload is padding
+    let payload = rest.get(..len)?;
+    Some((channel, payload))
+}
+
+#[test]
+#[cfg(test)]
+fn relay_channel_data_roundtrip() {
+    let framed = encode(0x4001, b"hello");
+    assert_eq!(framed[..4], [0x40, 0x01, 0x00, 0x05]);
+    assert_eq!(decode(&framed), Some((0x4001, &b"hello"[..])));
+    assert_eq!(decode(&framed[..6]), None);
+    assert_eq!(decode(b"#lnksk@ping/id=1"), None);
+    assert_eq!(decode(&encode(0x4001, b"")), Some((0x4001, &b""[..])));
+}
@@ -1,5 +1,5 @@
 use crate::{
-    data::{FromMiddlemanMsg, ToMiddlemanMsg},
+    data::{FromMiddlemanMsg, LanMsg, PeerMsg, ToMiddlemanMsg},
     common::UDPUNCH_ID
 };
 
@@ -23,6 +23,15 @@ impl<'a> std::fmt::Display for KeyValueSerializer<'a> {
     }
 }
 
+/// Addresses in the compact form of `SocketAddrCustom`, `None` when there are none so the key is omitted
+fn addrs_str(addrs: &[std::net::SocketAddr]) -> Option<String> {
+    if addrs.is_empty() {
+        return None;
+    }
+    let addrs = addrs.iter().map(|a| super::deser_utils::SocketAddrCustom(*a)).collect::<Vec<_>>();
+    Some(super::deser_utils::VecCustom(addrs).to_string())
+}
+
 impl FromMiddlemanMsg {
     pub fn serialize(&self) -> Vec<u8> {
         use KeyValueSerializer as KVS;
@@ -35,6 +44,22 @@ impl FromMiddlemanMsg {
                     KVS::new("id", id_str.as_ref())
                 )
             },
+            FromMiddlemanMsg::RenewOk { id } => {
+                let id_str = format!("{}", id);
+                format!(
+                    "{}renewok{}",
+                    UDPUNCH_ID,
+                    KVS::new("id", id_str.as_ref())
+                )
+            },
+            FromMiddlemanMsg::RegisterExpired { id } => {
+                let id_str = format!("{}", id);
+                format!(
+                    "{}regexpired{}",
+                    UDPUNCH_ID,
+                    KVS::new("id", id_str.as_ref())
+                )
+            },
             FromMiddlemanMsg::RegisterErr { msg } => {
                 format!(
                     "{}registererr{}",
@@ -49,27 +74,37 @@ impl FromMiddlemanMsg {
                     KVS::new("msg", msg.as_ref()),
                 )
             },
-            FromMiddlemanMsg::PunchOrder { remote } => {
+            FromMiddlemanMsg::PunchOrder { remote, delta, candidates } => {
                 let remote = remote.to_string();
+                let delta = delta.map(|d| d.to_string());
+                let candidates = addrs_str(candidates);
                 format!(
-                    "{}punchorder{}",
+                    "{}punchorder{}{}{}",
                     UDPUNCH_ID,
-                    KVS::new("remote", &*remote)
+                    KVS::new("remote", &*remote),
+                    KVS::new("delta", delta.as_deref()),
+                    KVS::new("cands", candidates.as_deref()),
                 )
             },
-            FromMiddlemanMsg::PunchLinkseeker { port } => {
+            FromMiddlemanMsg::PunchLinkseeker { port, remote } => {
                 let port = port.to_string();
+                let remote = remote.map(|r| r.to_string());
                 format!(
-                    "{}punchlnksk{}",
+                    "{}punchlnksk{}{}",
                     UDPUNCH_ID,
-                    KVS::new("port", &*port)
+                    KVS::new("port", &*port),
+                    KVS::new("remote", remote.as_deref()),
                 )
             },
-            FromMiddlemanMsg::PunchCheckResult { ok } => {
+            FromMiddlemanMsg::PunchCheckResult { ok, ports, delta } => {
+                let ports = format!("{}", super::deser_utils::VecCustom(ports.clone()));
+                let delta = delta.map(|d| d.to_string());
                 format!(
-                    "{}punchcheckr{}",
+                    "{}punchcheckr{}{}{}",
                     UDPUNCH_ID,
                     KVS::new("ok", if *ok { "1" } else { "0" }),
+                    KVS::new("ports", ports.as_ref()),
+                    KVS::new("delta", delta.as_deref()),
                 )
             },
             FromMiddlemanMsg::ProxyResult { remote, ok } => {
@@ -94,6 +129,14 @@ impl FromMiddlemanMsg {
                     KVS::new("results", results_str.as_ref()),
                 )
             },
+            FromMiddlemanMsg::DomainNameErr { domain, msg } => {
+                format!(
+                    "{}dnrerr{}{}",
+                    UDPUNCH_ID,
+                    KVS::new("domain", domain.as_ref()),
+                    KVS::new("msg", msg.as_ref()),
+                )
+            },
             FromMiddlemanMsg::Pong { id } => {
                 let id_str = format!("{}", id);
                 format!(
@@ -102,6 +145,64 @@ impl FromMiddlemanMsg {
                     KVS::new("id", id_str.as_ref())
                 )
             },
+            FromMiddlemanMsg::NatProbeResult { id, observed, socket_n } => {
+                let id_str = format!("{}", id);
+                let observed = observed.to_string();
+                let socket_n = socket_n.to_string();
+                format!(
+                    "{}natprober{}{}{}",
+                    UDPUNCH_ID,
+                    KVS::new("id", id_str.as_ref()),
+                    KVS::new("observed", &*observed),
+                    KVS::new("socket", &*socket_n),
+                )
+            },
+            FromMiddlemanMsg::YourAddr { observed, socket_n } => {
+                let observed = observed.to_string();
+                let socket_n = socket_n.to_string();
+                format!(
+                    "{}youraddr{}{}",
+                    UDPUNCH_ID,
+                    KVS::new("observed", &*observed),
+                    KVS::new("socket", &*socket_n),
+                )
+            },
+            FromMiddlemanMsg::AllocateOk { port } => {
+                let port = port.to_string();
+                format!(
+                    "{}allocok{}",
+                    UDPUNCH_ID,
+                    KVS::new("port", &*port),
+                )
+            },
+            FromMiddlemanMsg::ChannelBound { channel, peer } => {
+                let channel = channel.to_string();
+                let peer = peer.to_string();
+                format!(
+                    "{}chanbound{}{}",
+                    UDPUNCH_ID,
+                    KVS::new("channel", &*channel),
+                    KVS::new("peer", &*peer),
+                )
+            },
+            FromMiddlemanMsg::ChannelBindErr { channel, msg } => {
+                let channel = channel.to_string();
+                format!(
+                    "{}chanbinderr{}{}",
+                    UDPUNCH_ID,
+                    KVS::new("channel", &*channel),
+                    KVS::new("msg", msg.as_ref()),
+                )
+            },
+            FromMiddlemanMsg::ProxyClosed { remote, msg } => {
+                let remote = remote.to_string();
+                format!(
+                    "{}proxyclosed{}{}",
+                    UDPUNCH_ID,
+                    KVS::new("remote", &*remote),
+                    KVS::new("msg", msg.as_ref()),
+                )
+            },
         };
         s.into_bytes()
     }
@@ -111,19 +212,32 @@ impl ToMiddlemanMsg {
     pub fn serialize(&self) -> Vec<u8> {
         use KeyValueSerializer as KVS;
         let s = match self {
-            ToMiddlemanMsg::Register => {
+            ToMiddlemanMsg::Register { candidates } => {
+                let candidates = addrs_str(candidates);
                 format!(
-                    "{}register",
+                    "{}register{}",
                     UDPUNCH_ID,
+                    KVS::new("cands", candidates.as_deref()),
                 )
             },
-            ToMiddlemanMsg::Request { id, use_proxy } => {
+            ToMiddlemanMsg::Renew { id } => {
                 let id_str = format!("{}", id);
                 format!(
-                    "{}request{}{}",
+                    "{}renew{}",
+                    UDPUNCH_ID,
+                    KVS::new("id", id_str.as_ref()),
+                )
+            },
+            ToMiddlemanMsg::Request { id, use_proxy, candidates, token } => {
+                let id_str = format!("{}", id);
+                let candidates = addrs_str(candidates);
+                format!(
+                    "{}request{}{}{}{}",
                     UDPUNCH_ID,
                     KVS::new("id", id_str.as_ref()),
                     KVS::new("useproxy", if *use_proxy { "1" } else { "0" }),
+                    KVS::new("cands", candidates.as_deref()),
+                    KVS::new("token", token.as_deref()),
                 )
             }
             ToMiddlemanMsg::PunchCheck { id } => {
@@ -134,12 +248,13 @@ impl ToMiddlemanMsg {
                     KVS::new("id", id_str.as_ref()),
                 )
             },
-            ToMiddlemanMsg::ProxyTo { remote } => {
+            ToMiddlemanMsg::ProxyTo { remote, token } => {
                 let remote = remote.to_string();
                 format!(
-                    "{}proxy{}",
+                    "{}proxy{}{}",
                     UDPUNCH_ID,
-                    KVS::new("remote", &*remote)
+                    KVS::new("remote", &*remote),
+                    KVS::new("token", token.as_deref()),
                 )
             },
             ToMiddlemanMsg::Ping { id } => {
@@ -156,8 +271,105 @@ impl ToMiddlemanMsg {
                     UDPUNCH_ID,
                     KVS::new("domain", Some(&**domain)),
                 )
-            }
+            },
+            ToMiddlemanMsg::NatProbe { id, reply_from, reply_via } => {
+                let id_str = format!("{}", id);
+                let reply_str = reply_from.map(|n| n.to_string());
+                let via_str = reply_via.map(|addr| addr.to_string());
+                format!(
+                    "{}natprobe{}{}{}",
+                    UDPUNCH_ID,
+                    KVS::new("id", id_str.as_ref()),
+                    KVS::new("reply", reply_str.as_deref()),
+                    KVS::new("via", via_str.as_deref()),
+                )
+            },
+            ToMiddlemanMsg::NatProbeFor { id, observed } => {
+                let id_str = format!("{}", id);
+                let observed = observed.to_string();
+                format!(
+                    "{}natprobefor{}{}",
+                    UDPUNCH_ID,
+                    KVS::new("id", id_str.as_ref()),
+                    KVS::new("observed", &*observed),
+                )
+            },
+            ToMiddlemanMsg::WhatIsMyAddr => {
+                format!(
+                    "{}whatismyaddr",
+                    UDPUNCH_ID,
+                )
+            },
+            ToMiddlemanMsg::Allocate => {
+                format!(
+                    "{}allocate",
+                    UDPUNCH_ID,
+                )
+            },
+            ToMiddlemanMsg::ChannelBind { channel, peer, token } => {
+                let channel = channel.to_string();
+                let peer = peer.to_string();
+                format!(
+                    "{}chanbind{}{}{}",
+                    UDPUNCH_ID,
+                    KVS::new("channel", &*channel),
+                    KVS::new("peer", &*peer),
+                    KVS::new("token", token.as_deref()),
+                )
+            },
         };
         s.into_bytes()
     }
-}
\ No newline at end of file
+}
+
+impl PeerMsg {
+    pub fn serialize(&self) -> Vec<u8> {
+        use KeyValueSerializer as KVS;
+        let s = match self {
+            PeerMsg::PunchHello { nonce } => {
+                let nonce_str = format!("{}", nonce);
+                format!(
+                    "{}phello{}",
+                    UDPUNCH_ID,
+                    KVS::new("nonce", nonce_str.as_ref()),
+                )
+            },
+            PeerMsg::PunchAck { nonce } => {
+                let nonce_str = format!("{}", nonce);
+                format!(
+                    "{}pack{}",
+                    UDPUNCH_ID,
+                    KVS::new("nonce", nonce_str.as_ref()),
+                )
+            },
+        };
+        s.into_bytes()
+    }
+}
+
+impl LanMsg {
+    pub fn serialize(&self) -> Vec<u8> {
+        use KeyValueSerializer as KVS;
+        let s = match self {
+            LanMsg::Announce { id, port } => {
+                let id_str = format!("{}", id);
+                let port_str = format!("{}", port);
+                format!(
+                    "{}lanannounce{}{}",
+                    UDPUNCH_ID,
+                    KVS::new("id", id_str.as_ref()),
+                    KVS::new("port", port_str.as_ref()),
+                )
+            },
+            LanMsg::Query { id } => {
+                let id_str = format!("{}", id);
+                format!(
+                    "{}lanquery{}",
+                    UDPUNCH_ID,
+                    KVS::new("id", id_str.as_ref()),
+                )
+            },
+        };
+        s.into_bytes()
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration
+};
+
+use crate::client::DEFAULT_LINKSEEKER_PORT;
+
+use super::{
+    destination::{parse_cidrs, DestinationPolicy},
+    limits::{MsgKind, RateLimit}
+};
+
+/// Number of sockets of the default configuration, what clients punch check against
+pub const DEFAULT_SOCKET_COUNT: u16 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerConfig {
+    /// One socket per address, socket `n` being the n-th one.
+    ///
+    /// Every socket is a proxy slot: a host can be reached through the relay by as many peers as
+    /// there are sockets. Clients send their punch checks and NAT probes to the ports following the
+    /// one they talk to, so the first sockets should be on consecutive ports.
+    ///
+    /// The unspecified IPv6 address `::` (the default) binds dual-stack sockets, reachable over
+    /// IPv4 and IPv6.
+    pub bind_addrs: Vec<SocketAddr>,
+    /// Threads, each with its own sockets on `bind_addrs` (`SO_REUSEPORT`, linux spreads the flows
+    /// between them). Registrations are shared, proxies stay on the worker that created them.
+    pub workers: usize,
+    /// See `TrackerCore::set_public_ip`
+    pub public_ip: Option<IpAddr>,
+    /// How long a registered ID lives without a renew
+    pub register_expire: Duration,
+    /// How long a proxy or a relay session lives without traffic
+    pub proxy_expire: Duration,
+    pub punch_check_expire: Duration,
+    /// Packets of a new proxy are dropped for that long. If the host's router has a DMZ and we
+    /// send to the host before it sends to us, the DMZ catches our packets instead of the host.
+    pub first_packet_delay: Duration,
+    /// Per source (an IPv4 address or an IPv6 /64) and per kind of message, see `rate_limit`.
+    /// Messages over the limit are dropped without an answer.
+    pub rate_limits: [RateLimit; MsgKind::COUNT],
+    /// Ids registered at once by a source, 0 for no limit
+    pub max_registrations_per_ip: usize,
+    /// Proxies and relayed peers a source asks for at once, 0 for no limit
+    pub max_proxies_per_ip: usize,
+    /// A source dropping that many messages within `limits::BAN_WINDOW` is ignored for
+    /// `ban_duration`, 0 to never ban
+    pub ban_after: u32,
+    pub ban_duration: Duration,
+    /// Bytes per second a proxy or relayed peer forwards, both ways together, 0 for no limit.
+    /// Datagrams over it are dropped.
+    pub session_bandwidth: u64,
+    /// Bytes a proxy or relayed peer forwards before it is closed, both ends being told with a
+    /// `ProxyClosed`, 0 for no limit
+    pub session_quota: u64,
+    /// Like `session_bandwidth`, for all the sessions a source asked for together
+    pub ip_bandwidth: u64,
+    /// Bytes forwarded for the sessions of a source within `quota_period`, the next session going
+    /// over it is closed. 0 for no limit.
+    pub ip_quota: u64,
+    pub quota_period: Duration,
+    /// Proxies and relayed peers are only given to clients with a token made with this secret, see
+    /// `auth::make_token`. Without it nobody gets them, unless `open_proxy`.
+    pub proxy_secret: Option<String>,
+    /// Gives proxies and relayed peers to anyone, without a `proxy_secret`
+    pub open_proxy: bool,
+    /// Threads resolving the domains of `DomainNameReq`, shared by the workers
+    pub dns_threads: usize,
+    /// How long a resolved domain is answered from the cache
+    pub dns_cache: Duration,
+    /// Same for a domain that could not be resolved
+    pub dns_negative_cache: Duration,
+    /// Addresses raw proxies and channel binds may send to, and domain name requests may resolve
+    /// to. Private ranges are denied by default, the tracker's own addresses always.
+    pub destinations: DestinationPolicy,
+    /// Trackers that clients may ask to answer their NAT probes (`NatProbe` with `reply_via`), and
+    /// the only ones we answer a `NatProbeFor` for. Without them, nobody can use us as a reflector.
+    pub peer_trackers: Vec<SocketAddr>,
+    /// Where the `linkseeker` binary serves `SharedState::metrics` over HTTP, none by default.
+    /// There is no access control, keep it on localhost.
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl TrackerConfig {
+    /// `count` sockets on consecutive ports from `start_port`. Panics if they go past port 65535.
+    pub fn with_port_range(ip: IpAddr, start_port: u16, count: u16) -> Self {
+        Self {
+            bind_addrs: port_range(ip, start_port, count).unwrap(),
+            workers: 1,
+            public_ip: None,
+            register_expire: Duration::from_secs(60),
+            proxy_expire: Duration::from_secs(60),
+            punch_check_expire: Duration::from_secs(60),
+            first_packet_delay: Duration::from_millis(250),
+            rate_limits: MsgKind::ALL.map(MsgKind::default_limit),
+            max_registrations_per_ip: 64,
+            max_proxies_per_ip: 64,
+            ban_after: 200,
+            ban_duration: Duration::from_secs(300),
+            session_bandwidth: 0,
+            session_quota: 0,
+            ip_bandwidth: 0,
+            ip_quota: 0,
+            quota_period: Duration::from_secs(24 * 3600),
+            proxy_secret: None,
+            open_proxy: false,
+            dns_threads: 4,
+            dns_cache: Duration::from_secs(60),
+            dns_negative_cache: Duration::from_secs(10),
+            destinations: DestinationPolicy::default(),
+            peer_trackers: Vec::new(),
+            metrics_addr: None,
+        }
+    }
+
+    pub fn rate_limit(&self, kind: MsgKind) -> RateLimit {
+        self.rate_limits[kind as usize]
+    }
+
+    pub fn socket_count(&self) -> usize {
+        self.bind_addrs.len()
+    }
+
+    /// Sets a setting by name, as written in config files and on the command line (see `CONFIG_KEYS`).
+    /// Values are checked one by one here, and together by `validate`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let first = self.bind_addrs.first().copied()
+            .unwrap_or(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), DEFAULT_LINKSEEKER_PORT));
+        let count = u16::try_from(self.bind_addrs.len()).unwrap_or(u16::MAX);
+        let error = |e: &dyn std::fmt::Display| format!("invalid {} {:?}: {}", key, value, e);
+        match key {
+            "bind" => {
+                self.bind_addrs = value.split(',')
+                    .map(|addr| addr.trim().parse::<SocketAddr>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| error(&e))?;
+            },
+            "ip" => {
+                let ip = value.parse::<IpAddr>().map_err(|e| error(&e))?;
+                self.bind_addrs.iter_mut().for_each(|addr| addr.set_ip(ip));
+            },
+            "port" => self.bind_addrs = port_range(first.ip(), value.parse().map_err(|e| error(&e))?, count).map_err(|e| error(&e))?,
+            "sockets" => self.bind_addrs = port_range(first.ip(), first.port(), value.parse().map_err(|e| error(&e))?).map_err(|e| error(&e))?,
+            "workers" => self.workers = value.parse().map_err(|e| error(&e))?,
+            "public_ip" => self.public_ip = Some(value.parse().map_err(|e| error(&e))?),
+            "register_expire_secs" => self.register_expire = Duration::from_secs(value.parse().map_err(|e| error(&e))?),
+            "proxy_expire_secs" => self.proxy_expire = Duration::from_secs(value.parse().map_err(|e| error(&e))?),
+            "punch_check_expire_secs" => self.punch_check_expire = Duration::from_secs(value.parse().map_err(|e| error(&e))?),
+            "first_packet_delay_ms" => self.first_packet_delay = Duration::from_millis(value.parse().map_err(|e| error(&e))?),
+            "max_registrations_per_ip" => self.max_registrations_per_ip = value.parse().map_err(|e| error(&e))?,
+            "max_proxies_per_ip" => self.max_proxies_per_ip = value.parse().map_err(|e| error(&e))?,
+            "ban_after" => self.ban_after = value.parse().map_err(|e| error(&e))?,
+            "ban_secs" => self.ban_duration = Duration::from_secs(value.parse().map_err(|e| error(&e))?),
+            "session_bandwidth" => self.session_bandwidth = value.parse().map_err(|e| error(&e))?,
+            "session_quota_bytes" => self.session_quota = value.parse().map_err(|e| error(&e))?,
+            "ip_bandwidth" => self.ip_bandwidth = value.parse().map_err(|e| error(&e))?,
+            "ip_quota_bytes" => self.ip_quota = value.parse().map_err(|e| error(&e))?,
+            "proxy_secret" => self.proxy_secret = Some(value.to_string()).filter(|s| !s.is_empty()),
+            "open_proxy" => self.open_proxy = value.parse().map_err(|e| error(&e))?,
+            "dns_threads" => self.dns_threads = value.parse().map_err(|e| error(&e))?,
+            "dns_cache_secs" => self.dns_cache = Duration::from_secs(value.parse().map_err(|e| error(&e))?),
+            "dns_negative_cache_secs" => self.dns_negative_cache = Duration::from_secs(value.parse().map_err(|e| error(&e))?),
+            "allow_destinations" => self.destinations.allow = parse_cidrs(value).map_err(|e| error(&e))?,
+            "deny_destinations" => self.destinations.deny = parse_cidrs(value).map_err(|e| error(&e))?,
+            "metrics_addr" => self.metrics_addr = match value.trim() {
+                "" => None,
+                addr => Some(addr.parse().map_err(|e| error(&e))?),
+            },
+            "quota_period_secs" => self.quota_period = Duration::from_secs(value.parse().map_err(|e| error(&e))?),
+            _ if key.starts_with("rate_limit_") => {
+                let kind = MsgKind::from_name(&key["rate_limit_".len()..]).ok_or(format!("unknown setting {:?}", key))?;
+                self.rate_limits[kind as usize] = RateLimit::parse(value).map_err(|e| error(&e))?;
+            },
+            "peer_trackers" => {
+                self.peer_trackers = value.split(',')
+                    .map(str::trim)
+                    .filter(|addr| !addr.is_empty())
+                    .map(|addr| addr.parse::<SocketAddr>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| error(&e))?;
+            },
+            _ => return Err(format!("unknown setting {:?}", key)),
+        }
+        Ok(())
+    }
+
+    /// Sets every setting of one source, a config file or the command line, with `set`. `bind`
+    /// gives every address, it cannot come with `ip`, `port` or `sockets`, which build them.
+    pub fn set_all(&mut self, settings: &[(String, String)]) -> Result<(), String> {
+        let has = |key: &str| settings.iter().any(|(k, _)| k == key);
+        if let Some(key) = ["ip", "port", "sockets"].into_iter().find(|key| has("bind") && has(key)) {
+            return Err(format!("bind and {} cannot be set together, bind gives every address", key));
+        }
+        settings.iter().try_for_each(|(key, value)| self.set(key, value))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bind_addrs.is_empty() {
+            return Err("at least one bind address is needed".to_string());
+        }
+        if let Some(addr) = self.bind_addrs.iter().enumerate().find_map(|(i, a)| self.bind_addrs[..i].contains(a).then_some(a)) {
+            return Err(format!("{} is bound twice", addr));
+        }
+        if self.bind_addrs.len() > u8::MAX as usize + 1 {
+            // socket numbers are sent as one byte
+            return Err(format!("{} sockets, at most 256 are supported", self.bind_addrs.len()));
+        }
+        if self.workers == 0 {
+            return Err("at least one worker is needed".to_string());
+        }
+        if cfg!(not(unix)) && self.workers > 1 {
+            return Err("several workers need SO_REUSEPORT, only available on unix".to_string());
+        }
+        for (key, value) in [
+            ("register_expire_secs", self.register_expire),
+            ("proxy_expire_secs", self.proxy_expire),
+            ("punch_check_expire_secs", self.punch_check_expire),
+        ] {
+            if value.is_zero() {
+                return Err(format!("{} must be more than 0", key));
+            }
+        }
+        if self.first_packet_delay >= self.proxy_expire {
+            return Err("first_packet_delay_ms must be shorter than proxy_expire_secs".to_string());
+        }
+        if self.ban_after > 0 && self.ban_duration.is_zero() {
+            return Err("ban_secs must be more than 0, or ban_after 0 to never ban".to_string());
+        }
+        if self.open_proxy && self.proxy_secret.is_some() {
+            return Err("open_proxy and proxy_secret cannot be set together".to_string());
+        }
+        if self.dns_threads == 0 {
+            return Err("at least one dns thread is needed".to_string());
+        }
+        if self.ip_quota > 0 && self.quota_period.is_zero() {
+            return Err("quota_period_secs must be more than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self::with_port_range(IpAddr::V6(Ipv6Addr::UNSPECIFIED), DEFAULT_LINKSEEKER_PORT, DEFAULT_SOCKET_COUNT)
+    }
+}
+
+/// Settings `TrackerConfig::set` knows, with their description
+pub const CONFIG_KEYS: &[(&str, &str)] = &[
+    ("bind", "addresses of the sockets, comma separated, instead of ip, port and sockets"),
+    ("ip", "ip of every socket, :: for dual-stack sockets"),
+    ("port", "first port, the sockets are on consecutive ports"),
+    ("sockets", "number of sockets, on consecutive ports"),
+    ("workers", "threads sharing the sockets' ports"),
+    ("public_ip", "public ip of the tracker, carried by the ids it gives"),
+    ("register_expire_secs", "lifetime of a registered id without renew"),
+    ("proxy_expire_secs", "lifetime of an idle proxy"),
+    ("punch_check_expire_secs", "lifetime of a punch check"),
+    ("first_packet_delay_ms", "how long the first packets of a proxy are dropped"),
+    ("rate_limit_register", "per source: <per minute>/<burst>, or off"),
+    ("rate_limit_renew", "same for renews"),
+    ("rate_limit_request", "same for requests"),
+    ("rate_limit_punch_check", "same for punch checks"),
+    ("rate_limit_proxy", "same for raw proxy requests"),
+    ("rate_limit_domain_name", "same for domain name requests"),
+    ("rate_limit_relay", "same for relay sessions and channel binds"),
+    ("rate_limit_probe", "same for pings and address probes"),
+    ("max_registrations_per_ip", "ids registered at once by a source, 0 for no limit"),
+    ("max_proxies_per_ip", "proxies and relayed peers of a source, 0 for no limit"),
+    ("ban_after", "messages over the limits in 10s that get a source banned, 0 to never ban"),
+    ("ban_secs", "how long a source stays banned"),
+    ("session_bandwidth", "bytes per second a proxy forwards, 0 for no limit"),
+    ("session_quota_bytes", "bytes a proxy forwards before it is closed, 0 for no limit"),
+    ("ip_bandwidth", "bytes per second forwarded for the proxies of a source, 0 for no limit"),
+    ("ip_quota_bytes", "bytes forwarded for the proxies of a source per quota period, 0 for no limit"),
+    ("quota_period_secs", "period of ip_quota_bytes"),
+    ("proxy_secret", "secret of the tokens proxy access needs, no proxy access if empty"),
+    ("open_proxy", "true to give proxy access to anyone, without proxy_secret"),
+    ("dns_threads", "threads resolving domain name requests"),
+    ("dns_cache_secs", "how long resolved domains are cached"),
+    ("dns_negative_cache_secs", "how long failed resolutions are cached"),
+    ("allow_destinations", "address blocks clients may proxy to, comma separated, private ones included"),
+    ("deny_destinations", "address blocks clients may not proxy to, the most specific block wins"),
+    ("peer_trackers", "trackers answering nat probes for ours and the other way around, comma separated"),
+    ("metrics_addr", "address to serve prometheus metrics on over http, like 127.0.0.1:9161"),
+];
+
+fn port_range(ip: IpAddr, start_port: u16, count: u16) -> Result<Vec<SocketAddr>, String> {
+    (0..count)
+        .map(|i| start_port.checked_add(i).map(|port| SocketAddr::new(ip, port)))
+        .collect::<Option<_>>()
+        .ok_or(format!("{} sockets from port {} go past port 65535", count, start_port))
+}
+
+/// Settings of a TOML config file, to give to `TrackerConfig::set`. Arrays are joined with commas,
+/// tables are not allowed.
+pub fn settings_from_toml(text: &str) -> Result<Vec<(String, String)>, String> {
+    let table = text.parse::<toml::Table>().map_err(|e| e.to_string())?;
+    table.into_iter().map(|(key, value)| {
+        let value = match value {
+            toml::Value::String(s) => s,
+            toml::Value::Integer(i) => i.to_string(),
+            toml::Value::Boolean(b) => b.to_string(),
+            toml::Value::Array(values) => values.into_iter()
+                .map(|v| v.as_str().map(str::to_string).ok_or(format!("{} must be an array of strings", key)))
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            _ => return Err(format!("{} must be a string, an integer, a boolean or an array of strings", key)),
+        };
+        Ok((key, value))
+    }).collect()
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_config_from_settings() {
+    let text = "
+        port = 5000
+        sockets = 2
+        workers = 4
+        proxy_expire_secs = 30
+        allow_destinations = [\"10.0.0.0/8\", \"fd00::1\"]
+        log_level = \"info\"
+    ";
+    let mut config = TrackerConfig::default();
+    for (key, value) in settings_from_toml(text).unwrap() {
+        if key != "log_level" {
+            config.set(&key, &value).unwrap();
+        }
+    }
+    config.set("ip", "127.0.0.1").unwrap();
+    assert_eq!(config.bind_addrs, ["127.0.0.1:5000".parse().unwrap(), "127.0.0.1:5001".parse().unwrap()]);
+    assert_eq!((config.workers, config.proxy_expire), (4, Duration::from_secs(30)));
+    assert_eq!(config.validate(), Ok(()));
+    assert!(config.destinations.allows("10.1.2.3".parse().unwrap()));
+    assert!(config.set("deny_destinations", "10.0.0.0/8,nope").is_err());
+
+    assert_eq!(config.set("proxy_expire_secs", "soon"), Err("invalid proxy_expire_secs \"soon\": invalid digit found in string".to_string()));
+    assert_eq!(config.set("proxy_expire", "30"), Err("unknown setting \"proxy_expire\"".to_string()));
+    config.set("rate_limit_register", "10/2").unwrap();
+    config.set("rate_limit_probe", "off").unwrap();
+    assert_eq!(config.rate_limit(MsgKind::Register), RateLimit { per_minute: 10, burst: 2 });
+    assert!(config.rate_limit(MsgKind::Probe).is_unlimited());
+    assert!(config.set("rate_limit_register", "10/0").is_err());
+    assert!(config.set("rate_limit_everything", "10/2").is_err());
+    config.set("open_proxy", "true").unwrap();
+    config.set("proxy_secret", "hunter2").unwrap();
+    assert!(config.validate().is_err());
+    config.set("proxy_secret", "").unwrap();
+    assert!(config.set("open_proxy", "yes").is_err());
+    config.set("first_packet_delay_ms", "30000").unwrap();
+    assert!(config.validate().is_err());
+    assert!(settings_from_toml("[table]\nport = 1").is_err());
+
+    // an explicit bind list does not go with what builds one, whatever the order
+    let bind = settings_from_toml("sockets = 2\nbind = [\"127.0.0.1:5000\", \"[::1]:6000\"]").unwrap();
+    let mut config = TrackerConfig::default();
+    assert_eq!(config.set_all(&bind), Err("bind and sockets cannot be set together, bind gives every address".to_string()));
+    config.set_all(&[("bind".to_string(), "127.0.0.1:5000, [::1]:6000".to_string())]).unwrap();
+    assert_eq!(config.bind_addrs, ["127.0.0.1:5000".parse().unwrap(), "[::1]:6000".parse().unwrap()]);
+    // a later source overrides it
+    config.set_all(&[("port".to_string(), "7000".to_string())]).unwrap();
+    assert_eq!(config.bind_addrs, ["127.0.0.1:7000".parse().unwrap(), "127.0.0.1:7001".parse().unwrap()]);
+    // every socket gets a port, or none does
+    assert_eq!(config.set("port", "65535"), Err("invalid port \"65535\": 2 sockets from port 65535 go past port 65535".to_string()));
+    assert!(config.set("sockets", "64600").is_err());
+    assert_eq!(config.bind_addrs.len(), 2);
+}
@@ -0,0 +1,1632 @@
+use crate::{
+    auth::{self, Scope},
+    candidate::{canonical, punch_addrs, MAX_CANDIDATES},
+    client::{compute_linkseeker_key, make_link_id, Transmit},
+    code::LinkCode,
+    common::UDPUNCH_ID_BYTES,
+    data::{FromMiddlemanMsg, ToMiddlemanMsg},
+    relay
+};
+
+use super::{
+    config::TrackerConfig,
+    limits::{source_of, Bandwidth, Limiter, MsgKind, Quota},
+    metrics::{Gauges, Metrics}
+};
+
+use rand::Rng;
+
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+
+/// Resolved domains kept at most, the expired ones are dropped first
+const DNS_CACHE_MAX: usize = 4096;
+/// Requesters waiting for the same domain at most, the next ones get no answer
+const DNS_MAX_WAITERS: usize = 64;
+/// A lookup that has not come back by then is started again by the next request
+const DNS_LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Addresses answered at most, to keep answers small
+const DNS_MAX_RESULTS: usize = 16;
+
+pub struct RdvRemote {
+    pub socket_addr: SocketAddr,
+    /// our socket it last talked to
+    pub socket_n: usize,
+    pub expiring: Instant,
+    /// other addresses the host gave, forwarded to whoever requests it
+    pub candidates: Vec<SocketAddr>,
+}
+
+impl RdvRemote {
+    fn is_expired(&self, now: Instant) -> bool {
+        now >= self.expiring
+    }
+}
+
+pub struct ProxyData {
+    /// socket that asked for a proxy
+    pub incoming: SocketAddr,
+    pub in_socket_n: usize,
+    /// socket we need to proxy to
+    pub outgoing: SocketAddr,
+    pub out_socket_n: usize,
+    pub in_packets: u64,
+    pub out_packets: u64,
+    /// bytes forwarded from `incoming`, as it sent them
+    pub in_bytes: u64,
+    /// bytes forwarded from `outgoing`, as it sent them
+    pub out_bytes: u64,
+    pub last_active: Instant,
+    pub first_active: Instant,
+    /// Set for relay sessions: `incoming` has an allocation and frames what it exchanges with
+    /// `outgoing` for this channel, see `relay::encode`
+    pub channel: Option<u16>,
+    /// source that asked for it, see `TrackerConfig::max_proxies_per_ip`
+    pub requested_by: IpAddr,
+    /// Set by `ToMiddlemanMsg::ProxyTo`: `incoming` is not a linkseeker client
+    pub raw: bool,
+    bandwidth: Bandwidth,
+}
+
+impl ProxyData {
+    fn new(incoming: (SocketAddr, usize), outgoing: (SocketAddr, usize), requested_by: IpAddr, now: Instant) -> Self {
+        Self {
+            incoming: incoming.0,
+            in_socket_n: incoming.1,
+            outgoing: outgoing.0,
+            out_socket_n: outgoing.1,
+            requested_by,
+            raw: false,
+            bandwidth: Bandwidth::new(now),
+            in_packets: 0,
+            out_packets: 0,
+            in_bytes: 0,
+            out_bytes: 0,
+            last_active: now,
+            first_active: now,
+            channel: None,
+        }
+    }
+
+    fn is_expired(&self, now: Instant, expire_time: Duration) -> bool {
+        now >= self.last_active + expire_time
+    }
+
+    /// Whether `len` more bytes can be forwarded, see `TrackerConfig::session_quota`. Nothing is
+    /// counted, the bytes only count once the source's limits let them through too.
+    fn quota(&mut self, len: usize, config: &TrackerConfig, now: Instant) -> Quota {
+        if config.session_quota > 0 && self.in_bytes + self.out_bytes + len as u64 > config.session_quota {
+            return Quota::Exceeded;
+        }
+        match self.bandwidth.allows(len, config.session_bandwidth, now) {
+            true => Quota::Within,
+            false => Quota::Throttled,
+        }
+    }
+}
+
+/// Relay session of a client, see `ToMiddlemanMsg::Allocate`
+pub struct Allocation {
+    pub expire: Instant,
+    /// where to start looking for a free channel
+    next_channel: u16,
+}
+
+pub struct PunchCheck {
+    pub first_received: (SocketAddr, usize),
+    /// address seen by each of our sockets
+    pub observed: Vec<Option<SocketAddr>>,
+    pub id: u32,
+    pub expire: Instant,
+}
+
+impl PunchCheck {
+    pub fn new(id: u32, from: (SocketAddr, usize), socket_count: usize, expire: Instant) -> Self {
+        let mut observed = vec![None; socket_count];
+        observed[from.1] = Some(from.0);
+        Self {
+            id,
+            expire,
+            first_received: from,
+            observed,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now >= self.expire
+    }
+
+    /// addresses are the same from our PoV = udp punching is possible
+    /// addresses are different from our PoV = udp punching is not possible
+    fn is_ok(&self) -> bool {
+        self.observed.iter().flatten().all(|addr| *addr == self.first_received.0)
+    }
+
+    fn ports(&self) -> Vec<u16> {
+        self.observed.iter().flatten().map(|addr| addr.port()).collect()
+    }
+
+    /// Port allocation delta of the remote's NAT, only if it is the same between every socket.
+    ///
+    /// The remote is expected to send its checks to our sockets in order.
+    fn delta(&self) -> Option<i32> {
+        let ports = self.ports();
+        let mut diffs = ports.windows(2).map(|w| w[1] as i32 - w[0] as i32);
+        let first = diffs.next()?;
+        diffs.all(|d| d == first).then_some(first)
+    }
+}
+
+/// Registrations and punch checks. Every worker can receive a host's messages, and a punch check
+/// reaches our sockets from as many flows as we have sockets.
+#[derive(Default)]
+struct Registry {
+    rdv_hosts: HashMap<u32, RdvRemote>,
+    punch_checks: Vec<PunchCheck>,
+    /// number of `rdv_hosts` of each source, see `limits::source_of`
+    per_source: HashMap<IpAddr, usize>,
+}
+
+impl Registry {
+    fn insert_host(&mut self, key: Option<u8>, remote: RdvRemote) -> u32 {
+        'gen_loop: loop {
+            let random_id: u32 = match key {
+                Some(key) => make_link_id(key, rand::rng().random()),
+                None => rand::rng().random(),
+            };
+            let Entry::Vacant(v) = self.rdv_hosts.entry(random_id) else {
+                continue 'gen_loop;
+            };
+            *self.per_source.entry(source_of(remote.socket_addr.ip())).or_default() += 1;
+            v.insert(remote);
+            return random_id
+        }
+    }
+
+    /// Port allocation delta from the latest punch check of this remote, if any
+    fn port_delta_of(&self, remote: SocketAddr) -> Option<i32> {
+        self.punch_checks.iter().rev()
+            .find(|c| c.observed.iter().flatten().any(|addr| *addr == remote))
+            .and_then(|c| c.delta())
+    }
+}
+
+/// Worker each flow of a proxy or a relay session is pinned to, by (our socket, remote)
+#[derive(Default)]
+struct Owners {
+    proxies: HashMap<(usize, SocketAddr), usize>,
+    allocations: HashMap<(usize, SocketAddr), usize>,
+}
+
+/// What the workers of a tracker share, see `TrackerCore::worker`
+#[derive(Default, Clone)]
+pub struct SharedState {
+    registry: Arc<Mutex<Registry>>,
+    owners: Arc<RwLock<Owners>>,
+    limiter: Arc<Limiter>,
+    metrics: Arc<Metrics>,
+}
+
+impl SharedState {
+    /// Counters and gauges of every worker at `now`, in the Prometheus text format
+    pub fn metrics(&self, now: Instant) -> String {
+        let (registrations, punch_checks) = {
+            let registry = self.registry.lock().unwrap();
+            (registry.rdv_hosts.len(), registry.punch_checks.len())
+        };
+        let (banned_sources, bans) = self.limiter.ban_counts(now);
+        self.metrics.render(&Gauges { registrations, punch_checks, banned_sources, bans })
+    }
+}
+
+/// A datagram received by a worker that another one has to process, see `TrackerCore::poll_handoff`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handoff {
+    /// worker to give it to
+    pub worker: usize,
+    pub socket_n: usize,
+    pub from: SocketAddr,
+    pub bytes: Vec<u8>,
+}
+
+/// Sans-IO protocol logic of the tracker: registrations, punch orders, punch checks and proxying.
+///
+/// Feed it every datagram received along with the index of the socket that received it, call
+/// `handle_timeout` when `poll_timeout` expires, and send what `poll_transmit` returns from the
+/// socket it names. `LinkSeekTracker` does exactly that with its own sockets.
+///
+/// Several cores can share one set of ports, each being a worker with its own sockets (see
+/// `TrackerCore::worker`). Proxies and relay sessions are pinned to the worker that created them:
+/// datagrams of their flows received by another worker come out of `poll_handoff`, for the owner's
+/// `handle_handoff`.
+///
+/// Domains of `DomainNameReq` come out of `poll_lookup`, to resolve away from the loop driving the
+/// core and give back to `handle_lookup`.
+pub struct TrackerCore {
+    pub (self) config: TrackerConfig,
+    pub (self) now: Instant,
+    /// key of our public IP, embedded in the ids we give
+    pub (self) key: Option<u8>,
+    /// our index among the workers
+    worker: usize,
+    shared: SharedState,
+    pub proxy_list: Vec<ProxyData>,
+    /// relay sessions, by (our socket, client)
+    pub allocations: HashMap<(usize, SocketAddr), Allocation>,
+    /// index in `proxy_list` of the proxy of each (our socket, remote) pair
+    proxy_routes: HashMap<(usize, SocketAddr), usize>,
+    /// index in `proxy_list` of each channel of relay sessions, by (our socket, client, channel)
+    channel_routes: HashMap<(usize, SocketAddr, u16), usize>,
+    transmits: VecDeque<(usize, Transmit)>,
+    handoffs: VecDeque<Handoff>,
+    /// wall clock time of an instant, to check the expiry of proxy tokens
+    started: (Instant, SystemTime),
+    /// resolved domains, and until when they are answered from here
+    dns_cache: HashMap<String, (Result<Vec<SocketAddr>, String>, Instant)>,
+    /// domains being resolved, since when, and who asked for them
+    dns_pending: HashMap<String, (Instant, Vec<(usize, SocketAddr)>)>,
+    lookups: VecDeque<String>,
+    /// earliest expiry, or earlier when it was pushed back since: set as things are added, and
+    /// only looked for again in `handle_timeout`
+    next_deadline: Option<Instant>,
+}
+
+impl TrackerCore {
+    /// The ports of `config` must be the actual ports of the sockets, they are sent to clients
+    pub fn new(config: TrackerConfig, now: Instant) -> Self {
+        Self::worker(config, SharedState::default(), 0, now)
+    }
+
+    /// Worker number `worker` of a tracker, its sockets bound to the same addresses as the other
+    /// workers'. `shared` is the same for all of them.
+    pub fn worker(config: TrackerConfig, shared: SharedState, worker: usize, now: Instant) -> Self {
+        let public_ip = config.public_ip;
+        let mut core = Self {
+            config,
+            allocations: HashMap::new(),
+            proxy_routes: HashMap::new(),
+            channel_routes: HashMap::new(),
+            worker,
+            shared,
+            proxy_list: Vec::new(),
+            now,
+            key: None,
+            transmits: VecDeque::new(),
+            handoffs: VecDeque::new(),
+            started: (now, SystemTime::now()),
+            dns_cache: HashMap::new(),
+            dns_pending: HashMap::new(),
+            lookups: VecDeque::new(),
+            next_deadline: None,
+        };
+        if let Some(ip) = public_ip {
+            core.set_public_ip(ip);
+        }
+        core
+    }
+
+    /// Set the public IP clients reach us with, so the ids we give tell which tracker issued them.
+    ///
+    /// Without it ids are fully random, and requesters have to guess which tracker to ask.
+    pub fn set_public_ip(&mut self, ip: IpAddr) {
+        let key = compute_linkseeker_key(ip);
+        log::info!("public ip is {}, ids will carry key {:02x}", ip, key);
+        self.key = Some(key);
+    }
+
+    /// Expires registrations, punch checks and proxies
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.now = now;
+        let mut expired = Vec::new();
+        let mut registry = self.shared.registry.lock().unwrap();
+        let Registry { rdv_hosts, punch_checks, per_source } = &mut *registry;
+        rdv_hosts.retain(|k, remote| {
+            let r = !remote.is_expired(now);
+            if !r {
+                log::info!("registered id={} for {} has expired", LinkCode(*k), remote.socket_addr);
+                expired.push((*k, remote.socket_n, remote.socket_addr));
+                let source = source_of(remote.socket_addr.ip());
+                if let Some(count) = per_source.get_mut(&source) {
+                    *count -= 1;
+                    if *count == 0 {
+                        per_source.remove(&source);
+                    }
+                }
+            }
+            r
+        });
+        punch_checks.retain(|check| !check.is_expired(now));
+        drop(registry);
+        self.dns_cache.retain(|_, (_, expire)| now < *expire);
+        self.dns_pending.retain(|_, (started, _)| now < *started + DNS_LOOKUP_TIMEOUT);
+        for (id, socket_n, socket_addr) in expired {
+            self.send_msg(FromMiddlemanMsg::RegisterExpired { id }, socket_n, socket_addr);
+        }
+        let (proxies, allocations) = (self.proxy_list.len(), self.allocations.len());
+        self.proxy_list.retain(|proxy_data| {
+            let r = !proxy_data.is_expired(now, self.config.proxy_expire);
+            if !r {
+                log::info!("proxying S={} <-> R={} has expired: {}p/{}B from S, {}p/{}B from R",
+                    proxy_data.incoming, proxy_data.outgoing,
+                    proxy_data.out_packets, proxy_data.out_bytes, proxy_data.in_packets, proxy_data.in_bytes
+                );
+                self.shared.limiter.remove_proxy(proxy_data.requested_by);
+                Metrics::dec(&self.shared.metrics.proxy_sessions);
+            }
+            r
+        });
+        self.allocations.retain(|(_, client), allocation| {
+            let r = now < allocation.expire;
+            if !r {
+                log::info!("relay session of {} has expired", client);
+                Metrics::dec(&self.shared.metrics.relay_sessions);
+            }
+            r
+        });
+        // the routes only change with the sessions
+        if (proxies, allocations) != (self.proxy_list.len(), self.allocations.len()) {
+            self.reindex();
+        }
+        self.next_deadline = self.find_deadline();
+    }
+
+    /// Rebuilds the routes to `proxy_list` and this worker's owners, after proxies were removed
+    fn reindex(&mut self) {
+        self.proxy_routes.clear();
+        self.channel_routes.clear();
+        let shared = self.shared.owners.clone();
+        let mut owners = shared.write().unwrap();
+        let worker = self.worker;
+        owners.proxies.retain(|_, w| *w != worker);
+        owners.allocations.retain(|k, w| *w != worker || self.allocations.contains_key(k));
+        for i in 0..self.proxy_list.len() {
+            self.index_proxy(i, &mut owners);
+        }
+    }
+
+    fn index_proxy(&mut self, i: usize, owners: &mut Owners) {
+        let proxy = &self.proxy_list[i];
+        let mut route = |key: (usize, SocketAddr)| {
+            self.proxy_routes.entry(key).or_insert(i);
+            owners.proxies.entry(key).or_insert(self.worker);
+        };
+        match proxy.channel {
+            Some(channel) => { self.channel_routes.entry((proxy.in_socket_n, proxy.incoming, channel)).or_insert(i); },
+            None => route((proxy.in_socket_n, proxy.incoming)),
+        }
+        route((proxy.out_socket_n, proxy.outgoing));
+    }
+
+    /// Stops forwarding for proxy `i`, telling its ends why
+    fn close_proxy(&mut self, i: usize, msg: &str) {
+        let proxy = self.proxy_list.swap_remove(i);
+        log::info!("closing proxy S={} <-> R={}: {}, {}p/{}B from S, {}p/{}B from R", proxy.incoming, proxy.outgoing, msg,
+            proxy.out_packets, proxy.out_bytes, proxy.in_packets, proxy.in_bytes
+        );
+        self.shared.limiter.remove_proxy(proxy.requested_by);
+        Metrics::dec(&self.shared.metrics.proxy_sessions);
+        if !proxy.raw {
+            self.send_msg(FromMiddlemanMsg::ProxyClosed { remote: proxy.outgoing, msg: msg.to_string() }, proxy.in_socket_n, proxy.incoming);
+        }
+        self.send_msg(FromMiddlemanMsg::ProxyClosed { remote: proxy.incoming, msg: msg.to_string() }, proxy.out_socket_n, proxy.outgoing);
+        self.reindex();
+    }
+
+    fn add_proxy(&mut self, proxy: ProxyData) {
+        self.shared.limiter.add_proxy(proxy.requested_by, &self.config, self.now);
+        Metrics::inc(&self.shared.metrics.proxy_sessions);
+        Metrics::inc(&self.shared.metrics.proxies_opened);
+        self.schedule(proxy.last_active + self.config.proxy_expire);
+        self.proxy_list.push(proxy);
+        let shared = self.shared.owners.clone();
+        self.index_proxy(self.proxy_list.len() - 1, &mut shared.write().unwrap());
+    }
+
+    /// Worker the flow of `remote` on our socket `socket_n` is pinned to, if any
+    fn owner_of(&self, socket_n: usize, remote: SocketAddr) -> Option<usize> {
+        let owners = self.shared.owners.read().unwrap();
+        owners.proxies.get(&(socket_n, remote)).or_else(|| owners.allocations.get(&(socket_n, remote))).copied()
+    }
+
+    fn hand_off(&mut self, worker: usize, socket_n: usize, from: SocketAddr, bytes: Vec<u8>) {
+        self.handoffs.push_back(Handoff { worker, socket_n, from, bytes });
+    }
+
+    /// Whether `token` gives access to the proxy for `scope`, see `TrackerConfig::proxy_secret`
+    fn check_token(&self, token: Option<&str>, scope: Scope) -> Result<(), &'static str> {
+        let Some(secret) = &self.config.proxy_secret else {
+            return match self.config.open_proxy {
+                true => Ok(()),
+                false => Err("proxy access needs a token"),
+            };
+        };
+        let token = token.ok_or("proxy access needs a token")?;
+        let wall_now = self.started.1 + self.now.saturating_duration_since(self.started.0);
+        let unix_now = wall_now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        auth::verify_token(secret.as_bytes(), token, scope, unix_now)
+    }
+
+    /// Whether `source` may not ask for another proxy, see `TrackerConfig::max_proxies_per_ip`
+    /// Whether clients may have us send to `ip`, see `TrackerConfig::destinations`. Never to
+    /// ourselves, whatever the policy allows: our public IP and the IPs we are bound to.
+    fn allows_destination(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let ourselves = self.config.public_ip.map(|ip| ip.to_canonical()) == Some(ip)
+            || self.config.bind_addrs.iter().any(|addr| addr.ip().to_canonical() == ip);
+        !ourselves && self.config.destinations.allows(ip)
+    }
+
+    fn proxy_cap_reached(&self, source: IpAddr) -> bool {
+        let max = self.config.max_proxies_per_ip;
+        max > 0 && self.shared.limiter.proxy_count(source) >= max
+    }
+
+    /// Relay `peer` on `channel` of the session `client` has on `socket_n`, or on any free channel,
+    /// for `requested_by`. Returns the channel bound.
+    fn bind_channel(&mut self, socket_n: usize, client: SocketAddr, peer: SocketAddr, channel: Option<u16>, requested_by: IpAddr) -> Result<u16, &'static str> {
+        if !self.can_reach(socket_n, peer) {
+            return Err("peer unreachable from this port");
+        }
+        let Some(allocation) = self.allocations.get_mut(&(socket_n, client)) else {
+            return Err("no relay session on this port");
+        };
+        if let Some(&i) = self.proxy_routes.get(&(socket_n, peer)) {
+            return match self.proxy_list[i].channel {
+                // bound already, the client did not get our answer
+                Some(c) if self.proxy_list[i].incoming == client && channel.is_none_or(|channel| channel == c) => Ok(c),
+                _ => Err("peer already relayed on this port"),
+            };
+        }
+        if self.shared.owners.read().unwrap().proxies.contains_key(&(socket_n, peer)) {
+            // by another worker
+            return Err("peer already relayed on this port");
+        }
+        let max = self.config.max_proxies_per_ip;
+        if max > 0 && self.shared.limiter.proxy_count(requested_by) >= max {
+            return Err("too many proxies from this address");
+        }
+        let channel = match channel {
+            Some(c) if !relay::is_valid_channel(c) => return Err("invalid channel number"),
+            Some(c) if self.channel_routes.contains_key(&(socket_n, client, c)) => return Err("channel already bound"),
+            Some(c) => c,
+            None => {
+                let start = allocation.next_channel;
+                let range = relay::CHANNEL_MAX - relay::CHANNEL_MIN + 1;
+                let channel = (0..range)
+                    .map(|k| relay::CHANNEL_MIN + (start - relay::CHANNEL_MIN + k) % range)
+                    .find(|c| !self.channel_routes.contains_key(&(socket_n, client, *c)))
+                    .ok_or("all channels are bound")?;
+                allocation.next_channel = if channel == relay::CHANNEL_MAX { relay::CHANNEL_MIN } else { channel + 1 };
+                channel
+            },
+        };
+        self.add_proxy(ProxyData {
+            channel: Some(channel),
+            ..ProxyData::new((client, socket_n), (peer, socket_n), requested_by, self.now)
+        });
+        log::info!("relaying {} on channel {:#x} of {} (socket {})", peer, channel, client, socket_n);
+        Ok(channel)
+    }
+
+    /// When `handle_timeout` should be called next: the next expiry, possibly a bit early when it
+    /// was renewed since
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.next_deadline
+    }
+
+    /// Wakes `handle_timeout` at `deadline` at the latest
+    fn schedule(&mut self, deadline: Instant) {
+        self.next_deadline = Some(self.next_deadline.map_or(deadline, |next| next.min(deadline)));
+    }
+
+    /// Scans everything for the next expiry. Registrations of the other workers are in, those they
+    /// add later are their own to schedule.
+    fn find_deadline(&self) -> Option<Instant> {
+        let registry = self.shared.registry.lock().unwrap();
+        let hosts = registry.rdv_hosts.values().map(|r| r.expiring);
+        let checks = registry.punch_checks.iter().map(|c| c.expire);
+        let proxies = self.proxy_list.iter().map(|p| p.last_active + self.config.proxy_expire);
+        let allocations = self.allocations.values().map(|a| a.expire);
+        hosts.chain(checks).chain(proxies).chain(allocations).min()
+    }
+
+    /// Next datagram to send, and the index of the socket to send it from
+    pub fn poll_transmit(&mut self) -> Option<(usize, Transmit)> {
+        self.transmits.pop_front()
+    }
+
+    /// Next datagram to give to another worker
+    pub fn poll_handoff(&mut self) -> Option<Handoff> {
+        self.handoffs.pop_front()
+    }
+
+    /// Next domain to resolve, for `handle_lookup`. Resolving blocks for as long as name servers
+    /// take to answer, do it on another thread.
+    pub fn poll_lookup(&mut self) -> Option<String> {
+        self.lookups.pop_front()
+    }
+
+    /// Answers everyone waiting for `domain`, and caches the result
+    pub fn handle_lookup(&mut self, domain: String, result: Result<Vec<SocketAddr>, String>, now: Instant) {
+        self.now = now;
+        let result = result
+            .and_then(|results| {
+                let found = !results.is_empty();
+                // answering private addresses would tell clients about our network
+                let results = results.into_iter()
+                    .map(canonical)
+                    .filter(|addr| self.allows_destination(addr.ip()))
+                    .take(DNS_MAX_RESULTS)
+                    .collect::<Vec<_>>();
+                match (results.is_empty(), found) {
+                    (false, _) => Ok(results),
+                    (true, true) => Err("destination not allowed".to_string()),
+                    (true, false) => Err("no address found".to_string()),
+                }
+            })
+            // `/` separates the fields of our messages
+            .map_err(|e| e.replace('/', " "));
+        let ttl = match &result {
+            Ok(_) => self.config.dns_cache,
+            Err(e) => {
+                log::info!("could not resolve {}: {}", domain, e);
+                self.config.dns_negative_cache
+            },
+        };
+        let waiters = self.dns_pending.remove(&domain).map(|(_, waiters)| waiters).unwrap_or_default();
+        for (socket_n, remote) in waiters {
+            self.send_msg(domain_name_answer(&domain, &result), socket_n, remote);
+        }
+        if self.dns_cache.len() >= DNS_CACHE_MAX {
+            self.dns_cache.retain(|_, (_, expire)| now < *expire);
+        }
+        if self.dns_cache.len() < DNS_CACHE_MAX {
+            self.dns_cache.insert(domain, (result, now + ttl));
+        }
+    }
+
+    /// What the workers share, `SharedState::metrics` in particular
+    pub fn shared(&self) -> &SharedState {
+        &self.shared
+    }
+
+    /// Number of hosts registered, on every worker
+    pub fn registration_count(&self) -> usize {
+        self.shared.registry.lock().unwrap().rdv_hosts.len()
+    }
+
+    fn send_msg(&mut self, msg: FromMiddlemanMsg, socket_n: usize, remote: SocketAddr) {
+        let bytes = msg.serialize();
+        // send each message twice just to be sure
+        self.transmits.push_back((socket_n, Transmit { dest: remote, bytes: bytes.clone() }));
+        self.transmits.push_back((socket_n, Transmit { dest: remote, bytes }));
+    }
+
+    /// Handles a datagram received by our socket `our_socket_n`: linkseeker messages are answered,
+    /// anything else is forwarded if it belongs to a proxy
+    pub fn handle_datagram(&mut self, bytes: &[u8], our_socket_n: usize, socket_addr: SocketAddr, now: Instant) {
+        self.now = now;
+        self.process(bytes, our_socket_n, socket_addr, false);
+    }
+
+    /// Handles a datagram another worker received for us. It is never handed off again.
+    pub fn handle_handoff(&mut self, handoff: Handoff, now: Instant) {
+        self.now = now;
+        self.process(&handoff.bytes, handoff.socket_n, handoff.from, true);
+    }
+
+    fn process(&mut self, bytes: &[u8], our_socket_n: usize, socket_addr: SocketAddr, handed_off: bool) {
+        // dual-stack sockets see IPv4 peers as IPv4-mapped addresses
+        let socket_addr = canonical(socket_addr);
+        let source = source_of(socket_addr.ip());
+        let metrics = self.shared.metrics.clone();
+        if !handed_off && self.shared.limiter.is_banned(source, self.now) {
+            Metrics::inc(&metrics.banned_drops);
+            return;
+        }
+        let msg = ToMiddlemanMsg::parse(bytes);
+        // handed off messages were counted by the worker that received them
+        if !handed_off {
+            match &msg {
+                Some(msg) => Metrics::inc(&metrics.messages[MsgKind::of(msg) as usize]),
+                None if bytes.starts_with(UDPUNCH_ID_BYTES) => Metrics::inc(&metrics.parse_failures),
+                None => {},
+            }
+        }
+        match msg {
+            Some(msg) if !handed_off && !self.shared.limiter.allow(source, MsgKind::of(&msg), &self.config, self.now) => {
+                Metrics::inc(&metrics.rate_limited[MsgKind::of(&msg) as usize]);
+            },
+            Some(msg) => self.process_linkseeker_msg(msg, our_socket_n, socket_addr, handed_off),
+            None => self.process_other_msg(bytes, our_socket_n, socket_addr, handed_off),
+        };
+    }
+
+    /// Last socket no proxy uses yet for that remote: every socket is a slot, the remote tells the
+    /// peers it talks to through us apart by the socket they come from
+    fn get_next_proxy_socket_n(&self, remote_addr: SocketAddr) -> Option<usize> {
+        let owners = self.shared.owners.read().unwrap();
+        (0..self.config.socket_count()).rev()
+            .find(|i| self.can_reach(*i, remote_addr) && !owners.proxies.contains_key(&(*i, remote_addr)))
+    }
+
+    /// Whether our socket `socket_n` can send to `addr`: only the unspecified IPv6 address is bound
+    /// dual-stack
+    fn can_reach(&self, socket_n: usize, addr: SocketAddr) -> bool {
+        match self.config.bind_addrs[socket_n].ip() {
+            IpAddr::V6(ip) if ip.is_unspecified() => true,
+            ip => ip.is_ipv4() == addr.is_ipv4(),
+        }
+    }
+
+    fn process_linkseeker_msg(&mut self, msg: ToMiddlemanMsg, our_socket_n: usize, socket_addr: SocketAddr, handed_off: bool) {
+        match msg {
+            ToMiddlemanMsg::Register { mut candidates } => {
+                candidates.truncate(MAX_CANDIDATES);
+                candidates.iter_mut().for_each(|addr| *addr = canonical(*addr));
+                let mut registry = self.shared.registry.lock().unwrap();
+                if let Some((id, found)) = registry.rdv_hosts.iter_mut().find(|(_, r)| r.socket_addr == socket_addr) {
+                    // check if remote already exists, if it does refresh existing register
+                    found.expiring = self.now + self.config.register_expire;
+                    found.candidates = candidates;
+                    let id = *id;
+                    drop(registry);
+                    self.send_msg(FromMiddlemanMsg::RegisterOk { id }, our_socket_n, socket_addr);
+                    return;
+                }
+
+                let source = source_of(socket_addr.ip());
+                let max = self.config.max_registrations_per_ip;
+                if max > 0 && registry.per_source.get(&source).is_some_and(|count| *count >= max) {
+                    drop(registry);
+                    log::info!("not registering {}: {} ids registered from {} already", socket_addr, max, source);
+                    self.send_msg(
+                        FromMiddlemanMsg::RegisterErr { msg: "too many registrations from this address".to_string() },
+                        our_socket_n,
+                        socket_addr
+                    );
+                    return;
+                }
+                let expiring = self.now + self.config.register_expire;
+                let rdv_id = registry.insert_host(self.key, RdvRemote { socket_addr, socket_n: our_socket_n, expiring, candidates });
+                drop(registry);
+                self.schedule(expiring);
+                log::info!("registered id {} for {}", LinkCode(rdv_id), socket_addr);
+                self.send_msg(FromMiddlemanMsg::RegisterOk { id: rdv_id }, our_socket_n, socket_addr);
+            },
+            ToMiddlemanMsg::Renew { id } => {
+                let renewed = match self.shared.registry.lock().unwrap().rdv_hosts.get_mut(&id) {
+                    Some(host) if host.socket_addr == socket_addr => {
+                        host.expiring = self.now + self.config.register_expire;
+                        host.socket_n = our_socket_n;
+                        true
+                    },
+                    _ => false,
+                };
+                match renewed {
+                    true => {
+                        // only the relay sessions of this worker, the client keeps the others alive with its traffic
+                        for ((_, client), allocation) in &mut self.allocations {
+                            if *client == socket_addr {
+                                allocation.expire = self.now + self.config.proxy_expire;
+                            }
+                        }
+                        self.send_msg(FromMiddlemanMsg::RenewOk { id }, our_socket_n, socket_addr);
+                    },
+                    false => {
+                        // unknown, expired, or someone else's id
+                        self.send_msg(FromMiddlemanMsg::RegisterExpired { id }, our_socket_n, socket_addr);
+                    },
+                }
+            },
+            ToMiddlemanMsg::Request { id, use_proxy: false, mut candidates, .. } => {
+                let registry = self.shared.registry.lock().unwrap();
+                let Some(host) = registry.rdv_hosts.get(&id) else {
+                    drop(registry);
+                    self.send_msg(
+                        FromMiddlemanMsg::RequestErr { msg: "host code does not exist".to_string() },
+                        our_socket_n,
+                        socket_addr
+                    );
+                    return;
+                };
+                let host_socket = host.socket_addr;
+                let host_candidates = host.candidates.clone();
+                candidates.truncate(MAX_CANDIDATES);
+                candidates.iter_mut().for_each(|addr| *addr = canonical(*addr));
+                let Some((host_remote, remote)) = punch_addrs(host_socket, &host_candidates, socket_addr, &candidates) else {
+                    drop(registry);
+                    log::info!("cannot punch {} <-> {} (id={}): no address family in common", host_socket, socket_addr, LinkCode(id));
+                    self.send_msg(
+                        FromMiddlemanMsg::RequestErr { msg: "no address family in common with the host".to_string() },
+                        our_socket_n,
+                        socket_addr
+                    );
+                    return;
+                };
+                let (host_delta, delta) = (registry.port_delta_of(host_remote), registry.port_delta_of(remote));
+                drop(registry);
+                log::info!("trying to punch {} <-> {} (id={})", host_remote, remote, LinkCode(id));
+                // order server to punch client
+                self.send_msg(
+                    FromMiddlemanMsg::PunchOrder { remote: host_remote, delta: host_delta, candidates: host_candidates },
+                    our_socket_n,
+                    socket_addr
+                );
+                // order client to punch server
+                self.send_msg(
+                    FromMiddlemanMsg::PunchOrder { remote, delta, candidates },
+                    our_socket_n,
+                    host_socket
+                );
+            },
+            ToMiddlemanMsg::Request { id, use_proxy: true, token, .. } => {
+                if let Err(msg) = self.check_token(token.as_deref(), Scope::Id(id)) {
+                    log::info!("not proxying {} to id {}: {}", socket_addr, LinkCode(id), msg);
+                    self.send_msg(FromMiddlemanMsg::RequestErr { msg: msg.to_string() }, our_socket_n, socket_addr);
+                    return;
+                }
+                let host = self.shared.registry.lock().unwrap().rdv_hosts.get(&id).map(|host| host.socket_addr);
+                let Some(host_addr) = host else {
+                    self.send_msg(
+                        FromMiddlemanMsg::RequestErr { msg: "host code does not exist".to_string() },
+                        our_socket_n,
+                        socket_addr
+                    );
+                    return;
+                };
+                let allocation_owner = self.shared.owners.read().unwrap().allocations.get(&(our_socket_n, host_addr)).copied();
+                match allocation_owner {
+                    Some(worker) if worker != self.worker && !handed_off => {
+                        // the relay session of the host is on another worker, which binds the channel
+                        let bytes = ToMiddlemanMsg::Request { id, use_proxy: true, candidates: Vec::new(), token }.serialize();
+                        self.hand_off(worker, our_socket_n, socket_addr, bytes);
+                        return;
+                    },
+                    _ => {},
+                }
+                if self.proxy_list.iter().any(|proxy| proxy.incoming == socket_addr && proxy.outgoing == host_addr) {
+                    // the requester did not get our answer, send it again
+                    self.send_msg(FromMiddlemanMsg::ProxyResult { remote: host_addr, ok: true }, our_socket_n, socket_addr);
+                    return;
+                }
+                if self.allocations.contains_key(&(our_socket_n, host_addr)) {
+                    // the host has a relay session where the requester talks to us: no need for a socket of its own
+                    if let Ok(channel) = self.bind_channel(our_socket_n, host_addr, socket_addr, None, source_of(socket_addr.ip())) {
+                        self.send_msg(FromMiddlemanMsg::ChannelBound { channel, peer: socket_addr }, our_socket_n, host_addr);
+                        self.send_msg(FromMiddlemanMsg::ProxyResult { remote: host_addr, ok: true }, our_socket_n, socket_addr);
+                        return;
+                    }
+                }
+                if self.proxy_cap_reached(source_of(socket_addr.ip())) {
+                    log::info!("not proxying {} to {}: too many proxies from this address", socket_addr, host_addr);
+                    self.send_msg(
+                        FromMiddlemanMsg::RequestErr { msg: "too many proxies from this address".to_string() },
+                        our_socket_n,
+                        socket_addr
+                    );
+                    return;
+                }
+                let Some(host_socket_n) = self.get_next_proxy_socket_n(host_addr) else {
+                    Metrics::inc(&self.shared.metrics.proxy_slots_full);
+                    log::error!("could not get a new proxy socket for {}: all slots are full", host_addr);
+                    self.send_msg(
+                        FromMiddlemanMsg::RequestErr { msg: "all proxy slots are full".to_string() },
+                        our_socket_n,
+                        socket_addr
+                    );
+                    return;
+                };
+                // order host to punch us so they can receive messages
+                self.send_msg(
+                    FromMiddlemanMsg::PunchLinkseeker { port: self.config.bind_addrs[host_socket_n].port(), remote: Some(socket_addr) },
+                    0,
+                    host_addr
+                );
+                // tell the requester it can start sending through us
+                self.send_msg(FromMiddlemanMsg::ProxyResult { remote: host_addr, ok: true }, our_socket_n, socket_addr);
+
+                self.add_proxy(ProxyData::new(
+                    (socket_addr, our_socket_n),
+                    (host_addr, host_socket_n),
+                    source_of(socket_addr.ip()),
+                    self.now
+                ));
+                log::info!("starting to proxy {} -> ({}:lnksk:{}) -> {} (rdv_id={})",
+                    socket_addr, our_socket_n, host_socket_n, host_addr, LinkCode(id)
+                );
+            },
+            ToMiddlemanMsg::PunchCheck { id } => {
+                let mut registry = self.shared.registry.lock().unwrap();
+                let Some(found) = registry.punch_checks.iter_mut().find(|c| c.id == id) else {
+                    let expire = self.now + self.config.punch_check_expire;
+                    registry.punch_checks.push(PunchCheck::new(id, (socket_addr, our_socket_n), self.config.socket_count(), expire));
+                    drop(registry);
+                    self.schedule(expire);
+                    return;
+                };
+                if found.observed[our_socket_n].is_some() {
+                    // coming from the same port: already received this request, ignore it
+                    return;
+                }
+                // coming from a different port: check if the socket_addr is different
+                found.observed[our_socket_n] = Some(socket_addr);
+                let first_received = found.first_received;
+                let result = FromMiddlemanMsg::PunchCheckResult { ok: found.is_ok(), ports: found.ports(), delta: found.delta() };
+                drop(registry);
+                log::info!("udp punch check for {} (rdv_id={:8x}): {:?}", socket_addr, id, result);
+
+                // send the result to remote (both ways).
+                self.send_msg(result.clone(), our_socket_n, socket_addr);
+                self.send_msg(result, first_received.1, first_received.0);
+            },
+            ToMiddlemanMsg::ProxyTo { remote, token } => {
+                let remote = canonical(remote);
+                if !self.allows_destination(remote.ip()) {
+                    log::info!("not proxying {} to {} (raw): destination not allowed", socket_addr, remote);
+                    self.send_msg(FromMiddlemanMsg::RequestErr { msg: "destination not allowed".to_string() }, our_socket_n, socket_addr);
+                    return;
+                }
+                if let Err(msg) = self.check_token(token.as_deref(), Scope::Remote(remote)) {
+                    log::info!("not proxying {} to {} (raw): {}", socket_addr, remote, msg);
+                    self.send_msg(FromMiddlemanMsg::RequestErr { msg: msg.to_string() }, our_socket_n, socket_addr);
+                    return;
+                }
+                // check if the proxy doesn't already exist
+                if self.proxy_list.iter().any(|p| p.outgoing == remote && p.incoming == socket_addr) {
+                    return;
+                }
+                if self.proxy_cap_reached(source_of(socket_addr.ip())) {
+                    log::info!("not proxying {} to {} (raw): too many proxies from this address", socket_addr, remote);
+                    self.send_msg(FromMiddlemanMsg::ProxyResult { remote, ok: false }, our_socket_n, socket_addr);
+                    return;
+                }
+                log::info!("starting proxying {} to {} (raw)", socket_addr, remote);
+                if let Some(used_socket_n) = self.get_next_proxy_socket_n(remote) {
+                    self.add_proxy(ProxyData {
+                        raw: true,
+                        ..ProxyData::new((remote, used_socket_n), (socket_addr, our_socket_n), source_of(socket_addr.ip()), self.now)
+                    });
+                    self.send_msg(
+                        FromMiddlemanMsg::ProxyResult { remote, ok: true },
+                        our_socket_n,
+                        socket_addr
+                    );
+                } else {
+                    Metrics::inc(&self.shared.metrics.proxy_slots_full);
+                    self.send_msg(
+                        FromMiddlemanMsg::ProxyResult { remote, ok: false },
+                        our_socket_n,
+                        socket_addr
+                    );
+                }
+            },
+            ToMiddlemanMsg::DomainNameReq { domain } => {
+                if let Some((result, _)) = self.dns_cache.get(&domain).filter(|(_, expire)| self.now < *expire) {
+                    let answer = domain_name_answer(&domain, result);
+                    self.send_msg(answer, our_socket_n, socket_addr);
+                    return;
+                }
+                let waiter = (our_socket_n, socket_addr);
+                match self.dns_pending.entry(domain) {
+                    Entry::Occupied(mut pending) if self.now < pending.get().0 + DNS_LOOKUP_TIMEOUT => {
+                        // being resolved already
+                        let waiters = &mut pending.get_mut().1;
+                        if waiters.len() < DNS_MAX_WAITERS && !waiters.contains(&waiter) {
+                            waiters.push(waiter);
+                        }
+                    },
+                    Entry::Occupied(mut pending) => {
+                        log::warn!("resolving {} did not come back, trying again", pending.key());
+                        Metrics::inc(&self.shared.metrics.dns_lookups);
+                        self.lookups.push_back(pending.key().clone());
+                        *pending.get_mut() = (self.now, vec![waiter]);
+                    },
+                    Entry::Vacant(v) => {
+                        Metrics::inc(&self.shared.metrics.dns_lookups);
+                        self.lookups.push_back(v.key().clone());
+                        v.insert((self.now, vec![waiter]));
+                    },
+                }
+            },
+            ToMiddlemanMsg::Ping { id } => {
+                self.send_msg(
+                    FromMiddlemanMsg::Pong { id },
+                    our_socket_n,
+                    socket_addr
+                );
+            },
+            ToMiddlemanMsg::Allocate => {
+                let expire = self.now + self.config.proxy_expire;
+                self.allocations.entry((our_socket_n, socket_addr))
+                    .and_modify(|a| a.expire = expire)
+                    .or_insert_with(|| {
+                        log::info!("opening relay session for {} (socket {})", socket_addr, our_socket_n);
+                        Metrics::inc(&self.shared.metrics.relay_sessions);
+                        Allocation { expire, next_channel: relay::CHANNEL_MIN }
+                    });
+                self.shared.owners.write().unwrap().allocations.insert((our_socket_n, socket_addr), self.worker);
+                self.schedule(expire);
+                let port = self.config.bind_addrs[our_socket_n].port();
+                self.send_msg(FromMiddlemanMsg::AllocateOk { port }, our_socket_n, socket_addr);
+            },
+            ToMiddlemanMsg::ChannelBind { channel, peer, token } => {
+                let peer = canonical(peer);
+                if !self.allows_destination(peer.ip()) {
+                    log::info!("not relaying {} to {}: destination not allowed", socket_addr, peer);
+                    self.send_msg(FromMiddlemanMsg::ChannelBindErr { channel, msg: "destination not allowed".to_string() }, our_socket_n, socket_addr);
+                    return;
+                }
+                if let Err(msg) = self.check_token(token.as_deref(), Scope::Remote(peer)) {
+                    self.send_msg(FromMiddlemanMsg::ChannelBindErr { channel, msg: msg.to_string() }, our_socket_n, socket_addr);
+                    return;
+                }
+                let msg = match self.bind_channel(our_socket_n, socket_addr, peer, Some(channel), source_of(socket_addr.ip())) {
+                    Ok(channel) => FromMiddlemanMsg::ChannelBound { channel, peer },
+                    Err(msg) => FromMiddlemanMsg::ChannelBindErr { channel, msg: msg.to_string() },
+                };
+                self.send_msg(msg, our_socket_n, socket_addr);
+            },
+            ToMiddlemanMsg::WhatIsMyAddr => {
+                self.send_msg(
+                    FromMiddlemanMsg::YourAddr { observed: socket_addr, socket_n: our_socket_n as u8 },
+                    our_socket_n,
+                    socket_addr
+                );
+            },
+            ToMiddlemanMsg::NatProbe { id, reply_via: Some(other), .. } => {
+                // only a tracker that knows us answers, anything else would make us a reflector
+                let other = canonical(other);
+                if !self.config.peer_trackers.iter().any(|&peer| canonical(peer) == other) {
+                    return;
+                }
+                let bytes = ToMiddlemanMsg::NatProbeFor { id, observed: socket_addr }.serialize();
+                self.transmits.push_back((our_socket_n, Transmit { dest: other, bytes }));
+            },
+            ToMiddlemanMsg::NatProbeFor { id, observed } => {
+                // another tracker asking for one of its clients, which is public if it reached it
+                let observed = canonical(observed);
+                let from_peer = self.config.peer_trackers.iter().any(|&peer| canonical(peer).ip() == socket_addr.ip());
+                if !from_peer || !self.allows_destination(observed.ip()) {
+                    return;
+                }
+                self.send_msg(
+                    FromMiddlemanMsg::NatProbeResult { id, observed, socket_n: our_socket_n as u8 },
+                    our_socket_n,
+                    observed
+                );
+            },
+            ToMiddlemanMsg::NatProbe { id, reply_from, reply_via: None } => {
+                let reply_socket_n = match reply_from {
+                    Some(n) if (n as usize) < self.config.socket_count() => n as usize,
+                    Some(_) => return,
+                    None => our_socket_n,
+                };
+                self.send_msg(
+                    FromMiddlemanMsg::NatProbeResult { id, observed: socket_addr, socket_n: our_socket_n as u8 },
+                    reply_socket_n,
+                    socket_addr
+                );
+            },
+        }
+    }
+
+    fn process_other_msg(&mut self, bytes: &[u8], our_socket_n: usize, socket_addr: SocketAddr, handed_off: bool) {
+        // framed by a client with a relay session
+        let channel_route = relay::decode(bytes).and_then(|(channel, payload)| {
+            let i = self.channel_routes.get(&(our_socket_n, socket_addr, channel))?;
+            Some((*i, payload))
+        });
+        let route = channel_route.or_else(|| {
+            let i = self.proxy_routes.get(&(our_socket_n, socket_addr))?;
+            Some((*i, bytes))
+        });
+        if let Some((i, bytes)) = route {
+            if let Some(allocation) = self.allocations.get_mut(&(our_socket_n, socket_addr)) {
+                allocation.expire = self.now + self.config.proxy_expire;
+            }
+            let found = &mut self.proxy_list[i];
+            found.last_active = self.now;
+
+            if self.now < found.first_active + self.config.first_packet_delay {
+                // don't proxy packets too soon
+                // if router's DmZ is active, if we send a packet *before* they send one,
+                // the packet will go through DmZ instead of the remote, and the remote will be invalid
+                // if we wait a bit before sending packets, we ensure that the DmZ does not catch our packet
+                return;
+            }
+
+            // the source's limits count the bytes when they let them through, the session's after them
+            let quota = match found.quota(bytes.len(), &self.config, self.now) {
+                Quota::Within => self.shared.limiter.relay(found.requested_by, bytes.len(), &self.config, self.now),
+                quota => quota,
+            };
+            match quota {
+                Quota::Within => {},
+                Quota::Throttled => {
+                    Metrics::inc(&self.shared.metrics.throttled_packets);
+                    return;
+                },
+                Quota::Exceeded => {
+                    Metrics::inc(&self.shared.metrics.proxies_over_quota);
+                    self.close_proxy(i, "relay quota exceeded");
+                    return;
+                },
+            }
+            Metrics::inc(&self.shared.metrics.relayed_packets);
+            Metrics::add(&self.shared.metrics.relayed_bytes, bytes.len() as u64);
+            let found = &mut self.proxy_list[i];
+            found.bandwidth.take(bytes.len(), self.config.session_bandwidth);
+            let (socket_n, dest, bytes) = if found.incoming == socket_addr && found.in_socket_n == our_socket_n {
+                found.in_packets += 1;
+                found.in_bytes += bytes.len() as u64;
+                (found.out_socket_n, found.outgoing, bytes.to_vec())
+            } else {
+                found.out_packets += 1;
+                found.out_bytes += bytes.len() as u64;
+                let bytes = match found.channel {
+                    Some(channel) => relay::encode(channel, bytes),
+                    None => bytes.to_vec(),
+                };
+                (found.in_socket_n, found.incoming, bytes)
+            };
+            self.transmits.push_back((socket_n, Transmit { dest, bytes }));
+        } else if !handed_off {
+            // the kernel spreads flows between workers, this one may belong to another proxy's owner
+            match self.owner_of(our_socket_n, socket_addr) {
+                Some(worker) if worker != self.worker => self.hand_off(worker, our_socket_n, socket_addr, bytes.to_vec()),
+                _ => {},
+            }
+        }
+    }
+}
+
+fn domain_name_answer(domain: &str, result: &Result<Vec<SocketAddr>, String>) -> FromMiddlemanMsg {
+    match result {
+        Ok(results) => FromMiddlemanMsg::DomainNameResult { domain: domain.to_string(), results: results.clone() },
+        Err(msg) => FromMiddlemanMsg::DomainNameErr { domain: domain.to_string(), msg: msg.clone() },
+    }
+}
+
+#[cfg(test)]
+fn drain(core: &mut TrackerCore) -> Vec<(usize, FromMiddlemanMsg, SocketAddr)> {
+    let mut msgs = Vec::new();
+    while let Some((socket_n, t)) = core.poll_transmit() {
+        msgs.push((socket_n, FromMiddlemanMsg::parse(&t.bytes).unwrap(), t.dest));
+    }
+    msgs
+}
+
+/// Sizes of the datagrams to send
+#[cfg(test)]
+fn drain_bytes(core: &mut TrackerCore) -> Vec<usize> {
+    std::iter::from_fn(|| core.poll_transmit()).map(|(_, t)| t.bytes.len()).collect()
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_registration_expires() {
+    let host: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let now = Instant::now();
+    let mut core = TrackerCore::new(TrackerConfig::default(), now);
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 2, host, now);
+    let msgs = drain(&mut core);
+    let (2, FromMiddlemanMsg::RegisterOk { id }, dest) = msgs[0] else {
+        panic!("unexpected {:?}", msgs);
+    };
+    assert_eq!(dest, host);
+    assert_eq!(core.poll_timeout(), Some(now + core.config.register_expire));
+
+    core.handle_timeout(now + core.config.register_expire - Duration::from_secs(1));
+    assert!(drain(&mut core).is_empty());
+    core.handle_timeout(now + core.config.register_expire);
+    assert_eq!(drain(&mut core)[0], (2, FromMiddlemanMsg::RegisterExpired { id }, host));
+    assert_eq!(core.registration_count(), 0);
+    assert_eq!(core.poll_timeout(), None);
+
+    // a renewal leaves the timer early, the wake up finds the new expiry
+    let now = now + core.config.register_expire;
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 2, host, now);
+    let Some((_, FromMiddlemanMsg::RegisterOk { id }, _)) = drain(&mut core).pop() else {
+        panic!("not registered");
+    };
+    let renewed = now + Duration::from_secs(10);
+    core.handle_datagram(&ToMiddlemanMsg::Renew { id }.serialize(), 2, host, renewed);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::RenewOk { id });
+    assert_eq!(core.poll_timeout(), Some(now + core.config.register_expire));
+    core.handle_timeout(now + core.config.register_expire);
+    assert!(drain(&mut core).is_empty());
+    assert_eq!(core.poll_timeout(), Some(renewed + core.config.register_expire));
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_proxies_after_delay() {
+    let host: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let requester: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let mut now = Instant::now();
+    let mut core = TrackerCore::new(TrackerConfig { open_proxy: true, ..TrackerConfig::default() }, now);
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 0, host, now);
+    let Some((_, FromMiddlemanMsg::RegisterOk { id }, _)) = drain(&mut core).pop() else {
+        panic!("not registered");
+    };
+
+    let request = ToMiddlemanMsg::Request { id, use_proxy: true, candidates: vec![], token: None };
+    core.handle_datagram(&request.serialize(), 0, requester, now);
+    let msgs = drain(&mut core);
+    let Some((_, FromMiddlemanMsg::PunchLinkseeker { port, remote: Some(remote) }, _)) = msgs.first().cloned() else {
+        panic!("unexpected {:?}", msgs);
+    };
+    assert_eq!(remote, requester);
+    let relay_n = (port - 61990) as usize;
+    assert!(msgs.contains(&(0, FromMiddlemanMsg::ProxyResult { remote: host, ok: true }, requester)));
+
+    // too soon, dropped
+    core.handle_datagram(b"game data", 0, requester, now);
+    assert!(core.poll_transmit().is_none());
+
+    now += Duration::from_millis(300);
+    core.handle_datagram(b"game data", 0, requester, now);
+    assert_eq!(core.poll_transmit(), Some((relay_n, Transmit { dest: host, bytes: b"game data".to_vec() })));
+    core.handle_datagram(b"answer", relay_n, host, now);
+    assert_eq!(core.poll_transmit(), Some((0, Transmit { dest: requester, bytes: b"answer".to_vec() })));
+    assert_eq!(core.proxy_list[0].in_packets, 1);
+    assert_eq!(core.proxy_list[0].out_packets, 1);
+
+    core.handle_timeout(now + core.config.proxy_expire);
+    assert!(core.proxy_list.is_empty());
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_proxy_slots_scale_with_sockets() {
+    let host: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let now = Instant::now();
+    let config = TrackerConfig { open_proxy: true, ..TrackerConfig::with_port_range("0.0.0.0".parse().unwrap(), 40000, 8) };
+    let mut core = TrackerCore::new(config, now);
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 0, host, now);
+    let Some((_, FromMiddlemanMsg::RegisterOk { id }, _)) = drain(&mut core).pop() else {
+        panic!("not registered");
+    };
+    let mut ports = Vec::new();
+    for i in 0..9 {
+        let requester = SocketAddr::new([2, 2, 2, i].into(), 2000);
+        let request = ToMiddlemanMsg::Request { id, use_proxy: true, candidates: vec![], token: None };
+        core.handle_datagram(&request.serialize(), 0, requester, now);
+        match drain(&mut core).remove(0) {
+            (_, FromMiddlemanMsg::PunchLinkseeker { port, .. }, _) => ports.push(port),
+            (_, msg, _) => {
+                assert_eq!(i, 8);
+                assert_eq!(msg, FromMiddlemanMsg::RequestErr { msg: "all proxy slots are full".to_string() });
+            },
+        }
+    }
+    assert_eq!(ports, (40000..40008).rev().collect::<Vec<_>>());
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_relays_on_channels() {
+    let host: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let mut now = Instant::now();
+    let mut core = TrackerCore::new(TrackerConfig { open_proxy: true, ..TrackerConfig::default() }, now);
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 0, host, now);
+    let Some((_, FromMiddlemanMsg::RegisterOk { id }, _)) = drain(&mut core).pop() else {
+        panic!("not registered");
+    };
+    core.handle_datagram(&ToMiddlemanMsg::Allocate.serialize(), 0, host, now);
+    assert_eq!(drain(&mut core)[0], (0, FromMiddlemanMsg::AllocateOk { port: 61990 }, host));
+
+    // more peers than the tracker has sockets, all on socket 0
+    let mut channels = Vec::new();
+    for i in 0..6 {
+        let requester = SocketAddr::new([2, 2, 2, i].into(), 2000);
+        let request = ToMiddlemanMsg::Request { id, use_proxy: true, candidates: vec![], token: None };
+        core.handle_datagram(&request.serialize(), 0, requester, now);
+        let msgs = drain(&mut core);
+        let Some((0, FromMiddlemanMsg::ChannelBound { channel, peer }, dest)) = msgs.first().cloned() else {
+            panic!("unexpected {:?}", msgs);
+        };
+        assert_eq!((peer, dest), (requester, host));
+        assert!(msgs.contains(&(0, FromMiddlemanMsg::ProxyResult { remote: host, ok: true }, requester)));
+        channels.push((channel, requester));
+    }
+    assert_eq!(channels.iter().map(|(c, _)| *c).collect::<Vec<_>>(), (0x4000..0x4006).collect::<Vec<_>>());
+
+    now += Duration::from_millis(300);
+    let (channel, requester) = channels[3];
+    core.handle_datagram(b"from peer", 0, requester, now);
+    assert_eq!(core.poll_transmit(), Some((0, Transmit { dest: host, bytes: relay::encode(channel, b"from peer") })));
+    core.handle_datagram(&relay::encode(channel, b"to peer"), 0, host, now);
+    assert_eq!(core.poll_transmit(), Some((0, Transmit { dest: requester, bytes: b"to peer".to_vec() })));
+
+    // a channel can only be bound once
+    let bind = ToMiddlemanMsg::ChannelBind { channel, peer: "3.3.3.3:3000".parse().unwrap(), token: None };
+    core.handle_datagram(&bind.serialize(), 0, host, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::ChannelBindErr { channel, msg: "channel already bound".to_string() });
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_workers_share_hosts_and_hand_off_flows() {
+    let host: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let requester: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let mut now = Instant::now();
+    let shared = SharedState::default();
+    let config = TrackerConfig { open_proxy: true, ..TrackerConfig::default() };
+    let mut first = TrackerCore::worker(config.clone(), shared.clone(), 0, now);
+    let mut second = TrackerCore::worker(config, shared, 1, now);
+    first.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 0, host, now);
+    let Some((_, FromMiddlemanMsg::RegisterOk { id }, _)) = drain(&mut first).pop() else {
+        panic!("not registered");
+    };
+    assert_eq!(second.registration_count(), 1);
+
+    // the proxy is pinned to the worker the requester talks to
+    let request = ToMiddlemanMsg::Request { id, use_proxy: true, candidates: vec![], token: None };
+    second.handle_datagram(&request.serialize(), 0, requester, now);
+    let Some((_, FromMiddlemanMsg::PunchLinkseeker { port, .. }, _)) = drain(&mut second).first().cloned() else {
+        panic!("no proxy");
+    };
+    let relay_n = (port - 61990) as usize;
+    // slots are shared too
+    first.handle_datagram(&request.serialize(), 0, "3.3.3.3:3000".parse().unwrap(), now);
+    assert_eq!(drain(&mut first)[0].1, FromMiddlemanMsg::PunchLinkseeker { port: port - 1, remote: Some("3.3.3.3:3000".parse().unwrap()) });
+
+    now += Duration::from_millis(300);
+    first.handle_datagram(b"answer", relay_n, host, now);
+    assert!(first.poll_transmit().is_none());
+    let handoff = first.poll_handoff().unwrap();
+    assert_eq!(handoff, Handoff { worker: 1, socket_n: relay_n, from: host, bytes: b"answer".to_vec() });
+    second.handle_handoff(handoff, now);
+    assert_eq!(second.poll_transmit(), Some((0, Transmit { dest: requester, bytes: b"answer".to_vec() })));
+
+    now += second.config.proxy_expire;
+    second.handle_timeout(now);
+    first.handle_datagram(b"answer", relay_n, host, now);
+    assert!(first.poll_handoff().is_none());
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_nat_probes_through_another_tracker() {
+    let client: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let other: SocketAddr = "2.2.2.2:61990".parse().unwrap();
+    let ours: SocketAddr = "3.3.3.3:61990".parse().unwrap();
+    let now = Instant::now();
+    let mut config = TrackerConfig::default();
+    config.set("peer_trackers", "2.2.2.2:61990").unwrap();
+    let mut core = TrackerCore::new(config, now);
+    core.handle_datagram(&ToMiddlemanMsg::NatProbe { id: 7, reply_from: None, reply_via: Some(other) }.serialize(), 0, client, now);
+    let (socket_n, t) = core.poll_transmit().unwrap();
+    assert_eq!((socket_n, t.dest), (0, other));
+    assert_eq!(ToMiddlemanMsg::parse(&t.bytes), Some(ToMiddlemanMsg::NatProbeFor { id: 7, observed: client }));
+    assert_eq!(core.poll_transmit(), None);
+    // only to trackers we know
+    let unknown = ToMiddlemanMsg::NatProbe { id: 7, reply_from: None, reply_via: Some("4.4.4.4:61990".parse().unwrap()) };
+    core.handle_datagram(&unknown.serialize(), 0, client, now);
+    assert_eq!(core.poll_transmit(), None);
+
+    // the other tracker answers the client
+    let mut config = TrackerConfig::default();
+    config.set("peer_trackers", &ours.to_string()).unwrap();
+    let mut other_core = TrackerCore::new(config, now);
+    other_core.handle_datagram(&t.bytes, 1, ours, now);
+    assert_eq!(drain(&mut other_core)[0], (1, FromMiddlemanMsg::NatProbeResult { id: 7, observed: client, socket_n: 1 }, client));
+    // from any socket of a known tracker, but from nobody else
+    other_core.handle_datagram(&t.bytes, 1, "3.3.3.3:61991".parse().unwrap(), now);
+    assert_eq!(drain(&mut other_core).len(), 2);
+    other_core.handle_datagram(&t.bytes, 1, "5.5.5.5:61990".parse().unwrap(), now);
+    assert_eq!(drain(&mut other_core), []);
+    // not into a private network
+    let bytes = ToMiddlemanMsg::NatProbeFor { id: 7, observed: "10.0.0.1:22".parse().unwrap() }.serialize();
+    other_core.handle_datagram(&bytes, 1, ours, now);
+    assert_eq!(drain(&mut other_core), []);
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_dual_stack_punch_orders() {
+    let host: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let host_mapped: SocketAddr = "[::ffff:1.1.1.1]:1000".parse().unwrap();
+    let host_v6: SocketAddr = "[2001:db8::1]:1000".parse().unwrap();
+    let requester: SocketAddr = "[2001:db8::2]:2000".parse().unwrap();
+    let now = Instant::now();
+    let mut core = TrackerCore::new(TrackerConfig::default(), now);
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![host_v6] }.serialize(), 0, host_mapped, now);
+    let Some((_, FromMiddlemanMsg::RegisterOk { id }, dest)) = drain(&mut core).pop() else {
+        panic!("not registered");
+    };
+    assert_eq!(dest, host);
+    // the same host, once mapped addresses are normalized
+    core.handle_datagram(&ToMiddlemanMsg::Renew { id }.serialize(), 0, host, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::RenewOk { id });
+
+    // the IPv6 requester punches the host's IPv6 address, not the IPv4 one we see
+    core.handle_datagram(&ToMiddlemanMsg::Request { id, use_proxy: false, candidates: vec![], token: None }.serialize(), 0, requester, now);
+    let msgs = drain(&mut core);
+    assert_eq!(msgs[0], (0, FromMiddlemanMsg::PunchOrder { remote: host_v6, delta: None, candidates: vec![host_v6] }, requester));
+    assert!(msgs.contains(&(0, FromMiddlemanMsg::PunchOrder { remote: requester, delta: None, candidates: vec![] }, host)));
+
+    // no way to reach an IPv4 only host
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 0, host, now);
+    core.handle_datagram(&ToMiddlemanMsg::Request { id, use_proxy: false, candidates: vec![], token: None }.serialize(), 0, requester, now);
+    assert_eq!(drain(&mut core).pop().unwrap().1, FromMiddlemanMsg::RequestErr { msg: "no address family in common with the host".to_string() });
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_limits_sources() {
+    use super::limits::RateLimit;
+
+    let config = TrackerConfig {
+        max_registrations_per_ip: 2,
+        max_proxies_per_ip: 1,
+        ban_after: 3,
+        open_proxy: true,
+        ..TrackerConfig::default()
+    };
+    let mut now = Instant::now();
+    let mut core = TrackerCore::new(config, now);
+    core.config.rate_limits[MsgKind::Register as usize] = RateLimit { per_minute: 60, burst: 3 };
+    let register = ToMiddlemanMsg::Register { candidates: vec![] }.serialize();
+    let flooder = |port| SocketAddr::new([1, 1, 1, 1].into(), port);
+
+    for port in 1000..1002 {
+        core.handle_datagram(&register, 0, flooder(port), now);
+        assert!(matches!(drain(&mut core)[0].1, FromMiddlemanMsg::RegisterOk { .. }));
+    }
+    core.handle_datagram(&register, 0, flooder(1002), now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::RegisterErr { msg: "too many registrations from this address".to_string() });
+    // over the rate limit: dropped, then banned
+    for port in 1003..1006 {
+        core.handle_datagram(&register, 0, flooder(port), now);
+        assert_eq!(drain(&mut core), []);
+    }
+    let ping = ToMiddlemanMsg::Ping { id: 1 }.serialize();
+    core.handle_datagram(&ping, 0, flooder(1000), now);
+    assert_eq!(drain(&mut core), []);
+    core.handle_datagram(&ping, 0, "2.2.2.2:1000".parse().unwrap(), now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::Pong { id: 1 });
+    core.handle_datagram(b"#lnksk@nonsense", 0, "2.2.2.2:1000".parse().unwrap(), now);
+    let metrics = core.shared().metrics(now);
+    for line in [
+        "linkseeker_registrations 2",
+        "linkseeker_messages_total{kind=\"register\"} 6",
+        "linkseeker_rate_limited_total{kind=\"register\"} 3",
+        "linkseeker_bans_total 1",
+        "linkseeker_banned_sources 1",
+        "linkseeker_banned_drops_total 1",
+        "linkseeker_parse_failures_total 1",
+    ] {
+        assert!(metrics.lines().any(|l| l == line), "{} missing from\n{}", line, metrics);
+    }
+    now += core.config.ban_duration;
+    core.handle_timeout(now);
+    drain(&mut core);
+    core.handle_datagram(&ping, 0, flooder(1000), now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::Pong { id: 1 });
+
+    // one proxy at a time, another once it expired
+    let requester: SocketAddr = "3.3.3.3:1000".parse().unwrap();
+    let proxy_to = |remote: &str| ToMiddlemanMsg::ProxyTo { remote: remote.parse().unwrap(), token: None }.serialize();
+    core.handle_datagram(&proxy_to("9.9.9.9:1"), 0, requester, now);
+    assert!(matches!(drain(&mut core)[0].1, FromMiddlemanMsg::ProxyResult { ok: true, .. }));
+    core.handle_datagram(&proxy_to("9.9.9.9:2"), 0, requester, now);
+    assert!(matches!(drain(&mut core)[0].1, FromMiddlemanMsg::ProxyResult { ok: false, .. }));
+    now += core.config.proxy_expire;
+    core.handle_timeout(now);
+    drain(&mut core);
+    core.handle_datagram(&proxy_to("9.9.9.9:2"), 0, requester, now);
+    assert!(matches!(drain(&mut core)[0].1, FromMiddlemanMsg::ProxyResult { ok: true, .. }));
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_closes_proxies_over_quota() {
+    let host: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let requester: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let config = TrackerConfig { session_quota: 2000, ip_bandwidth: 1500, open_proxy: true, ..TrackerConfig::default() };
+    let mut now = Instant::now();
+    let mut core = TrackerCore::new(config, now);
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 0, host, now);
+    let Some((_, FromMiddlemanMsg::RegisterOk { id }, _)) = drain(&mut core).pop() else {
+        panic!("not registered");
+    };
+    core.handle_datagram(&ToMiddlemanMsg::Request { id, use_proxy: true, candidates: vec![], token: None }.serialize(), 0, requester, now);
+    drain(&mut core);
+    let relay_n = core.proxy_list[0].out_socket_n;
+    now += core.config.first_packet_delay;
+
+    core.handle_datagram(&[0; 1000], 0, requester, now);
+    core.handle_datagram(&[0; 400], relay_n, host, now);
+    assert_eq!(drain_bytes(&mut core), [1000, 400]);
+    // over the bandwidth of the requester: dropped
+    core.handle_datagram(&[0; 400], relay_n, host, now);
+    assert_eq!(drain_bytes(&mut core), []);
+    assert_eq!((core.proxy_list[0].in_bytes, core.proxy_list[0].out_bytes), (1000, 400));
+
+    now += Duration::from_secs(1);
+    core.handle_datagram(&[0; 1000], 0, requester, now);
+    let msg = "relay quota exceeded".to_string();
+    assert_eq!(drain(&mut core), [
+        (0, FromMiddlemanMsg::ProxyClosed { remote: host, msg: msg.clone() }, requester),
+        (0, FromMiddlemanMsg::ProxyClosed { remote: host, msg: msg.clone() }, requester),
+        (relay_n, FromMiddlemanMsg::ProxyClosed { remote: requester, msg: msg.clone() }, host),
+        (relay_n, FromMiddlemanMsg::ProxyClosed { remote: requester, msg }, host),
+    ]);
+    assert!(core.proxy_list.is_empty());
+    core.handle_datagram(&[0; 10], 0, requester, now);
+    assert_eq!(drain_bytes(&mut core), []);
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_counts_only_forwarded_bytes() {
+    let host: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let requester: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let config = TrackerConfig { session_bandwidth: 2000, ip_bandwidth: 10000, open_proxy: true, ..TrackerConfig::default() };
+    let mut now = Instant::now();
+    let mut core = TrackerCore::new(config, now);
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 0, host, now);
+    let Some((_, FromMiddlemanMsg::RegisterOk { id }, _)) = drain(&mut core).pop() else {
+        panic!("not registered");
+    };
+    core.handle_datagram(&ToMiddlemanMsg::Request { id, use_proxy: true, candidates: vec![], token: None }.serialize(), 0, requester, now);
+    drain(&mut core);
+    now += core.config.first_packet_delay;
+
+    // the other sessions of the requester used up its bandwidth
+    let source = core.proxy_list[0].requested_by;
+    assert_eq!(core.shared.limiter.relay(source, 10000, &core.config, now), Quota::Within);
+    core.handle_datagram(&[0; 1500], 0, requester, now);
+    assert_eq!(drain_bytes(&mut core), []);
+    // the dropped datagram took nothing from the session's bandwidth
+    now += Duration::from_millis(100);
+    core.handle_datagram(&[0; 800], 0, requester, now);
+    assert_eq!(drain_bytes(&mut core), [800]);
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_gates_proxy_with_tokens() {
+    let host: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let requester: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let config = TrackerConfig { proxy_secret: Some("hunter2".to_string()), ..TrackerConfig::default() };
+    let now = Instant::now();
+    let mut core = TrackerCore::new(config, now);
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 0, host, now);
+    let Some((_, FromMiddlemanMsg::RegisterOk { id }, _)) = drain(&mut core).pop() else {
+        panic!("not registered");
+    };
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let request = |token: Option<String>| ToMiddlemanMsg::Request { id, use_proxy: true, candidates: vec![], token }.serialize();
+
+    core.handle_datagram(&request(None), 0, requester, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::RequestErr { msg: "proxy access needs a token".to_string() });
+    let expired = auth::make_token(b"hunter2", Scope::Id(id), unix_now - 1);
+    core.handle_datagram(&request(Some(expired)), 0, requester, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::RequestErr { msg: "proxy token expired".to_string() });
+    // a token for another host
+    let token = auth::make_token(b"hunter2", Scope::Id(id ^ 1), unix_now + 60);
+    core.handle_datagram(&request(Some(token)), 0, requester, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::RequestErr { msg: "invalid proxy token".to_string() });
+    let token = auth::make_token(b"hunter2", Scope::Id(id), unix_now + 60);
+    core.handle_datagram(&request(Some(token)), 0, requester, now);
+    assert!(drain(&mut core).contains(&(0, FromMiddlemanMsg::ProxyResult { remote: host, ok: true }, requester)));
+
+    // no more open relay to anywhere
+    let remote: SocketAddr = "9.9.9.9:53".parse().unwrap();
+    core.handle_datagram(&ToMiddlemanMsg::ProxyTo { remote, token: None }.serialize(), 0, requester, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::RequestErr { msg: "proxy access needs a token".to_string() });
+    let token = auth::make_token(b"hunter2", Scope::Any, unix_now + 60);
+    core.handle_datagram(&ToMiddlemanMsg::ProxyTo { remote, token: Some(token) }.serialize(), 0, requester, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::ProxyResult { remote, ok: true });
+    // no secret: no proxy either, unless opened explicitly
+    let mut core = TrackerCore::new(TrackerConfig::default(), now);
+    core.handle_datagram(&ToMiddlemanMsg::Register { candidates: vec![] }.serialize(), 0, host, now);
+    let Some((_, FromMiddlemanMsg::RegisterOk { id }, _)) = drain(&mut core).pop() else {
+        panic!("not registered");
+    };
+    core.handle_datagram(&ToMiddlemanMsg::Request { id, use_proxy: true, candidates: vec![], token: None }.serialize(), 0, requester, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::RequestErr { msg: "proxy access needs a token".to_string() });
+    core.handle_datagram(&ToMiddlemanMsg::ProxyTo { remote, token: None }.serialize(), 0, requester, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::RequestErr { msg: "proxy access needs a token".to_string() });
+    core.handle_datagram(&ToMiddlemanMsg::Allocate.serialize(), 0, host, now);
+    drain(&mut core);
+    core.handle_datagram(&ToMiddlemanMsg::ChannelBind { channel: relay::CHANNEL_MIN, peer: remote, token: None }.serialize(), 0, host, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::ChannelBindErr { channel: relay::CHANNEL_MIN, msg: "proxy access needs a token".to_string() });
+    core.config.open_proxy = true;
+    core.handle_datagram(&ToMiddlemanMsg::ProxyTo { remote, token: None }.serialize(), 0, requester, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::ProxyResult { remote, ok: true });
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_resolves_domains_once() {
+    let a: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let b: SocketAddr = "2.2.2.2:2000".parse().unwrap();
+    let mut now = Instant::now();
+    let mut core = TrackerCore::new(TrackerConfig::default(), now);
+    let req = |domain: &str| ToMiddlemanMsg::DomainNameReq { domain: domain.to_string() }.serialize();
+
+    core.handle_datagram(&req("game.example:7777"), 0, a, now);
+    core.handle_datagram(&req("game.example:7777"), 1, b, now);
+    assert_eq!(core.poll_lookup(), Some("game.example:7777".to_string()));
+    assert_eq!(core.poll_lookup(), None);
+    assert_eq!(drain(&mut core), []);
+
+    let results = vec!["[::ffff:9.9.9.9]:7777".parse().unwrap()];
+    core.handle_lookup("game.example:7777".to_string(), Ok(results), now);
+    let answer = FromMiddlemanMsg::DomainNameResult { domain: "game.example:7777".to_string(), results: vec!["9.9.9.9:7777".parse().unwrap()] };
+    let msgs = drain(&mut core);
+    assert!(msgs.contains(&(0, answer.clone(), a)) && msgs.contains(&(1, answer.clone(), b)));
+    // from the cache
+    core.handle_datagram(&req("game.example:7777"), 0, a, now);
+    assert_eq!(drain(&mut core)[0], (0, answer, a));
+    assert_eq!(core.poll_lookup(), None);
+
+    core.handle_datagram(&req("nowhere.example:1"), 0, a, now);
+    let domain = core.poll_lookup().unwrap();
+    core.handle_lookup(domain, Err("failed to lookup address information: Name or service not known".to_string()), now);
+    assert!(matches!(&drain(&mut core)[0].1, FromMiddlemanMsg::DomainNameErr { msg, .. } if msg.contains("not known")));
+    now += core.config.dns_negative_cache;
+    core.handle_datagram(&req("nowhere.example:1"), 0, a, now);
+    assert_eq!(core.poll_lookup(), Some("nowhere.example:1".to_string()));
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_denies_private_destinations() {
+    use super::destination::parse_cidrs;
+    let client: SocketAddr = "1.1.1.1:1000".parse().unwrap();
+    let now = Instant::now();
+    let mut core = TrackerCore::new(TrackerConfig { open_proxy: true, ..TrackerConfig::default() }, now);
+    let denied = FromMiddlemanMsg::RequestErr { msg: "destination not allowed".to_string() };
+    for remote in ["169.254.169.254:80", "127.0.0.1:22", "[::ffff:10.0.0.1]:53", "[fd00:ec2::254]:80"] {
+        let remote = remote.parse().unwrap();
+        core.handle_datagram(&ToMiddlemanMsg::ProxyTo { remote, token: None }.serialize(), 0, client, now);
+        assert_eq!(drain(&mut core)[0].1, denied);
+    }
+    assert!(core.proxy_list.is_empty());
+
+    core.handle_datagram(&ToMiddlemanMsg::Allocate.serialize(), 0, client, now);
+    drain(&mut core);
+    let bind = ToMiddlemanMsg::ChannelBind { channel: relay::CHANNEL_MIN, peer: "192.168.1.1:80".parse().unwrap(), token: None };
+    core.handle_datagram(&bind.serialize(), 0, client, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::ChannelBindErr { channel: relay::CHANNEL_MIN, msg: "destination not allowed".to_string() });
+
+    // private addresses are left out of resolved ones
+    core.handle_datagram(&ToMiddlemanMsg::DomainNameReq { domain: "internal.example:80".to_string() }.serialize(), 0, client, now);
+    let results = vec!["10.0.0.5:80".parse().unwrap(), "9.9.9.9:80".parse().unwrap()];
+    let domain = core.poll_lookup().unwrap();
+    core.handle_lookup(domain, Ok(results), now);
+    let answer = FromMiddlemanMsg::DomainNameResult { domain: "internal.example:80".to_string(), results: vec!["9.9.9.9:80".parse().unwrap()] };
+    assert_eq!(drain(&mut core)[0].1, answer);
+    core.handle_datagram(&ToMiddlemanMsg::DomainNameReq { domain: "metadata.example:80".to_string() }.serialize(), 0, client, now);
+    let domain = core.poll_lookup().unwrap();
+    core.handle_lookup(domain, Ok(vec!["169.254.169.254:80".parse().unwrap()]), now);
+    assert!(matches!(&drain(&mut core)[0].1, FromMiddlemanMsg::DomainNameErr { msg, .. } if msg == "destination not allowed"));
+
+    // unless allowed
+    core.config.destinations.allow = parse_cidrs("10.0.0.0/8").unwrap();
+    let remote = "10.0.0.1:53".parse().unwrap();
+    core.handle_datagram(&ToMiddlemanMsg::ProxyTo { remote, token: None }.serialize(), 0, client, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::ProxyResult { remote, ok: true });
+
+    // never to ourselves, even allowed
+    core.config.public_ip = Some("5.5.5.5".parse().unwrap());
+    core.config.bind_addrs[0] = "10.0.0.2:61990".parse().unwrap();
+    for remote in ["5.5.5.5:22", "[::ffff:5.5.5.5]:61990", "10.0.0.2:8080"] {
+        let remote = remote.parse().unwrap();
+        core.handle_datagram(&ToMiddlemanMsg::ProxyTo { remote, token: None }.serialize(), 0, client, now);
+        assert_eq!(drain(&mut core)[0].1, denied);
+    }
+    let bind = ToMiddlemanMsg::ChannelBind { channel: relay::CHANNEL_MIN, peer: "5.5.5.5:80".parse().unwrap(), token: None };
+    core.handle_datagram(&bind.serialize(), 0, client, now);
+    assert_eq!(drain(&mut core)[0].1, FromMiddlemanMsg::ChannelBindErr { channel: relay::CHANNEL_MIN, msg: "destination not allowed".to_string() });
+}
@@ -0,0 +1,153 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A block of addresses, `10.0.0.0/8` or `fc00::/7`. A bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub ip: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    pub const fn v4(octets: [u8; 4], prefix: u8) -> Self {
+        Self { ip: IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])), prefix }
+    }
+
+    pub const fn v6(segments: [u16; 8], prefix: u8) -> Self {
+        let [a, b, c, d, e, f, g, h] = segments;
+        Self { ip: IpAddr::V6(Ipv6Addr::new(a, b, c, d, e, f, g, h)), prefix }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (ip, prefix) = match s.trim().split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let ip = ip.parse::<IpAddr>().map_err(|e| e.to_string())?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max).ok_or(format!("prefix must be 0 to {}", max))?,
+            None => max,
+        };
+        Ok(Self { ip, prefix })
+    }
+
+    /// Whether `ip` is in the block. IPv4-mapped addresses are not in IPv4 blocks, make them
+    /// canonical first.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.ip, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                net.to_bits() & mask == ip.to_bits() & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                net.to_bits() & mask == ip.to_bits() & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+/// Addresses the tracker never proxies to nor resolves to unless allowed explicitly: its own host,
+/// the networks it sits on, and the cloud metadata services found there.
+pub const PRIVATE_RANGES: &[Cidr] = &[
+    // "this" network, private, carrier-grade NAT, loopback, link local (metadata at 169.254.169.254)
+    Cidr::v4([0, 0, 0, 0], 8),
+    Cidr::v4([10, 0, 0, 0], 8),
+    Cidr::v4([100, 64, 0, 0], 10),
+    Cidr::v4([127, 0, 0, 0], 8),
+    Cidr::v4([169, 254, 0, 0], 16),
+    Cidr::v4([172, 16, 0, 0], 12),
+    Cidr::v4([192, 0, 0, 0], 24),
+    Cidr::v4([192, 168, 0, 0], 16),
+    // benchmarking, multicast, reserved and broadcast
+    Cidr::v4([198, 18, 0, 0], 15),
+    Cidr::v4([224, 0, 0, 0], 4),
+    Cidr::v4([240, 0, 0, 0], 4),
+    // unspecified, loopback, and NAT64 whatever IPv4 address it carries (IPv4-mapped addresses are
+    // checked as IPv4 ones)
+    Cidr::v6([0, 0, 0, 0, 0, 0, 0, 0], 128),
+    Cidr::v6([0, 0, 0, 0, 0, 0, 0, 1], 128),
+    Cidr::v6([0x64, 0xff9b, 0, 0, 0, 0, 0, 0], 96),
+    Cidr::v6([0x64, 0xff9b, 1, 0, 0, 0, 0, 0], 48),
+    // unique local (metadata at fd00:ec2::254), link local and multicast
+    Cidr::v6([0xfc00, 0, 0, 0, 0, 0, 0, 0], 7),
+    Cidr::v6([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10),
+    Cidr::v6([0xff00, 0, 0, 0, 0, 0, 0, 0], 8),
+];
+
+/// Where clients may have the tracker send datagrams (`ProxyTo`, `ChannelBind`) and which resolved
+/// addresses `DomainNameReq` answers.
+///
+/// The most specific block of `allow` and `deny` holding an address decides, `deny` winning ties.
+/// Addresses in neither are allowed unless in `PRIVATE_RANGES`, so a client cannot reach the
+/// tracker's own network through it: allow `10.0.0.0/8` to open it, or deny `0.0.0.0/0` and
+/// `::/0` then allow a few blocks to only proxy there.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DestinationPolicy {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl DestinationPolicy {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let longest = |cidrs: &[Cidr]| cidrs.iter().filter(|cidr| cidr.contains(ip)).map(|cidr| cidr.prefix).max();
+        match (longest(&self.allow), longest(&self.deny)) {
+            (Some(allow), Some(deny)) => allow > deny,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => !PRIVATE_RANGES.iter().any(|cidr| cidr.contains(ip)),
+        }
+    }
+}
+
+/// Blocks separated by commas, as in `TrackerConfig::set`
+pub fn parse_cidrs(s: &str) -> Result<Vec<Cidr>, String> {
+    s.split(',')
+        .filter(|cidr| !cidr.trim().is_empty())
+        .map(|cidr| Cidr::parse(cidr).map_err(|e| format!("{:?}: {}", cidr.trim(), e)))
+        .collect()
+}
+
+#[test]
+#[cfg(test)]
+fn destination_policy_denies_private_ranges() {
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    let open = DestinationPolicy::default();
+    for private in ["127.0.0.1", "10.1.2.3", "172.31.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+        "::1", "::", "fd00:ec2::254", "fe80::1", "::ffff:127.0.0.1", "::ffff:10.0.0.1", "64:ff9b::a00:1"] {
+        assert!(!open.allows(ip(private)), "{} should be denied", private);
+    }
+    assert!(open.allows(ip("1.1.1.1")));
+    assert!(open.allows(ip("::ffff:8.8.8.8")));
+    assert!(open.allows(ip("2606:4700::1111")));
+
+    let lan = DestinationPolicy {
+        allow: parse_cidrs("10.0.0.0/8").unwrap(),
+        deny: parse_cidrs("10.0.0.1, 1.1.1.0/24").unwrap(),
+    };
+    assert!(lan.allows(ip("10.2.3.4")));
+    assert!(!lan.allows(ip("10.0.0.1")));
+    assert!(!lan.allows(ip("1.1.1.1")));
+    assert!(lan.allows(ip("8.8.8.8")));
+    assert!(!lan.allows(ip("192.168.1.1")));
+
+    let only = DestinationPolicy {
+        allow: parse_cidrs("8.8.8.0/24").unwrap(),
+        deny: parse_cidrs("0.0.0.0/0,::/0").unwrap(),
+    };
+    assert!(only.allows(ip("8.8.8.8")));
+    assert!(!only.allows(ip("1.1.1.1")));
+    assert!(!only.allows(ip("2606:4700::1111")));
+
+    assert_eq!(Cidr::parse("0.0.0.0/0").map(|c| c.contains(ip("203.0.113.7"))), Ok(true));
+    assert!(Cidr::parse("10.0.0.0/33").is_err());
+    assert!(parse_cidrs("").unwrap().is_empty());
+}
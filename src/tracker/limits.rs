@@ -0,0 +1,363 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, RwLock
+    },
+    time::{Duration, Instant}
+};
+
+use crate::data::ToMiddlemanMsg;
+
+use super::config::TrackerConfig;
+
+/// Messages dropped by a source within that window before it is banned, see `TrackerConfig::ban_after`
+pub const BAN_WINDOW: Duration = Duration::from_secs(10);
+/// How often sources that went quiet are forgotten. Only new messages add sources, so sweeping
+/// as they come keeps the table bounded without waking up an idle tracker.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Kinds of messages rate limited separately: a client polling its address does not use up its
+/// registrations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MsgKind {
+    Register,
+    Renew,
+    Request,
+    PunchCheck,
+    /// `ProxyTo`
+    Proxy,
+    /// `DomainNameReq`
+    DomainName,
+    /// `Allocate` and `ChannelBind`
+    Relay,
+    /// `Ping`, `WhatIsMyAddr`, `NatProbe` and `NatProbeFor`
+    Probe,
+}
+
+impl MsgKind {
+    pub const COUNT: usize = 8;
+    pub const ALL: [MsgKind; Self::COUNT] = [
+        MsgKind::Register,
+        MsgKind::Renew,
+        MsgKind::Request,
+        MsgKind::PunchCheck,
+        MsgKind::Proxy,
+        MsgKind::DomainName,
+        MsgKind::Relay,
+        MsgKind::Probe,
+    ];
+
+    pub fn of(msg: &ToMiddlemanMsg) -> Self {
+        match msg {
+            ToMiddlemanMsg::Register { .. } => MsgKind::Register,
+            ToMiddlemanMsg::Renew { .. } => MsgKind::Renew,
+            ToMiddlemanMsg::Request { .. } => MsgKind::Request,
+            ToMiddlemanMsg::PunchCheck { .. } => MsgKind::PunchCheck,
+            ToMiddlemanMsg::ProxyTo { .. } => MsgKind::Proxy,
+            ToMiddlemanMsg::DomainNameReq { .. } => MsgKind::DomainName,
+            ToMiddlemanMsg::Allocate | ToMiddlemanMsg::ChannelBind { .. } => MsgKind::Relay,
+            ToMiddlemanMsg::Ping { .. } | ToMiddlemanMsg::WhatIsMyAddr | ToMiddlemanMsg::NatProbe { .. }
+                | ToMiddlemanMsg::NatProbeFor { .. } => MsgKind::Probe,
+        }
+    }
+
+    /// Name in settings, `rate_limit_<name>`
+    pub fn name(self) -> &'static str {
+        match self {
+            MsgKind::Register => "register",
+            MsgKind::Renew => "renew",
+            MsgKind::Request => "request",
+            MsgKind::PunchCheck => "punch_check",
+            MsgKind::Proxy => "proxy",
+            MsgKind::DomainName => "domain_name",
+            MsgKind::Relay => "relay",
+            MsgKind::Probe => "probe",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    /// Generous enough for many clients behind one carrier-grade NAT. Punch checks and probes are
+    /// sent to every socket of the tracker at once.
+    pub fn default_limit(self) -> RateLimit {
+        let (per_minute, burst) = match self {
+            MsgKind::Register => (60, 10),
+            MsgKind::Renew => (120, 20),
+            MsgKind::Request => (120, 20),
+            MsgKind::PunchCheck => (600, 64),
+            MsgKind::Proxy => (60, 10),
+            MsgKind::DomainName => (60, 10),
+            MsgKind::Relay => (240, 32),
+            MsgKind::Probe => (600, 64),
+        };
+        RateLimit { per_minute, burst }
+    }
+}
+
+/// Token bucket: `burst` messages at once, refilled at `per_minute`. No limit if `per_minute` is 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub const UNLIMITED: RateLimit = RateLimit { per_minute: 0, burst: 0 };
+
+    pub fn is_unlimited(&self) -> bool {
+        self.per_minute == 0
+    }
+
+    /// `<per_minute>/<burst>`, or `off`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if s.trim() == "off" {
+            return Ok(Self::UNLIMITED);
+        }
+        let (per_minute, burst) = s.split_once('/').ok_or("expected <per minute>/<burst> or off")?;
+        let per_minute = per_minute.trim().parse::<u32>().map_err(|e| e.to_string())?;
+        let burst = burst.trim().parse::<u32>().map_err(|e| e.to_string())?;
+        if per_minute == 0 || burst == 0 {
+            return Err("0 would drop everything, use off for no limit".to_string());
+        }
+        Ok(Self { per_minute, burst })
+    }
+}
+
+/// What limits apply to: an IPv4 address, or the /64 prefix of an IPv6 one since a single client
+/// usually gets a whole prefix
+pub fn source_of(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & !(u64::MAX as u128))),
+    }
+}
+
+/// Where a relayed datagram stands with the quotas, see `TrackerConfig::session_quota`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    Within,
+    /// over a bandwidth limit: drop the datagram
+    Throttled,
+    /// over a volume limit: close the session
+    Exceeded,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(tokens: f64, now: Instant) -> Self {
+        Self { tokens, updated: now }
+    }
+
+    fn refill(&mut self, per_sec: f64, capacity: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(capacity);
+        self.updated = now;
+    }
+
+    fn refill_messages(&mut self, limit: RateLimit, now: Instant) {
+        self.refill(limit.per_minute as f64 / 60.0, limit.burst as f64, now);
+    }
+}
+
+/// Token bucket of a bandwidth limit in bytes per second: a second worth of bytes at once, and at
+/// least a full datagram
+pub(super) struct Bandwidth(Bucket);
+
+impl Bandwidth {
+    pub fn new(now: Instant) -> Self {
+        Self(Bucket::new(f64::MAX, now))
+    }
+
+    /// Whether `len` more bytes are within `per_sec`, nothing is taken. Always true without a limit.
+    pub fn allows(&mut self, len: usize, per_sec: u64, now: Instant) -> bool {
+        if per_sec == 0 {
+            return true;
+        }
+        let capacity = (per_sec as f64).max(1500.0);
+        self.0.refill(per_sec as f64, capacity, now);
+        self.0.tokens >= len as f64
+    }
+
+    /// Takes `len` bytes `allows` accepted
+    pub fn take(&mut self, len: usize, per_sec: u64) {
+        if per_sec > 0 {
+            self.0.tokens -= len as f64;
+        }
+    }
+}
+
+struct Source {
+    buckets: [Option<Bucket>; MsgKind::COUNT],
+    /// messages dropped since `strikes_since`
+    strikes: u32,
+    strikes_since: Instant,
+    /// proxies and relayed peers this source asked for, on every worker
+    proxies: usize,
+    /// bytes forwarded for them since `bytes_since`, see `TrackerConfig::ip_quota`
+    bytes: u64,
+    bytes_since: Instant,
+    bandwidth: Bandwidth,
+}
+
+impl Source {
+    /// Nothing to remember: full buckets, no recent strikes, no proxies and no bytes counted
+    fn is_idle(&mut self, config: &TrackerConfig, now: Instant) -> bool {
+        self.proxies == 0
+            && now >= self.strikes_since + BAN_WINDOW
+            && (self.bytes == 0 || now >= self.bytes_since + config.quota_period)
+            && MsgKind::ALL.iter().zip(&mut self.buckets).all(|(kind, bucket)| match bucket {
+                Some(bucket) => {
+                    let limit = config.rate_limit(*kind);
+                    bucket.refill_messages(limit, now);
+                    limit.is_unlimited() || bucket.tokens >= limit.burst as f64
+                },
+                None => true,
+            })
+    }
+}
+
+#[derive(Default)]
+struct Sources {
+    by_ip: HashMap<IpAddr, Source>,
+    next_sweep: Option<Instant>,
+}
+
+impl Sources {
+    fn get(&mut self, source: IpAddr, now: Instant) -> &mut Source {
+        self.next_sweep.get_or_insert(now + SWEEP_INTERVAL);
+        self.by_ip.entry(source).or_insert_with(|| {
+            Source {
+                buckets: Default::default(),
+                strikes: 0,
+                strikes_since: now,
+                proxies: 0,
+                bytes: 0,
+                bytes_since: now,
+                bandwidth: Bandwidth::new(now),
+            }
+        })
+    }
+}
+
+/// Rate limits, bans and proxy counts of every source, shared by the workers
+#[derive(Default)]
+pub struct Limiter {
+    sources: Mutex<Sources>,
+    /// until when each banned source is
+    bans: RwLock<HashMap<IpAddr, Instant>>,
+    /// bans since we started
+    bans_total: AtomicU64,
+}
+
+impl Limiter {
+    pub fn is_banned(&self, source: IpAddr, now: Instant) -> bool {
+        self.bans.read().unwrap().get(&source).is_some_and(|until| now < *until)
+    }
+
+    /// Takes a token of `kind` from the bucket of `source`, false if it is empty and the message
+    /// should be dropped. Sources dropping too many messages get banned.
+    pub fn allow(&self, source: IpAddr, kind: MsgKind, config: &TrackerConfig, now: Instant) -> bool {
+        let limit = config.rate_limit(kind);
+        if limit.is_unlimited() {
+            return true;
+        }
+        let mut sources = self.sources.lock().unwrap();
+        self.sweep(&mut sources, config, now);
+        let entry = sources.get(source, now);
+        let bucket = entry.buckets[kind as usize].get_or_insert(Bucket::new(limit.burst as f64, now));
+        bucket.refill_messages(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+        if now >= entry.strikes_since + BAN_WINDOW {
+            entry.strikes = 0;
+            entry.strikes_since = now;
+        }
+        entry.strikes += 1;
+        if entry.strikes == 1 {
+            log::debug!("dropping {} messages from {}: over {}/min", kind.name(), source, limit.per_minute);
+        }
+        if config.ban_after > 0 && entry.strikes >= config.ban_after {
+            entry.strikes = 0;
+            drop(sources);
+            log::warn!("banning {} for {}s: {} {} messages dropped in {}s",
+                source, config.ban_duration.as_secs(), config.ban_after, kind.name(), BAN_WINDOW.as_secs()
+            );
+            self.bans.write().unwrap().insert(source, now + config.ban_duration);
+            self.bans_total.fetch_add(1, Ordering::Relaxed);
+        }
+        false
+    }
+
+    /// Sources banned at `now`, and bans since we started
+    pub fn ban_counts(&self, now: Instant) -> (usize, u64) {
+        let banned = self.bans.read().unwrap().values().filter(|until| now < **until).count();
+        (banned, self.bans_total.load(Ordering::Relaxed))
+    }
+
+    /// Proxies and relayed peers `source` asked for, on every worker
+    pub fn proxy_count(&self, source: IpAddr) -> usize {
+        self.sources.lock().unwrap().by_ip.get(&source).map_or(0, |s| s.proxies)
+    }
+
+    pub fn add_proxy(&self, source: IpAddr, config: &TrackerConfig, now: Instant) {
+        let mut sources = self.sources.lock().unwrap();
+        self.sweep(&mut sources, config, now);
+        sources.get(source, now).proxies += 1;
+    }
+
+    pub fn remove_proxy(&self, source: IpAddr) {
+        if let Some(s) = self.sources.lock().unwrap().by_ip.get_mut(&source) {
+            s.proxies = s.proxies.saturating_sub(1);
+        }
+    }
+
+    /// Counts `len` bytes forwarded for a session `source` asked for, unless they are over its
+    /// `ip_bandwidth` or `ip_quota`
+    pub fn relay(&self, source: IpAddr, len: usize, config: &TrackerConfig, now: Instant) -> Quota {
+        if config.ip_bandwidth == 0 && config.ip_quota == 0 {
+            return Quota::Within;
+        }
+        let mut sources = self.sources.lock().unwrap();
+        let entry = sources.get(source, now);
+        if now >= entry.bytes_since + config.quota_period {
+            entry.bytes = 0;
+            entry.bytes_since = now;
+        }
+        if config.ip_quota > 0 && entry.bytes + len as u64 > config.ip_quota {
+            return Quota::Exceeded;
+        }
+        if !entry.bandwidth.allows(len, config.ip_bandwidth, now) {
+            return Quota::Throttled;
+        }
+        entry.bandwidth.take(len, config.ip_bandwidth);
+        entry.bytes += len as u64;
+        Quota::Within
+    }
+
+    /// Forgets idle sources and lifts bans that are over, when it is time to
+    fn sweep(&self, sources: &mut Sources, config: &TrackerConfig, now: Instant) {
+        if sources.next_sweep.is_none_or(|t| now < t) {
+            return;
+        }
+        sources.by_ip.retain(|_, s| !s.is_idle(config, now));
+        let mut bans = self.bans.write().unwrap();
+        bans.retain(|source, until| {
+            let r = now < *until;
+            if !r {
+                log::info!("ban of {} is over", source);
+            }
+            r
+        });
+        sources.next_sweep = (!sources.by_ip.is_empty() || !bans.is_empty()).then_some(now + SWEEP_INTERVAL);
+    }
+}
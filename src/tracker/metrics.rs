@@ -0,0 +1,118 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering}
+};
+
+use super::limits::MsgKind;
+
+/// Counters of a tracker, shared by its workers. `SharedState::metrics` renders them along with
+/// the registry's gauges.
+#[derive(Default)]
+pub struct Metrics {
+    /// linkseeker messages received, by kind, dropped ones included
+    pub messages: [AtomicU64; MsgKind::COUNT],
+    /// messages dropped over a rate limit, by kind
+    pub rate_limited: [AtomicU64; MsgKind::COUNT],
+    /// datagrams of banned sources, dropped without a look
+    pub banned_drops: AtomicU64,
+    /// datagrams starting with `#lnksk@` that are no message we know
+    pub parse_failures: AtomicU64,
+    /// proxies and relayed peers open, on every worker
+    pub proxy_sessions: AtomicU64,
+    pub proxies_opened: AtomicU64,
+    /// proxies closed over a volume quota
+    pub proxies_over_quota: AtomicU64,
+    /// proxy requests refused because every socket is used for that remote already
+    pub proxy_slots_full: AtomicU64,
+    /// relay sessions (`Allocate`) open, on every worker
+    pub relay_sessions: AtomicU64,
+    pub relayed_packets: AtomicU64,
+    pub relayed_bytes: AtomicU64,
+    /// datagrams dropped over a bandwidth limit
+    pub throttled_packets: AtomicU64,
+    /// domains given to the resolver, not answered from the cache
+    pub dns_lookups: AtomicU64,
+}
+
+/// What only the registry and the limiter know, for `Metrics::render`
+pub struct Gauges {
+    pub registrations: usize,
+    pub punch_checks: usize,
+    pub banned_sources: usize,
+    pub bans: u64,
+}
+
+impl Metrics {
+    /// Prometheus text format
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let by_kind = |counters: &[AtomicU64; MsgKind::COUNT]| {
+            MsgKind::ALL.into_iter().zip(counters).map(|(kind, n)| (Some(kind.name()), load(n))).collect::<Vec<_>>()
+        };
+        let mut out = String::new();
+        for (name, kind, help, values) in [
+            ("registrations", "gauge", "ids registered", vec![(None, gauges.registrations as u64)]),
+            ("punch_checks", "gauge", "punch checks waiting for their second datagram", vec![(None, gauges.punch_checks as u64)]),
+            ("proxy_sessions", "gauge", "proxies and relayed peers open", vec![(None, load(&self.proxy_sessions))]),
+            ("relay_sessions", "gauge", "relay sessions open", vec![(None, load(&self.relay_sessions))]),
+            ("banned_sources", "gauge", "sources banned", vec![(None, gauges.banned_sources as u64)]),
+            ("messages_total", "counter", "linkseeker messages received", by_kind(&self.messages)),
+            ("rate_limited_total", "counter", "messages dropped over a rate limit", by_kind(&self.rate_limited)),
+            ("bans_total", "counter", "sources banned for going over the rate limits", vec![(None, gauges.bans)]),
+            ("banned_drops_total", "counter", "datagrams of banned sources dropped", vec![(None, load(&self.banned_drops))]),
+            ("parse_failures_total", "counter", "datagrams starting with #lnksk@ that are no known message", vec![(None, load(&self.parse_failures))]),
+            ("proxies_opened_total", "counter", "proxies and relayed peers opened", vec![(None, load(&self.proxies_opened))]),
+            ("proxies_over_quota_total", "counter", "proxies closed over a volume quota", vec![(None, load(&self.proxies_over_quota))]),
+            ("proxy_slots_full_total", "counter", "proxy requests refused with every socket in use for the remote", vec![(None, load(&self.proxy_slots_full))]),
+            ("relayed_packets_total", "counter", "datagrams relayed", vec![(None, load(&self.relayed_packets))]),
+            ("relayed_bytes_total", "counter", "payload bytes relayed", vec![(None, load(&self.relayed_bytes))]),
+            ("throttled_packets_total", "counter", "datagrams dropped over a bandwidth limit", vec![(None, load(&self.throttled_packets))]),
+            ("dns_lookups_total", "counter", "domains resolved, cache misses", vec![(None, load(&self.dns_lookups))]),
+        ] {
+            let _r = writeln!(out, "# HELP linkseeker_{} {}\n# TYPE linkseeker_{} {}", name, help, name, kind);
+            for (label, value) in values {
+                let _r = match label {
+                    Some(label) => writeln!(out, "linkseeker_{}{{kind=\"{}\"}} {}", name, label, value),
+                    None => writeln!(out, "linkseeker_{} {}", name, value),
+                };
+            }
+        }
+        out
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        Self::add(counter, 1);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn dec(gauge: &AtomicU64) {
+        let _r = gauge.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+}
+
+#[test]
+#[cfg(test)]
+fn metrics_render_prometheus_text() {
+    let metrics = Metrics::default();
+    Metrics::inc(&metrics.messages[MsgKind::Register as usize]);
+    Metrics::add(&metrics.relayed_bytes, 1200);
+    Metrics::inc(&metrics.proxy_sessions);
+    Metrics::dec(&metrics.proxy_sessions);
+    Metrics::dec(&metrics.proxy_sessions);
+    let text = metrics.render(&Gauges { registrations: 3, punch_checks: 0, banned_sources: 0, bans: 0 });
+    let lines: Vec<&str> = text.lines().collect();
+    for line in [
+        "# TYPE linkseeker_registrations gauge",
+        "linkseeker_registrations 3",
+        "linkseeker_proxy_sessions 0",
+        "# TYPE linkseeker_messages_total counter",
+        "linkseeker_messages_total{kind=\"register\"} 1",
+        "linkseeker_messages_total{kind=\"probe\"} 0",
+        "linkseeker_relayed_bytes_total 1200",
+    ] {
+        assert!(lines.contains(&line), "{} missing", line);
+    }
+}
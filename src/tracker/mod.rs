@@ -0,0 +1,289 @@
+mod config;
+mod core;
+mod destination;
+mod limits;
+mod metrics;
+mod resolver;
+
+pub use self::config::{settings_from_toml, TrackerConfig, CONFIG_KEYS, DEFAULT_SOCKET_COUNT};
+pub use self::core::{Handoff, PunchCheck, ProxyData, RdvRemote, SharedState, TrackerCore};
+pub use self::destination::{parse_cidrs, Cidr, DestinationPolicy, PRIVATE_RANGES};
+pub use self::limits::{source_of, MsgKind, RateLimit, BAN_WINDOW};
+pub use self::metrics::{Gauges, Metrics};
+pub use self::resolver::{ResolverPool, Resolved};
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{mpsc, Arc},
+    time::Instant
+};
+
+use mio::{net::UdpSocket, Events, Interest, Poll, Token, Waker};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Wakes a worker up when others hand it datagrams or domains are resolved, the sockets use the
+/// tokens below it
+const WAKER: Token = Token(usize::MAX);
+
+/// How a worker reaches another one
+#[derive(Clone)]
+struct WorkerHandle {
+    sender: mpsc::Sender<Handoff>,
+    waker: Arc<Waker>,
+}
+
+/// The largest UDP payload: relayed datagrams carry a 4 bytes channel header on top of the peer's
+pub const MAX_DATAGRAM: usize = 65535;
+
+/// The tracker with its own sockets: a thin IO driver around `TrackerCore`
+pub struct LinkSeekTracker {
+    pub core: TrackerCore,
+    pub udp_sockets: Vec<UdpSocket>,
+    /// for each socket, whether it is an IPv6 one
+    ipv6: Vec<bool>,
+    poll: Poll,
+    /// datagrams the other workers received for us
+    inbox: mpsc::Receiver<Handoff>,
+    /// every worker, us included
+    workers: Vec<WorkerHandle>,
+    waker: Arc<Waker>,
+    resolver: ResolverPool,
+    /// where `resolver` sends what it resolved for us
+    resolved: (mpsc::Sender<Resolved>, mpsc::Receiver<Resolved>),
+}
+
+impl LinkSeekTracker {
+    /// The default 4 dual-stack sockets on consecutive ports from `start_port`
+    pub fn new(start_port: u16) -> Result<Self, Box<dyn std::error::Error>> {
+        let ip = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+        Self::with_config(TrackerConfig::with_port_range(ip, start_port, DEFAULT_SOCKET_COUNT))
+    }
+
+    /// A single worker tracker, see `LinkSeekTracker::workers` for more
+    pub fn with_config(config: TrackerConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        if config.workers != 1 {
+            return Err(format!("{} workers configured, use LinkSeekTracker::workers", config.workers).into());
+        }
+        Ok(Self::workers(config)?.remove(0))
+    }
+
+    /// One tracker per worker of `config`, each to `run` on its own thread
+    pub fn workers(mut config: TrackerConfig) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        config.validate()?;
+        log::info!("starting link seek tracker on {} sockets, {} workers", config.socket_count(), config.workers);
+        let reuse_port = config.workers > 1;
+        let mut parts = Vec::with_capacity(config.workers);
+        for _ in 0..config.workers {
+            let poll = Poll::new()?;
+            // one socket per proxy slot: a host talking to several peers through us tells them apart
+            // by the socket their packets come from
+            let mut udp_sockets = Vec::with_capacity(config.socket_count());
+            for (i, addr) in config.bind_addrs.iter_mut().enumerate() {
+                let mut socket = match bind_socket(*addr, reuse_port) {
+                    Err(e) if *addr == SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), addr.port()) && e.kind() != ErrorKind::AddrInUse => {
+                        // no IPv6 on this host
+                        log::warn!("cannot bind {}: {}, falling back to IPv4", addr, e);
+                        *addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port());
+                        bind_socket(*addr, reuse_port)?
+                    },
+                    r => r?,
+                };
+                poll.registry().register(&mut socket, Token(i), Interest::READABLE)?;
+                // port 0 picks any port, the core and the next workers need the real one
+                *addr = socket.local_addr()?;
+                udp_sockets.push(socket);
+            }
+            let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+            let (sender, inbox) = mpsc::channel();
+            parts.push((poll, udp_sockets, inbox, WorkerHandle { sender, waker }));
+        }
+        let workers: Vec<_> = parts.iter().map(|(.., handle)| handle.clone()).collect();
+        let resolver = ResolverPool::new(config.dns_threads)?;
+        let shared = SharedState::default();
+        let now = Instant::now();
+        let ipv6: Vec<bool> = config.bind_addrs.iter().map(|addr| addr.is_ipv6()).collect();
+        Ok(parts.into_iter().enumerate().map(|(worker, (poll, udp_sockets, inbox, handle))| Self {
+            core: TrackerCore::worker(config.clone(), shared.clone(), worker, now),
+            udp_sockets,
+            ipv6: ipv6.clone(),
+            poll,
+            inbox,
+            workers: workers.clone(),
+            waker: handle.waker,
+            resolver: resolver.clone(),
+            resolved: mpsc::channel(),
+        }).collect())
+    }
+
+    /// See `TrackerCore::set_public_ip`
+    pub fn set_public_ip(&mut self, ip: IpAddr) {
+        self.core.set_public_ip(ip);
+    }
+
+    /// Expires what is due, `run` calls it whenever the core's timer fires
+    pub fn cleanup(&mut self) {
+        self.core.handle_timeout(Instant::now());
+        self.flush();
+    }
+
+    /// Sends everything the core has to send, datagrams first
+    fn flush(&mut self) {
+        while let Some((socket_n, transmit)) = self.core.poll_transmit() {
+            // the core only knows canonical addresses, IPv6 sockets reach IPv4 ones through mapped addresses
+            let dest = match transmit.dest {
+                SocketAddr::V4(v4) if self.ipv6[socket_n] => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+                dest => dest,
+            };
+            // a full send buffer drops the datagram, as the network would
+            let _r = self.udp_sockets[socket_n].send_to(&transmit.bytes, dest);
+        }
+        while let Some(handoff) = self.core.poll_handoff() {
+            let worker = &self.workers[handoff.worker];
+            if worker.sender.send(handoff).is_ok() {
+                let _r = worker.waker.wake();
+            }
+        }
+        while let Some(domain) = self.core.poll_lookup() {
+            self.resolver.lookup(domain, &self.resolved.0, &self.waker);
+        }
+    }
+
+    /// Waits for datagrams or for the core's next deadline, whichever comes first. Nothing wakes
+    /// up the tracker when it has nothing to do. Only returns on a polling error.
+    pub fn run(&mut self) -> std::io::Result<()> {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut events = Events::with_capacity(self.udp_sockets.len().max(16));
+        loop {
+            let deadline = self.core.poll_timeout();
+            if deadline.is_some_and(|t| t <= Instant::now()) {
+                self.cleanup();
+                continue;
+            }
+            let timeout = deadline.map(|t| t.saturating_duration_since(Instant::now()));
+            match self.poll.poll(&mut events, timeout) {
+                Ok(()) => {},
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.process_inbox(),
+                    Token(socket_n) => { self.process(socket_n, &mut buf); },
+                }
+            }
+        }
+    }
+
+    /// Handles every datagram waiting on socket `socket_n`, returns how many there were.
+    ///
+    /// Readiness is edge-triggered: the socket must be drained before waiting on it again. A `buf`
+    /// shorter than `MAX_DATAGRAM` truncates the datagrams over its length.
+    pub fn process(&mut self, socket_n: usize, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        loop {
+            match self.udp_sockets[socket_n].recv_from(buf) {
+                Ok((size, socket_addr)) => {
+                    self.core.handle_datagram(&buf[0..size], socket_n, socket_addr, Instant::now());
+                    count += 1;
+                    // relay as we go rather than after a burst
+                    self.flush();
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // ICMP errors of earlier sends, reported on linux, the socket is still usable
+                Err(e) if matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset) => continue,
+                Err(e) => {
+                    log::warn!("recv error on socket {}: {}", socket_n, e);
+                    break;
+                },
+            }
+        }
+        count
+    }
+
+    /// Handles what the other workers received for us, and the domains resolved
+    fn process_inbox(&mut self) {
+        while let Ok(handoff) = self.inbox.try_recv() {
+            self.core.handle_handoff(handoff, Instant::now());
+            self.flush();
+        }
+        while let Ok(Resolved { domain, result }) = self.resolved.1.try_recv() {
+            self.core.handle_lookup(domain, result, Instant::now());
+            self.flush();
+        }
+    }
+}
+
+/// Binding the unspecified IPv6 address makes a dual-stack socket
+fn bind_socket(addr: SocketAddr, reuse_port: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into()))
+}
+
+#[test]
+#[cfg(test)]
+fn tracker_relays_full_size_datagrams() {
+    use crate::{data::{FromMiddlemanMsg, ToMiddlemanMsg}, relay};
+    use std::time::Duration;
+
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let config = TrackerConfig { first_packet_delay: Duration::ZERO, open_proxy: true, ..TrackerConfig::with_port_range(localhost, 0, 1) };
+    let mut tracker = LinkSeekTracker::with_config(config).unwrap();
+    let tracker_addr = tracker.udp_sockets[0].local_addr().unwrap();
+    let mut buf = vec![0; MAX_DATAGRAM];
+    let mut exchange = |tracker: &mut LinkSeekTracker, from: &std::net::UdpSocket, bytes: &[u8]| {
+        from.send_to(bytes, tracker_addr).unwrap();
+        while tracker.process(0, &mut buf) == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    };
+    let recv = |socket: &std::net::UdpSocket| {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let size = socket.recv(&mut buf).unwrap();
+        buf.truncate(size);
+        buf
+    };
+    let socket = || {
+        let socket = std::net::UdpSocket::bind((localhost, 0)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    };
+    let (host, requester) = (socket(), socket());
+
+    exchange(&mut tracker, &host, &ToMiddlemanMsg::Register { candidates: vec![] }.serialize());
+    let Some(FromMiddlemanMsg::RegisterOk { id }) = FromMiddlemanMsg::parse(&recv(&host)) else {
+        panic!("not registered");
+    };
+    exchange(&mut tracker, &host, &ToMiddlemanMsg::Allocate.serialize());
+    let channel = loop {
+        match FromMiddlemanMsg::parse(&recv(&host)) {
+            Some(FromMiddlemanMsg::AllocateOk { .. }) => {
+                exchange(&mut tracker, &requester, &ToMiddlemanMsg::Request { id, use_proxy: true, candidates: vec![], token: None }.serialize());
+            },
+            Some(FromMiddlemanMsg::ChannelBound { channel, .. }) => break channel,
+            _ => {},
+        }
+    };
+
+    // over the 1496 bytes a 1500 bytes buffer leaves once framed
+    let payload: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+    exchange(&mut tracker, &host, &relay::encode(channel, &payload));
+    // past the ProxyResult
+    let relayed = loop {
+        let bytes = recv(&requester);
+        if FromMiddlemanMsg::parse(&bytes).is_none() {
+            break bytes;
+        }
+    };
+    assert_eq!(relayed, payload);
+}
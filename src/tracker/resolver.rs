@@ -0,0 +1,55 @@
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{mpsc, Arc, Mutex},
+};
+
+use mio::Waker;
+
+/// A domain resolved for a worker, see `TrackerCore::handle_lookup`
+pub struct Resolved {
+    pub domain: String,
+    pub result: Result<Vec<SocketAddr>, String>,
+}
+
+struct Job {
+    domain: String,
+    reply: mpsc::Sender<Resolved>,
+    waker: Arc<Waker>,
+}
+
+/// Threads resolving domains for the workers: the system resolver blocks for as long as name
+/// servers take to answer, and the workers have datagrams to relay meanwhile.
+///
+/// The threads stop once every clone is dropped.
+#[derive(Clone)]
+pub struct ResolverPool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl ResolverPool {
+    pub fn new(threads: usize) -> io::Result<Self> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for n in 0..threads {
+            let queue = queue.clone();
+            std::thread::Builder::new().name(format!("dns-{}", n)).spawn(move || loop {
+                let Ok(job) = queue.lock().unwrap().recv() else {
+                    return;
+                };
+                let result = job.domain.to_socket_addrs()
+                    .map(|addrs| addrs.collect())
+                    .map_err(|e| e.to_string());
+                if job.reply.send(Resolved { domain: job.domain, result }).is_ok() {
+                    let _r = job.waker.wake();
+                }
+            })?;
+        }
+        Ok(Self { jobs })
+    }
+
+    /// Resolves `domain` (`host:port`), the result is sent to `reply` and `waker` woken up
+    pub fn lookup(&self, domain: String, reply: &mpsc::Sender<Resolved>, waker: &Arc<Waker>) {
+        let _r = self.jobs.send(Job { domain, reply: reply.clone(), waker: waker.clone() });
+    }
+}